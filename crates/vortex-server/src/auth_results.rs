@@ -0,0 +1,322 @@
+use mail_auth::{
+    dmarc::verify::DmarcParameters, spf::verify::SpfParameters, AuthenticatedMessage,
+    AuthenticationResults, DkimOutput, DkimResult, DmarcOutput, DmarcResult, MessageAuthenticator,
+    SpfOutput, SpfResult,
+};
+use serde::{Deserialize, Serialize};
+use vortex_smtp::Email;
+
+/// The `authserv-id` we stamp on `Authentication-Results:` headers.
+const AUTHSERV_ID: &str = "mail.vortex.skyfall.dev";
+
+/// A JSON-friendly summary of the SPF, DKIM and DMARC checks for a message.
+///
+/// Result names are the RFC 8601 ones (`pass`, `fail`, `softfail`, `temperror`...).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthSummary {
+    pub spf: SpfSummary,
+    pub dkim: Vec<DkimSummary>,
+    pub dmarc: DmarcSummary,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpfSummary {
+    pub result: String,
+    pub domain: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DkimSummary {
+    pub result: String,
+    pub domain: Option<String>,
+    pub selector: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DmarcSummary {
+    pub result: String,
+    /// The RFC 5322 From: domain the policy was looked up for.
+    pub domain: String,
+    pub policy: String,
+    /// Whether SPF passed *and* the MAIL FROM domain aligned with the From: domain.
+    pub spf_aligned: bool,
+    /// Whether at least one DKIM signature passed *and* aligned with the From: domain.
+    pub dkim_aligned: bool,
+}
+
+/// Runs SPF, DKIM and DMARC against a received email.
+///
+/// Returns the `Authentication-Results:` header (including the trailing CRLF) to prepend
+/// to the message, alongside a summary for the API. Returns `None` if the message
+/// couldn't be parsed at all.
+pub async fn evaluate(
+    authenticator: &MessageAuthenticator,
+    email: &Email,
+) -> Option<(String, AuthSummary)> {
+    let message = AuthenticatedMessage::parse(email.data.as_bytes())?;
+    let helo = email.helo.as_deref().unwrap_or_default();
+    let mail_from_domain = email
+        .mail_from
+        .rsplit_once('@')
+        .map_or(helo, |(_, domain)| domain);
+
+    let dkim = authenticator.verify_dkim(&message).await;
+    let spf = match email.remote_addr {
+        Some(remote_addr) => {
+            authenticator
+                .verify_spf(SpfParameters::verify_mail_from(
+                    remote_addr.ip(),
+                    helo,
+                    AUTHSERV_ID,
+                    &email.mail_from,
+                ))
+                .await
+        }
+        // Without a client IP there's nothing to check SPF against.
+        None => SpfOutput::new(mail_from_domain.to_string()),
+    };
+    let dmarc = authenticator
+        .verify_dmarc(DmarcParameters::new(
+            &message,
+            &dkim,
+            mail_from_domain,
+            &spf,
+        ))
+        .await;
+
+    let header_from = message.from.first().map(String::as_str).unwrap_or_default();
+    Some(report(email, header_from, &dkim, &spf, &dmarc))
+}
+
+/// Builds the `Authentication-Results:` header and API summary from finished checks.
+fn report(
+    email: &Email,
+    header_from: &str,
+    dkim: &[DkimOutput],
+    spf: &SpfOutput,
+    dmarc: &DmarcOutput,
+) -> (String, AuthSummary) {
+    let helo = email.helo.as_deref().unwrap_or_default();
+    let mut header = AuthenticationResults::new(AUTHSERV_ID).with_dkim_results(dkim, header_from);
+    if let Some(remote_addr) = email.remote_addr {
+        header = header.with_spf_mailfrom_result(spf, remote_addr.ip(), &email.mail_from, helo);
+    }
+    let header = header.with_dmarc_result(dmarc);
+
+    let summary = AuthSummary {
+        spf: SpfSummary {
+            result: spf_result_name(spf.result()).to_string(),
+            domain: spf.domain().to_string(),
+        },
+        dkim: dkim.iter().map(summarize_dkim).collect(),
+        dmarc: DmarcSummary {
+            result: dmarc_result_name(&dmarc.result()).to_string(),
+            domain: dmarc.domain().to_string(),
+            policy: dmarc.policy().to_string(),
+            spf_aligned: *dmarc.spf_result() == DmarcResult::Pass,
+            dkim_aligned: *dmarc.dkim_result() == DmarcResult::Pass,
+        },
+    };
+
+    (format!("Authentication-Results: {header}\r\n"), summary)
+}
+
+/// Drops any `Authentication-Results:` headers claiming to be from us (RFC 8601 §5), so a
+/// sender can't forge a passing result. Headers from other authserv-ids are left alone.
+pub fn strip_own_headers(data: &str) -> String {
+    let header_end = data.find("\r\n\r\n").map_or(data.len(), |index| index + 2);
+    let (headers, body) = data.split_at(header_end);
+
+    let mut kept = String::with_capacity(data.len());
+    let mut field = String::new();
+    for line in headers.split_inclusive('\n') {
+        // Folded continuation lines belong to the field before them.
+        if !line.starts_with([' ', '\t']) {
+            if !is_own_header(&field) {
+                kept.push_str(&field);
+            }
+            field.clear();
+        }
+        field.push_str(line);
+    }
+    if !is_own_header(&field) {
+        kept.push_str(&field);
+    }
+    kept.push_str(body);
+    kept
+}
+
+fn is_own_header(line: &str) -> bool {
+    let Some((name, value)) = line.split_once(':') else {
+        return false;
+    };
+    if !name
+        .trim_end()
+        .eq_ignore_ascii_case("Authentication-Results")
+    {
+        return false;
+    }
+    // The authserv-id comes before the first `;`, optionally followed by a version number.
+    let authserv_id = value.split(';').next().unwrap_or_default();
+    authserv_id
+        .split_whitespace()
+        .next()
+        .is_some_and(|id| id.eq_ignore_ascii_case(AUTHSERV_ID))
+}
+
+fn summarize_dkim(output: &DkimOutput) -> DkimSummary {
+    DkimSummary {
+        result: dkim_result_name(output.result()).to_string(),
+        domain: output.signature().map(|signature| signature.d.clone()),
+        selector: output.signature().map(|signature| signature.s.clone()),
+    }
+}
+
+const fn spf_result_name(result: SpfResult) -> &'static str {
+    match result {
+        SpfResult::Pass => "pass",
+        SpfResult::Fail => "fail",
+        SpfResult::SoftFail => "softfail",
+        SpfResult::Neutral => "neutral",
+        SpfResult::TempError => "temperror",
+        SpfResult::PermError => "permerror",
+        SpfResult::None => "none",
+    }
+}
+
+const fn dkim_result_name(result: &DkimResult) -> &'static str {
+    match result {
+        DkimResult::Pass => "pass",
+        DkimResult::Neutral(_) => "neutral",
+        DkimResult::Fail(_) => "fail",
+        DkimResult::PermError(_) => "permerror",
+        DkimResult::TempError(_) => "temperror",
+        DkimResult::None => "none",
+    }
+}
+
+const fn dmarc_result_name(result: &DmarcResult) -> &'static str {
+    match result {
+        DmarcResult::Pass => "pass",
+        DmarcResult::Fail(_) => "fail",
+        DmarcResult::TempError(_) => "temperror",
+        DmarcResult::PermError(_) => "permerror",
+        DmarcResult::None => "none",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mail_auth::Error;
+
+    fn email(remote_addr: Option<&str>) -> Email {
+        Email {
+            mail_from: "sender@example.com".to_string(),
+            rcpt_to: vec!["alice@vortex.dev".to_string()],
+            dsn: Default::default(),
+            data: "From: sender@example.com\r\n\r\nhello\r\n".to_string(),
+            remote_addr: remote_addr.map(|addr| addr.parse().unwrap()),
+            helo: Some("mx.example.com".to_string()),
+            authenticated_as: None,
+            id: "abc".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_report_pass() {
+        let spf = SpfOutput::new("example.com".to_string()).with_result(SpfResult::Pass);
+        let dkim = [DkimOutput::pass()];
+        let dmarc = DmarcOutput::new("example.com".to_string())
+            .with_spf_result(DmarcResult::Pass)
+            .with_dkim_result(DmarcResult::Pass);
+        let (header, summary) = report(
+            &email(Some("192.0.2.1:25")),
+            "example.com",
+            &dkim,
+            &spf,
+            &dmarc,
+        );
+
+        assert!(header.starts_with(&format!("Authentication-Results: {AUTHSERV_ID};")));
+        assert!(header.ends_with("\r\n"));
+        assert!(header.contains("dkim=pass"));
+        assert!(header.contains("spf=pass"));
+        assert!(header.contains("smtp.mailfrom=sender@example.com"));
+        assert!(header.contains("dmarc=pass header.from=example.com"));
+        assert_eq!(summary.spf.result, "pass");
+        assert_eq!(summary.dkim.len(), 1);
+        assert_eq!(summary.dkim[0].result, "pass");
+        assert_eq!(summary.dmarc.result, "pass");
+        assert!(summary.dmarc.spf_aligned);
+        assert!(summary.dmarc.dkim_aligned);
+    }
+
+    #[test]
+    fn test_report_fail() {
+        let spf = SpfOutput::new("example.com".to_string()).with_result(SpfResult::Fail);
+        let dkim = [DkimOutput::fail(Error::NotAligned)];
+        let dmarc = DmarcOutput::new("example.com".to_string())
+            .with_spf_result(DmarcResult::Fail(Error::NotAligned))
+            .with_dkim_result(DmarcResult::Fail(Error::NotAligned));
+        let (header, summary) = report(
+            &email(Some("192.0.2.1:25")),
+            "example.com",
+            &dkim,
+            &spf,
+            &dmarc,
+        );
+
+        assert!(header.contains("dkim=fail"));
+        assert!(header.contains("spf=fail"));
+        assert!(header.contains("dmarc=fail"));
+        assert_eq!(summary.spf.result, "fail");
+        assert_eq!(summary.dkim[0].result, "fail");
+        assert_eq!(summary.dmarc.result, "fail");
+        assert!(!summary.dmarc.spf_aligned);
+        assert!(!summary.dmarc.dkim_aligned);
+    }
+
+    #[test]
+    fn test_report_none() {
+        // No client address, no signatures and no DMARC record.
+        let spf = SpfOutput::new("example.com".to_string());
+        let dmarc = DmarcOutput::new("example.com".to_string());
+        let (header, summary) = report(&email(None), "example.com", &[], &spf, &dmarc);
+
+        assert!(!header.contains("spf="));
+        assert!(!header.contains("dkim="));
+        assert!(header.contains("dmarc=none"));
+        assert_eq!(summary.spf.result, "none");
+        assert!(summary.dkim.is_empty());
+        assert_eq!(summary.dmarc.result, "none");
+    }
+
+    #[test]
+    fn test_strip_own_headers() {
+        let data = concat!(
+            "Authentication-Results: mail.vortex.skyfall.dev; spf=pass\r\n",
+            "Received: from somewhere\r\n",
+            "authentication-results: MAIL.VORTEX.SKYFALL.DEV 1;\r\n",
+            "\tdkim=pass header.d=example.com;\r\n",
+            "\tdmarc=pass\r\n",
+            "Authentication-Results:\r\n",
+            " mail.vortex.skyfall.dev; spf=pass\r\n",
+            "Authentication-Results: mx.example.com; spf=fail\r\n",
+            "Subject: hi\r\n",
+            "\r\n",
+            "Authentication-Results: mail.vortex.skyfall.dev; in the body\r\n",
+        );
+
+        assert_eq!(
+            strip_own_headers(data),
+            concat!(
+                "Received: from somewhere\r\n",
+                "Authentication-Results: mx.example.com; spf=fail\r\n",
+                "Subject: hi\r\n",
+                "\r\n",
+                "Authentication-Results: mail.vortex.skyfall.dev; in the body\r\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! Forwarding rules: inbox owners can have copies of their mail relayed to a real
+//! address through a smarthost.
+//!
+//! Rules are kept in a [`ForwardingStore`](crate::storage::ForwardingStore), one per
+//! target address. Only an SMTP_USERS account that owns the inbox can see or change
+//! them, using HTTP Basic auth, since a rule lets you send mail anywhere.
+
+use std::env;
+
+use axum::{
+    extract::{Path, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use email_address_parser::EmailAddress;
+use serde::{Deserialize, Serialize};
+use vortex_smtp::Email;
+use vortex_smtp_client::{Client, Credentials, Security};
+
+use crate::{srs::Srs, submission, AppState};
+
+/// Where forwarded mail goes out, and how its sender gets rewritten.
+pub struct Relay {
+    config: vortex_smtp_client::Config,
+    srs: Srs,
+}
+
+impl Relay {
+    /// Reads the relay settings from the environment, or returns `None` if `RELAY_HOST`
+    /// isn't set, in which case forwarding is turned off.
+    pub fn from_env(allowed_domains: &[String]) -> Result<Option<Self>> {
+        let Ok(host) = env::var("RELAY_HOST") else {
+            return Ok(None);
+        };
+        let port = match env::var("RELAY_PORT") {
+            Ok(port) => port.parse().wrap_err("Invalid RELAY_PORT")?,
+            Err(_) => 587,
+        };
+        let security = match env::var("RELAY_SECURITY").as_deref() {
+            Ok("starttls") | Err(_) => Security::StartTls,
+            Ok("tls") => Security::ImplicitTls,
+            Ok("plain") => Security::Plain,
+            Ok(other) => {
+                return Err(eyre!(
+                    "RELAY_SECURITY must be starttls, tls or plain, not {other:?}"
+                ))
+            }
+        };
+        let credentials = match (env::var("RELAY_USERNAME"), env::var("RELAY_PASSWORD")) {
+            (Ok(username), Ok(password)) => Some(Credentials { username, password }),
+            _ => None,
+        };
+
+        let secret = env::var("SRS_SECRET").wrap_err("SRS_SECRET must be set to use RELAY_HOST")?;
+        let srs_domain = match env::var("SRS_DOMAIN") {
+            Ok(domain) => domain,
+            Err(_) => allowed_domains
+                .first()
+                .cloned()
+                .ok_or_else(|| eyre!("SRS_DOMAIN must be set"))?,
+        };
+
+        Ok(Some(Self {
+            config: vortex_smtp_client::Config {
+                security,
+                credentials,
+                ..vortex_smtp_client::Config::new(host, port)
+            },
+            srs: Srs::new(secret, srs_domain),
+        }))
+    }
+
+    async fn send(&self, email: &Email, to: &str) -> Result<(), vortex_smtp_client::Error> {
+        let days = chrono::Utc::now().timestamp().max(0) as u64 / (24 * 60 * 60);
+        let sender = self.srs.forward(&email.mail_from, days);
+
+        let mut client = Client::connect(&self.config).await?;
+        client.send(&sender, &[to], email.data.as_bytes()).await?;
+        client.quit().await
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    /// The address to forward to.
+    pub to: String,
+    /// Only forward mail from this address, or from this domain if it's `@domain`.
+    #[serde(default)]
+    pub from: Option<String>,
+}
+
+impl Rule {
+    fn matches(&self, mail_from: &str) -> bool {
+        let Some(from) = &self.from else {
+            return true;
+        };
+        let mail_from = mail_from.to_lowercase();
+        let from = from.to_lowercase();
+        match from.strip_prefix('@') {
+            Some(domain) => mail_from
+                .rsplit_once('@')
+                .is_some_and(|(_, sender_domain)| sender_domain == domain),
+            None => mail_from == from,
+        }
+    }
+}
+
+/// Relays `email` to every target `recipient` has a matching rule for.
+pub async fn forward(state: &AppState, recipient: &str, email: &Email) {
+    let Some(relay) = &state.relay else {
+        return;
+    };
+
+    let rules = match state.forwarding.forwarding_rules(recipient).await {
+        Ok(rules) => rules,
+        Err(e) => {
+            tracing::error!(recipient, error = %e, "Failed to load forwarding rules");
+            return;
+        }
+    };
+
+    for rule in rules.iter().filter(|rule| rule.matches(&email.mail_from)) {
+        match relay.send(email, &rule.to).await {
+            Ok(()) => tracing::info!(recipient, to = rule.to, "Email forwarded"),
+            Err(e) => {
+                tracing::error!(recipient, to = rule.to, error = %e, "Failed to forward email");
+            }
+        }
+    }
+}
+
+/// Checks the request's Basic auth credentials belong to someone who owns `inbox`.
+fn authorize(headers: &HeaderMap, state: &AppState, inbox: &str) -> Result<(), StatusCode> {
+    let (username, password) = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_basic_auth)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let user = submission::find_user(&state.submission_users, &username, &password)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    if user.owns(inbox) {
+        Ok(())
+    } else {
+        tracing::warn!(
+            inbox,
+            username,
+            "Forwarding change for an inbox the user doesn't own"
+        );
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+fn parse_basic_auth(header: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = header.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let credentials = String::from_utf8(BASE64.decode(credentials.trim()).ok()?).ok()?;
+    let (username, password) = credentials.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+#[tracing::instrument(skip(state, headers))]
+pub async fn get_rules(
+    State(state): State<AppState>,
+    Path(email): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Rule>>, StatusCode> {
+    authorize(&headers, &state, &email)?;
+
+    let rules = state
+        .forwarding
+        .forwarding_rules(&email)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to load forwarding rules");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(rules))
+}
+
+#[tracing::instrument(skip(state, headers))]
+pub async fn add_rule(
+    State(state): State<AppState>,
+    Path(email): Path<String>,
+    headers: HeaderMap,
+    Json(rule): Json<Rule>,
+) -> Result<StatusCode, StatusCode> {
+    authorize(&headers, &state, &email)?;
+    if state.relay.is_none() {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    }
+
+    // Forwarding to one of our own inboxes could loop forever.
+    let Some(target) = EmailAddress::parse(&rule.to, None) else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    if state
+        .allowed_domains
+        .iter()
+        .any(|domain| target.get_domain().eq_ignore_ascii_case(domain))
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    state
+        .forwarding
+        .add_forwarding_rule(&email, &rule)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to save forwarding rule");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    tracing::info!(email, to = rule.to, "Forwarding rule added");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[tracing::instrument(skip(state, headers))]
+pub async fn delete_rule(
+    State(state): State<AppState>,
+    Path((email, to)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    authorize(&headers, &state, &email)?;
+
+    let removed = state
+        .forwarding
+        .delete_forwarding_rule(&email, &to)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to delete forwarding rule");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    if !removed {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    tracing::info!(email, to, "Forwarding rule removed");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_matches() {
+        let rule = |from: Option<&str>| Rule {
+            to: "me@example.com".to_string(),
+            from: from.map(String::from),
+        };
+
+        assert!(rule(None).matches("anyone@example.org"));
+        assert!(rule(Some("otp@bank.example")).matches("OTP@bank.example"));
+        assert!(!rule(Some("otp@bank.example")).matches("spam@bank.example"));
+        assert!(rule(Some("@bank.example")).matches("otp@bank.example"));
+        assert!(!rule(Some("@bank.example")).matches("otp@notbank.example"));
+    }
+
+    #[test]
+    fn test_parse_basic_auth() {
+        assert_eq!(
+            parse_basic_auth("Basic YWxpY2U6aHVudGVyMjpzdGlsbA=="),
+            Some(("alice".to_string(), "hunter2:still".to_string()))
+        );
+        assert_eq!(parse_basic_auth("Bearer YWxpY2U6aHVudGVyMg=="), None);
+        assert_eq!(parse_basic_auth("Basic not-base64"), None);
+    }
+
+    fn basic_auth(credentials: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = format!("Basic {}", BASE64.encode(credentials));
+        headers.insert(AUTHORIZATION, value.parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_rule_endpoints() {
+        let state = AppState {
+            submission_users: std::sync::Arc::new(
+                submission::parse_users("alice:hunter2:alice@vortex.dev").unwrap(),
+            ),
+            relay: Some(std::sync::Arc::new(Relay {
+                config: vortex_smtp_client::Config::new("localhost", 587),
+                srs: Srs::new("secret", "vortex.dev"),
+            })),
+            ..crate::tests::test_state()
+        };
+        let inbox = || Path("alice@vortex.dev".to_string());
+        let rule = Rule {
+            to: "alice@example.com".to_string(),
+            from: None,
+        };
+        let add = |headers| add_rule(State(state.clone()), inbox(), headers, Json(rule.clone()));
+
+        assert_eq!(add(HeaderMap::new()).await, Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(
+            add(basic_auth("alice:wrong")).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            add_rule(
+                State(state.clone()),
+                Path("bob@vortex.dev".to_string()),
+                basic_auth("alice:hunter2"),
+                Json(rule.clone())
+            )
+            .await,
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            add_rule(
+                State(state.clone()),
+                inbox(),
+                basic_auth("alice:hunter2"),
+                Json(Rule {
+                    to: "loop@vortex.dev".to_string(),
+                    from: None,
+                })
+            )
+            .await,
+            Err(StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(
+            add(basic_auth("alice:hunter2")).await,
+            Ok(StatusCode::NO_CONTENT)
+        );
+
+        assert_eq!(
+            get_rules(State(state.clone()), inbox(), HeaderMap::new())
+                .await
+                .unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+        let Json(rules) = get_rules(State(state.clone()), inbox(), basic_auth("alice:hunter2"))
+            .await
+            .unwrap();
+        assert_eq!(rules, std::slice::from_ref(&rule));
+
+        let path = || Path(("alice@vortex.dev".to_string(), rule.to.clone()));
+        assert_eq!(
+            delete_rule(State(state.clone()), path(), basic_auth("alice:wrong")).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            delete_rule(State(state.clone()), path(), basic_auth("alice:hunter2")).await,
+            Ok(StatusCode::NO_CONTENT)
+        );
+        assert_eq!(
+            delete_rule(State(state.clone()), path(), basic_auth("alice:hunter2")).await,
+            Err(StatusCode::NOT_FOUND)
+        );
+    }
+}
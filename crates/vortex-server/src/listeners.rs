@@ -0,0 +1,261 @@
+use std::fmt::{self, Display};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use color_eyre::{eyre::eyre, Result};
+use vortex_smtp::{auth::AuthMode, tarpit::Tarpit, tls::ServerConfig, Protocol};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// What a listener is for, which decides its TLS and AUTH settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerKind {
+    /// Plain SMTP for incoming mail, like port 25.
+    Smtp,
+    /// SMTP for incoming mail over implicit TLS, like port 465.
+    Smtps,
+    /// Authenticated submission, like port 587.
+    Submission,
+    /// Authenticated submission over implicit TLS.
+    Submissions,
+    /// LMTP, for an MTA like Postfix to hand mail over to us.
+    Lmtp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listener {
+    pub kind: ListenerKind,
+    pub address: ListenAddress,
+    pub proxy_protocol: bool,
+    /// Slow down clients that keep getting things wrong, and hang up on them after
+    /// this many errors. Off unless the listener asks for it.
+    pub tarpit: Option<u32>,
+}
+
+impl Listener {
+    pub fn smtp_config(&self, tls: Option<&Arc<ServerConfig>>) -> Result<vortex_smtp::Config> {
+        let (auth, implicit_tls) = match self.kind {
+            ListenerKind::Smtp | ListenerKind::Lmtp => (AuthMode::Disabled, false),
+            ListenerKind::Smtps => (AuthMode::Disabled, true),
+            ListenerKind::Submission => (AuthMode::Required, false),
+            ListenerKind::Submissions => (AuthMode::Required, true),
+        };
+        let protocol = match self.kind {
+            ListenerKind::Lmtp => Protocol::Lmtp,
+            _ => Protocol::Smtp,
+        };
+        let tarpit = self.tarpit.map(|disconnect_after| Tarpit {
+            disconnect_after,
+            ..Tarpit::default()
+        });
+        // Plaintext listeners offer STARTTLS if we have a certificate. Submission needs
+        // it, since we don't take passwords in plaintext.
+        let starttls = match self.kind {
+            ListenerKind::Smtp => tls.cloned(),
+            ListenerKind::Submission => Some(tls.cloned().ok_or_else(|| {
+                eyre!("listener {self} needs TLS for STARTTLS, but TLS_CERT/TLS_KEY aren't set")
+            })?),
+            _ => None,
+        };
+        let implicit_tls = match (implicit_tls, tls) {
+            (false, _) => None,
+            (true, Some(tls)) => Some(tls.clone()),
+            (true, None) => {
+                return Err(eyre!(
+                    "listener {self} needs TLS, but TLS_CERT/TLS_KEY aren't set"
+                ))
+            }
+        };
+
+        Ok(vortex_smtp::Config {
+            auth,
+            implicit_tls,
+            starttls,
+            proxy_protocol: self.proxy_protocol,
+            protocol,
+            tarpit,
+            ..Default::default()
+        })
+    }
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.kind {
+            ListenerKind::Smtp => "smtp",
+            ListenerKind::Smtps => "smtps",
+            ListenerKind::Submission => "submission",
+            ListenerKind::Submissions => "submissions",
+            ListenerKind::Lmtp => "lmtp",
+        };
+        match &self.address {
+            ListenAddress::Tcp(addr) => write!(f, "{scheme}://{addr}")?,
+            ListenAddress::Unix(path) => write!(f, "{scheme}://{}", path.display())?,
+        }
+        let mut options = Vec::new();
+        if self.proxy_protocol {
+            options.push("proxy".to_string());
+        }
+        if let Some(disconnect_after) = self.tarpit {
+            options.push(format!("tarpit={disconnect_after}"));
+        }
+        if !options.is_empty() {
+            write!(f, "?{}", options.join("&"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the `SMTP_LISTENERS` env var: a comma-separated list of
+/// `<kind>://<address>[?<options>]`, where `kind` is `smtp`, `smtps`, `submission`,
+/// `submissions` or `lmtp`, and `address` is either `host:port` (`[::]:25` for IPv6) or
+/// an absolute path to a Unix socket (`smtp:///run/vortex/smtp.sock`).
+///
+/// Options are separated by `&`:
+/// - `proxy` expects the PROXY protocol from a load balancer.
+/// - `tarpit` slows down clients that keep sending unknown recipients or commands, and
+///   hangs up on them after 20 errors. `tarpit=<errors>` picks a different limit. Only
+///   `smtp` and `smtps` listeners take it, since everyone else is either logged in or
+///   our own MTA. Behind a load balancer, use it with `proxy`, or every client will
+///   share the balancer's address.
+pub fn parse_listeners(spec: &str) -> Result<Vec<Listener>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(parse_listener)
+        .collect()
+}
+
+fn parse_listener(entry: &str) -> Result<Listener> {
+    let (scheme, rest) = entry
+        .split_once("://")
+        .ok_or_else(|| eyre!("listener {entry:?} must look like <kind>://<address>"))?;
+    let kind = match scheme {
+        "smtp" => ListenerKind::Smtp,
+        "smtps" => ListenerKind::Smtps,
+        "submission" => ListenerKind::Submission,
+        "submissions" => ListenerKind::Submissions,
+        "lmtp" => ListenerKind::Lmtp,
+        _ => return Err(eyre!("listener {entry:?} has an unknown kind {scheme:?}")),
+    };
+
+    let (address, options) = rest.split_once('?').unwrap_or((rest, ""));
+    let mut proxy_protocol = false;
+    let mut tarpit = None;
+    for option in options.split('&').filter(|option| !option.is_empty()) {
+        match option.split_once('=') {
+            None if option == "proxy" => proxy_protocol = true,
+            None if option == "tarpit" => tarpit = Some(Tarpit::default().disconnect_after),
+            Some(("tarpit", errors)) => {
+                tarpit = Some(errors.parse().map_err(|e| {
+                    eyre!("listener {entry:?} has an invalid tarpit limit {errors:?}: {e}")
+                })?)
+            }
+            _ => return Err(eyre!("listener {entry:?} has an unknown option {option:?}")),
+        }
+    }
+    if tarpit.is_some() && !matches!(kind, ListenerKind::Smtp | ListenerKind::Smtps) {
+        return Err(eyre!(
+            "listener {entry:?} can't have a tarpit, only smtp and smtps listeners can"
+        ));
+    }
+    let address = if address.starts_with('/') {
+        ListenAddress::Unix(PathBuf::from(address))
+    } else {
+        ListenAddress::Tcp(
+            address
+                .parse()
+                .map_err(|e| eyre!("listener {entry:?} has an invalid address: {e}"))?,
+        )
+    };
+
+    Ok(Listener {
+        kind,
+        address,
+        proxy_protocol,
+        tarpit,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listeners() {
+        let listeners = parse_listeners(
+            "smtp://0.0.0.0:25, smtp://[::]:25?proxy&tarpit=5,submission://0.0.0.0:587,lmtp:///run/vortex/lmtp.sock",
+        )
+        .unwrap();
+
+        assert_eq!(
+            listeners,
+            vec![
+                Listener {
+                    kind: ListenerKind::Smtp,
+                    address: ListenAddress::Tcp("0.0.0.0:25".parse().unwrap()),
+                    proxy_protocol: false,
+                    tarpit: None,
+                },
+                Listener {
+                    kind: ListenerKind::Smtp,
+                    address: ListenAddress::Tcp("[::]:25".parse().unwrap()),
+                    proxy_protocol: true,
+                    tarpit: Some(5),
+                },
+                Listener {
+                    kind: ListenerKind::Submission,
+                    address: ListenAddress::Tcp("0.0.0.0:587".parse().unwrap()),
+                    proxy_protocol: false,
+                    tarpit: None,
+                },
+                Listener {
+                    kind: ListenerKind::Lmtp,
+                    address: ListenAddress::Unix(PathBuf::from("/run/vortex/lmtp.sock")),
+                    proxy_protocol: false,
+                    tarpit: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_listeners_rejects_garbage() {
+        assert!(parse_listeners("0.0.0.0:25").is_err());
+        assert!(parse_listeners("imap://0.0.0.0:143").is_err());
+        assert!(parse_listeners("smtp://localhost").is_err());
+        assert!(parse_listeners("smtp://0.0.0.0:25?fast").is_err());
+        assert!(parse_listeners("smtp://0.0.0.0:25?tarpit=lots").is_err());
+        assert!(parse_listeners("submission://0.0.0.0:587?tarpit").is_err());
+    }
+
+    #[test]
+    fn test_tarpit_is_opt_in() {
+        let listeners = parse_listeners("smtp://0.0.0.0:25,smtp://0.0.0.0:2525?tarpit").unwrap();
+        assert!(listeners[0].smtp_config(None).unwrap().tarpit.is_none());
+        let tarpit = listeners[1].smtp_config(None).unwrap().tarpit.unwrap();
+        assert_eq!(tarpit.disconnect_after, 20);
+        assert_eq!(listeners[1].to_string(), "smtp://0.0.0.0:2525?tarpit=20");
+    }
+
+    #[test]
+    fn test_tls_listener_needs_certificate() {
+        let listeners = parse_listeners("smtps://0.0.0.0:465,submission://0.0.0.0:587").unwrap();
+        assert!(listeners[0].smtp_config(None).is_err());
+        assert!(listeners[1].smtp_config(None).is_err());
+    }
+}
@@ -1,23 +1,51 @@
 use std::env;
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::{
-    extract::{Path, State},
-    http::{HeaderValue, Method, StatusCode},
+    extract::{Path, Query, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderValue, Method, StatusCode,
+    },
+    response::{IntoResponse, Response},
     routing::{delete, get},
     Json, Router,
 };
-use color_eyre::{eyre::Context, Result};
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
 use email_address_parser::EmailAddress;
-use redis::{aio::ConnectionManager, AsyncCommands, Client, RedisResult};
+use mail_auth::MessageAuthenticator;
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, task::JoinSet};
 use tower_http::cors::CorsLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-use vortex_smtp::{event::Event, Email};
+use vortex_smtp::{
+    auth::AuthMode,
+    event::{Delivery, Event},
+    milter::{DefaultAction, Milter},
+    Email, RecipientVerdict, VerifyPolicy,
+};
 
-const HTTP_ADDR: &str = "0.0.0.0:3000";
+mod auth_results;
+mod forwarding;
+mod listeners;
+mod quota;
+mod srs;
+mod storage;
+mod submission;
+mod summary;
+
+use listeners::{ListenAddress, Listener, ListenerKind};
+use storage::{
+    ForwardingStore, MailStore, MaildirStore, MemoryStore, Page, RedisStore, SqliteStore, Ttl,
+};
+
+const DEFAULT_HTTP_ADDR: &str = "0.0.0.0:3000";
+// Only used when SMTP_LISTENERS isn't set.
 const SMTP_ADDR: &str = "0.0.0.0:2525";
 
 #[rustfmt::skip]
@@ -33,12 +61,21 @@ const BLOCKED_USERNAMES: &[&str] = &[
 struct ExtendedEmail {
     email: Email,
     timestamp: String,
+    #[serde(default)]
+    authentication: Option<auth_results::AuthSummary>,
 }
 
 #[derive(Clone)]
 struct AppState {
-    redis_manager: ConnectionManager,
+    store: Arc<dyn MailStore>,
+    forwarding: Arc<dyn ForwardingStore>,
     allowed_domains: Arc<Vec<String>>,
+    authenticator: Arc<MessageAuthenticator>,
+    submission_users: Arc<Vec<submission::SubmissionUser>>,
+    ttl: Ttl,
+    quota: quota::Quota,
+    /// Set if forwarding is turned on.
+    relay: Option<Arc<forwarding::Relay>>,
 }
 
 #[tracing::instrument]
@@ -48,77 +85,172 @@ async fn server_main() -> Result<()> {
     let allowed_domains: Arc<Vec<String>> =
         Arc::new(allowed_domains.split(',').map(String::from).collect());
     let frontend_domain = env::var("FRONTEND_DOMAIN").wrap_err("FRONTEND_DOMAIN must be set")?;
+    let submission_users = match env::var("SMTP_USERS") {
+        Ok(spec) => submission::parse_users(&spec).wrap_err("Invalid SMTP_USERS")?,
+        Err(_) => Vec::new(),
+    };
 
-    let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
-    let redis_client = Client::open(redis_url.clone())
-        .wrap_err_with(|| format!("Failed to connect to Redis at {redis_url}"))?;
+    // Forwarding rules live alongside the mail, in the same backend.
+    fn backend<S: MailStore + ForwardingStore + 'static>(
+        store: S,
+    ) -> (Arc<dyn MailStore>, Arc<dyn ForwardingStore>) {
+        let store = Arc::new(store);
+        (store.clone(), store)
+    }
+    let (store, forwarding) = match env::var("STORAGE").as_deref() {
+        Ok("redis") | Err(_) => {
+            let redis_url =
+                env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+            let store = RedisStore::connect(&redis_url).await?;
+            tracing::info!("Connected to Redis server at {}", redis_url);
+            backend(store)
+        }
+        Ok("sqlite") => {
+            let path = env::var("SQLITE_PATH").unwrap_or_else(|_| "vortex.db".to_string());
+            let store = SqliteStore::open(&path)?;
+            tracing::info!("Using SQLite database at {path}");
+            backend(store)
+        }
+        Ok("maildir") => {
+            let path = env::var("MAILDIR_PATH").unwrap_or_else(|_| "mail".to_string());
+            tracing::info!("Writing mail into Maildirs under {path}");
+            backend(MaildirStore::new(path))
+        }
+        // Handy for development, but everything's gone when the server stops.
+        Ok("memory") => {
+            tracing::warn!("Keeping mail in memory, it'll be lost on restart");
+            backend(MemoryStore::new())
+        }
+        Ok(other) => {
+            return Err(eyre!(
+                "STORAGE must be redis, sqlite, maildir or memory, not {other:?}"
+            ))
+        }
+    };
 
-    let redis_manager = ConnectionManager::new(redis_client)
-        .await
-        .wrap_err("Failed to establish Redis connection")?;
+    // In seconds. Both are off by default, so nothing is deleted unless asked for.
+    let ttl_from_env = |name| match env::var(name) {
+        Ok(secs) => secs
+            .parse()
+            .map(|secs| Some(Duration::from_secs(secs)))
+            .wrap_err_with(|| format!("Invalid {name}")),
+        Err(_) => Ok(None),
+    };
+    let ttl = Ttl {
+        message: ttl_from_env("MESSAGE_TTL")?,
+        inbox_idle: ttl_from_env("INBOX_IDLE_TTL")?,
+    };
+    // Pruning empties old inboxes, but only INBOX_IDLE_TTL ever deletes them, so
+    // without it they'd pile up forever.
+    if ttl.message.is_some() && ttl.inbox_idle.is_none() {
+        return Err(eyre!("MESSAGE_TTL needs INBOX_IDLE_TTL to be set too"));
+    }
+
+    // Used for SPF/DKIM/DMARC checks. Fall back to Cloudflare if there's no usable resolv.conf.
+    let authenticator = MessageAuthenticator::new_system_conf()
+        .or_else(|_| MessageAuthenticator::new_cloudflare_tls())
+        .wrap_err("Failed to create DNS resolver")?;
 
-    // test the connection!
-    let mut test_conn = redis_manager.clone();
-    let _: String = redis::cmd("PING")
-        .query_async(&mut test_conn)
-        .await
-        .wrap_err("Failed to ping Redis server")?;
-    tracing::info!("Connected to Redis server at {}", redis_url);
+    let relay = forwarding::Relay::from_env(&allowed_domains)?;
+    if relay.is_some() && submission_users.is_empty() {
+        tracing::warn!("RELAY_HOST is set, but without SMTP_USERS nobody can add forwarding rules");
+    }
 
     let app_state = AppState {
-        redis_manager,
+        store,
+        forwarding,
         allowed_domains,
+        authenticator: Arc::new(authenticator),
+        submission_users: Arc::new(submission_users),
+        ttl,
+        quota: quota::Quota::from_env()?,
+        relay: relay.map(Arc::new),
     };
 
-    let smtp_validator_state = app_state.clone();
-    let smtp_event_state = app_state.clone();
-    let smtp_server = tokio::spawn(async move {
-        tracing::info!("SMTP server listening on {SMTP_ADDR}");
-        vortex_smtp::listen(
-            SMTP_ADDR,
-            move |email| {
-                let state = smtp_validator_state.clone();
-                let email_str = email.to_string();
-                async move {
-                    validate_vortex_email_with_redis(&email_str, &state).await
-                }
-            },
-            move |event| {
-                #[allow(irrefutable_let_patterns)]
-                if let Event::EmailReceived(email) = &event {
-                    tracing::debug!(
-                        mail_from = email.mail_from,
-                        rcpt_to = email.rcpt_to.join(", "),
-                        "email received via SMTP"
-                    );
-
-                    let timestamp = chrono::Utc::now().to_rfc3339();
-                    let state = smtp_event_state.clone();
-                    let email_clone = email.clone();
-
-                    tokio::spawn(async move {
-                        for recipient in &email_clone.rcpt_to {
-                            match store_email_in_redis(&state, recipient, &email_clone, &timestamp).await {
-                                Ok(_) => {
-                                    tracing::debug!(recipient, "Email stored in Redis");
-                                }
-                                Err(e) => {
-                                    tracing::error!(recipient, error = %e, "Failed to store email in Redis");
-                                }
-                            }
-                        }
-                    });
-                }
-            },
-        )
-        .await
-        .wrap_err("SMTP server failed")
-    });
+    let tls_config = match (env::var("TLS_CERT"), env::var("TLS_KEY")) {
+        (Ok(cert_path), Ok(key_path)) => Some(
+            vortex_smtp::tls::load_server_config(&cert_path, &key_path)
+                .wrap_err_with(|| format!("Failed to load TLS certificate from {cert_path}"))?,
+        ),
+        _ => None,
+    };
+
+    let smtp_listeners = match env::var("SMTP_LISTENERS") {
+        Ok(spec) => listeners::parse_listeners(&spec).wrap_err("Invalid SMTP_LISTENERS")?,
+        Err(_) => {
+            // Set this when running behind a load balancer that speaks the PROXY protocol.
+            let proxy_protocol = env::var("SMTP_PROXY_PROTOCOL").is_ok_and(|value| value == "true");
+            // SMTPS and submission want privileged ports, so they're only there if
+            // SMTP_LISTENERS asks for them.
+            if !app_state.submission_users.is_empty() {
+                tracing::warn!(
+                    "SMTP_USERS is set, but submission needs a submission:// listener in SMTP_LISTENERS"
+                );
+            }
+            vec![Listener {
+                kind: ListenerKind::Smtp,
+                address: ListenAddress::Tcp(SMTP_ADDR.parse().expect("default address is valid")),
+                proxy_protocol,
+                tarpit: None,
+            }]
+        }
+    };
+
+    // Whether VRFY should reveal if an inbox exists. Off by default.
+    let verify = match env::var("SMTP_VRFY").as_deref() {
+        Ok("check") => VerifyPolicy::Check,
+        _ => VerifyPolicy::CannotVerify,
+    };
 
+    // Content filters like rspamd or clamav-milter, e.g. `inet:127.0.0.1:11332,unix:/run/clamav/clamav-milter.ctl`.
+    let default_action = match env::var("SMTP_MILTER_DEFAULT_ACTION").as_deref() {
+        Ok("accept") => DefaultAction::Accept,
+        Ok("tempfail") | Err(_) => DefaultAction::TempFail,
+        Ok(other) => {
+            return Err(eyre!(
+                "SMTP_MILTER_DEFAULT_ACTION must be accept or tempfail, not {other:?}"
+            ))
+        }
+    };
+    let milters = match env::var("SMTP_MILTERS") {
+        Ok(spec) => spec
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .map(|address| {
+                Ok(Milter {
+                    default_action,
+                    ..Milter::new(address.parse().map_err(|e: String| eyre!(e))?)
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+            .wrap_err("Invalid SMTP_MILTERS")?,
+        Err(_) => Vec::new(),
+    };
+
+    if ttl.message.is_some() || ttl.inbox_idle.is_some() {
+        tokio::spawn(storage::sweep(app_state.store.clone(), ttl));
+    }
+
+    let mut servers = JoinSet::new();
+    for listener in smtp_listeners {
+        let config = vortex_smtp::Config {
+            verify,
+            milters: milters.clone(),
+            ..listener.smtp_config(tls_config.as_ref())?
+        };
+        if config.auth == AuthMode::Required && app_state.submission_users.is_empty() {
+            tracing::warn!("{listener} requires AUTH, but SMTP_USERS isn't set");
+        }
+        servers.spawn(run_smtp_server(app_state.clone(), listener, config));
+    }
+
+    let http_addr = env::var("HTTP_ADDR").unwrap_or_else(|_| DEFAULT_HTTP_ADDR.to_string());
     let http_state = app_state.clone();
-    let http_server = tokio::spawn(async move {
+    servers.spawn(async move {
         let cors = CorsLayer::new()
-            .allow_methods([Method::GET, Method::DELETE])
+            .allow_methods([Method::GET, Method::POST, Method::DELETE])
+            .allow_headers([AUTHORIZATION, CONTENT_TYPE])
             .allow_origin(
                 frontend_domain
                     .parse::<HeaderValue>()
@@ -132,128 +264,317 @@ async fn server_main() -> Result<()> {
             )
             .route("/emails/{email}", get(get_emails))
             .route("/emails/{email}/clear", delete(clear_emails))
+            .route("/emails/{email}/search", get(search_emails))
+            .route("/emails/{email}/{id}", get(get_email).delete(delete_email))
+            .route(
+                "/emails/{email}/forwarding",
+                get(forwarding::get_rules).post(forwarding::add_rule),
+            )
+            .route(
+                "/emails/{email}/forwarding/{to}",
+                delete(forwarding::delete_rule),
+            )
             .with_state(http_state)
             .layer(cors);
 
-        let listener = TcpListener::bind(HTTP_ADDR)
+        let listener = TcpListener::bind(&http_addr)
             .await
-            .wrap_err_with(|| format!("Failed to bind HTTP server to {HTTP_ADDR}"))?;
+            .wrap_err_with(|| format!("Failed to bind HTTP server to {http_addr}"))?;
 
-        tracing::info!("HTTP server listening on {HTTP_ADDR}");
+        tracing::info!("HTTP server listening on {http_addr}");
         axum::serve(listener, router)
             .await
             .wrap_err("HTTP server failed")
     });
 
     tracing::info!("Starting servers...");
-    let (http_res, smtp_res) = tokio::try_join!(http_server, smtp_server)?;
-    if let Err(err) = http_res {
-        tracing::error!("HTTP server failed: {err}");
-        return Err(err);
-    }
-    if let Err(err) = smtp_res {
-        tracing::error!("SMTP server failed: {err}");
-        return Err(err);
+    while let Some(res) = servers.join_next().await {
+        if let Err(err) = res? {
+            tracing::error!("{err}");
+            return Err(err);
+        }
     }
 
     Ok(())
 }
 
-async fn store_email_in_redis(
-    state: &AppState,
-    recipient: &str,
-    email: &Email,
-    timestamp: &str,
-) -> RedisResult<()> {
-    let mut conn = state.redis_manager.clone();
-
-    let key = format!("emails:{recipient}");
-
-    let timestamp_dt = chrono::DateTime::parse_from_rfc3339(timestamp)
-        .map_err(|e| {
-            redis::RedisError::from((
-                redis::ErrorKind::TypeError,
-                "Timestamp parse error",
-                e.to_string(),
+async fn run_smtp_server(
+    state: AppState,
+    listener: Listener,
+    config: vortex_smtp::Config,
+) -> Result<()> {
+    let auth = config.auth;
+    let validator_state = state.clone();
+    let credentials_state = state.clone();
+    let validate_email = move |email: &str, username: Option<&str>| {
+        let state = validator_state.clone();
+        let email = email.to_string();
+        let username = username.map(String::from);
+        async move {
+            let valid = match auth {
+                // Authenticated clients can deposit into any inbox they own,
+                // whether or not it has been opened yet.
+                AuthMode::Required => {
+                    username.is_some_and(|username| {
+                        state
+                            .submission_users
+                            .iter()
+                            .any(|user| user.username == username && user.owns(&email))
+                    }) && validate_vortex_email(&email, &state.allowed_domains)
+                }
+                AuthMode::Disabled => validate_vortex_email_with_store(&email, &state).await,
+            };
+            if !valid {
+                return RecipientVerdict::Unknown;
+            }
+            if state.quota.is_unlimited() {
+                return RecipientVerdict::Accept;
+            }
+
+            match state.store.usage(&email).await {
+                Ok(usage) => state.quota.check(usage),
+                Err(e) => {
+                    // Better to go over quota than lose mail.
+                    tracing::error!(email, error = %e, "Failed to check inbox usage");
+                    RecipientVerdict::Accept
+                }
+            }
+        }
+    };
+    let validate_credentials = move |username: &str, password: &str| {
+        let valid = submission::find_user(&credentials_state.submission_users, username, password)
+            .is_some();
+        async move { valid }
+    };
+    let handle_event = move |event| handle_smtp_event(state.clone(), event);
+
+    tracing::info!("SMTP server listening on {listener}");
+    match &listener.address {
+        ListenAddress::Tcp(addr) => {
+            vortex_smtp::listen(
+                *addr,
+                config,
+                validate_email,
+                validate_credentials,
+                handle_event,
+            )
+            .await
+        }
+        #[cfg(unix)]
+        ListenAddress::Unix(path) => {
+            use std::os::unix::fs::FileTypeExt;
+
+            // Clean up after a previous run, otherwise binding fails. Anything that
+            // isn't a socket is left alone, in case the path is a typo.
+            match std::fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.file_type().is_socket() => {
+                    std::fs::remove_file(path).wrap_err_with(|| {
+                        format!("Failed to remove stale socket {}", path.display())
+                    })?;
+                }
+                Ok(_) => {
+                    return Err(eyre!(
+                        "{} already exists and isn't a socket",
+                        path.display()
+                    ))
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).wrap_err_with(|| format!("Failed to check {}", path.display()))
+                }
+            }
+            vortex_smtp::listen_unix(
+                path,
+                config,
+                validate_email,
+                validate_credentials,
+                handle_event,
+            )
+            .await
+        }
+        #[cfg(not(unix))]
+        ListenAddress::Unix(_) => {
+            return Err(eyre!(
+                "{listener} is a Unix socket, which this platform doesn't have"
             ))
-        })?
-        .with_timezone(&chrono::Utc);
-    let score = timestamp_dt.timestamp();
+        }
+    }
+    .wrap_err_with(|| format!("SMTP server on {listener} failed"))
+}
+
+/// Stores an email for each of its recipients, and says which of them it couldn't be
+/// stored for, so the client isn't told it was delivered when it wasn't.
+async fn handle_smtp_event(state: AppState, event: Event) -> Delivery {
+    let Event::EmailReceived(mut email) = event;
+    tracing::debug!(
+        mail_from = email.mail_from,
+        rcpt_to = email.rcpt_to.join(", "),
+        authenticated_as = email.authenticated_as,
+        "email received via SMTP"
+    );
+
+    let timestamp = chrono::Utc::now().to_rfc3339();
+    let results = auth_results::evaluate(&state.authenticator, &email).await;
+    email.data = auth_results::strip_own_headers(&email.data);
+    let authentication = match results {
+        Some((header, summary)) => {
+            email.data.insert_str(0, &header);
+            Some(summary)
+        }
+        None => {
+            tracing::warn!("Failed to parse email for authentication checks");
+            None
+        }
+    };
 
     let extended_email = ExtendedEmail {
         email: email.clone(),
-        timestamp: timestamp.to_string(),
+        timestamp,
+        authentication,
     };
-    let json = serde_json::to_string(&extended_email).map_err(|e| {
-        redis::RedisError::from((
-            redis::ErrorKind::IoError,
-            "Serialization error",
-            e.to_string(),
-        ))
-    })?;
+    let mut delivery = Delivery::default();
+    for recipient in &email.rcpt_to {
+        match state.store.store(recipient, &extended_email).await {
+            Ok(()) => {
+                tracing::debug!(recipient, "Email stored");
+                if let Err(e) = state.ttl.touch(&*state.store, recipient).await {
+                    tracing::error!(recipient, error = %e, "Failed to set inbox TTL");
+                }
+                match state.quota.enforce(&*state.store, recipient).await {
+                    Ok(0) => {}
+                    Ok(evicted) => {
+                        tracing::debug!(recipient, evicted, "Evicted old emails");
+                    }
+                    Err(e) => {
+                        tracing::error!(recipient, error = %e, "Failed to enforce quota");
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::error!(recipient, error = %e, "Failed to store email");
+                delivery.failed.push(recipient.clone());
+            }
+        }
+    }
 
-    // ConnectionManager handles reconnection automatically :D
-    let _: () = conn.zadd(&key, json, score).await?;
+    // Relaying can take a while, and the client doesn't need to wait for it. If we
+    // couldn't keep a copy, the client will try again, and we'd forward it twice.
+    let stored: Vec<String> = email
+        .rcpt_to
+        .iter()
+        .filter(|recipient| !delivery.failed.contains(recipient))
+        .cloned()
+        .collect();
+    tokio::spawn(async move {
+        for recipient in &stored {
+            forwarding::forward(&state, recipient, &email).await;
+        }
+    });
+    delivery
+}
 
-    Ok(())
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    limit: Option<usize>,
+    /// The ID of the last email on the previous page.
+    before: Option<String>,
+    /// Only return an [`EmailSummary`](summary::EmailSummary) of each email.
+    #[serde(default)]
+    summary: bool,
 }
 
-// lua script that ensures the key exists as an empty sorted set and returns all emails
-// what's the point, you ask? this way we can ensure that the key exists and is a sorted set
-// - *in a single roundtrip!*
-//
-// you might also ask - why the sentinel? because we want to ensure that the key is not empty
-// as otherwise redis will remove the key when we try to read it
-const ENSURE_ZSET_SCRIPT: &str = r#"
-    local key = KEYS[1]
-    local sentinel = ARGV[1]
+#[tracing::instrument(skip(state))]
+async fn get_emails(
+    State(state): State<AppState>,
+    Path(email): Path<String>,
+    Query(query): Query<ListQuery>,
+) -> Result<Response, StatusCode> {
+    if !validate_vortex_email(&email, &state.allowed_domains) {
+        tracing::warn!(email, "Invalid domain in GET request");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let page = Page {
+        limit: query.limit,
+        before: query.before,
+        // The sweeper might not have got to these yet.
+        since: state.ttl.cutoff(),
+    };
+    let response = if query.summary {
+        state
+            .store
+            .list_summaries(&email, &page)
+            .await
+            .map(|summaries| Json(summaries).into_response())
+    } else {
+        state
+            .store
+            .list_page(&email, &page)
+            .await
+            .map(|emails| Json(emails).into_response())
+    };
+    let response = response.map_err(|e| {
+        tracing::error!(error = %e, "Failed to list emails");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if let Err(e) = state.ttl.touch(&*state.store, &email).await {
+        tracing::error!(error = %e, "Failed to set inbox TTL");
+    }
+    Ok(response)
+}
 
-    -- Add sentinel member if it doesn't exist (and thus create the key)
-    redis.call('ZADD', key, 'NX', -1, sentinel)
+#[tracing::instrument(skip(state))]
+async fn get_email(
+    State(state): State<AppState>,
+    Path((email, id)): Path<(String, String)>,
+) -> Result<Json<ExtendedEmail>, StatusCode> {
+    if !validate_vortex_email(&email, &state.allowed_domains) {
+        tracing::warn!(email, "Invalid domain in GET request");
+        return Err(StatusCode::BAD_REQUEST);
+    }
 
-    -- Fetch everything newest-first
-    local all = redis.call('ZREVRANGE', key, 0, -1)
+    let found = state.store.get(&email, &id).await.map_err(|e| {
+        tracing::error!(error = %e, "Failed to get email");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if let Err(e) = state.ttl.touch(&*state.store, &email).await {
+        tracing::error!(error = %e, "Failed to set inbox TTL");
+    }
 
-    -- Remove sentinel from the result set
-    for i = #all, 1, -1 do
-        if all[i] == sentinel then
-            table.remove(all, i)
-        end
-    end
+    match found {
+        Some(found) if !state.ttl.is_expired(&found) => Ok(Json(found)),
+        _ => Err(StatusCode::NOT_FOUND),
+    }
+}
 
-    return all
-"#;
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    q: String,
+}
 
 #[tracing::instrument(skip(state))]
-async fn get_emails(
+async fn search_emails(
     State(state): State<AppState>,
     Path(email): Path<String>,
-) -> Result<(StatusCode, Json<Vec<ExtendedEmail>>), StatusCode> {
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<ExtendedEmail>>, StatusCode> {
     if !validate_vortex_email(&email, &state.allowed_domains) {
-        tracing::warn!(email, "Invalid domain in GET request");
+        tracing::warn!(email, "Invalid domain in search request");
         return Err(StatusCode::BAD_REQUEST);
     }
 
-    let key = format!("emails:{email}");
-    let mut conn = state.redis_manager.clone();
-
-    let sentinel = "__empty__";
-    let email_jsons: Vec<String> = redis::Script::new(ENSURE_ZSET_SCRIPT)
-        .key(&key)
-        .arg(sentinel)
-        .invoke_async(&mut conn)
-        .await
-        .map_err(|e| {
-            tracing::error!(error = %e, "Failed to execute Redis script");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-
-    let emails: Vec<ExtendedEmail> = email_jsons
-        .into_iter()
-        .filter_map(|json| serde_json::from_str(&json).ok())
-        .collect();
-
-    Ok((StatusCode::OK, Json(emails)))
+    match state.store.search(&email, &query.q).await {
+        Ok(Some(mut emails)) => {
+            emails.retain(|email| !state.ttl.is_expired(email));
+            Ok(Json(emails))
+        }
+        // Only some backends can search.
+        Ok(None) => Err(StatusCode::NOT_IMPLEMENTED),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to search emails");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
 }
 
 #[tracing::instrument(skip(state))]
@@ -266,11 +587,8 @@ async fn clear_emails(
         return Err(StatusCode::BAD_REQUEST);
     }
 
-    let key = format!("emails:{email}");
-    let mut conn = state.redis_manager.clone();
-
-    let _: () = conn.del(&key).await.map_err(|e| {
-        tracing::error!(error = %e, "Failed to clear emails from Redis");
+    state.store.clear(&email).await.map_err(|e| {
+        tracing::error!(error = %e, "Failed to clear emails");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
@@ -278,6 +596,28 @@ async fn clear_emails(
     Ok(StatusCode::NO_CONTENT)
 }
 
+#[tracing::instrument(skip(state))]
+async fn delete_email(
+    State(state): State<AppState>,
+    Path((email, id)): Path<(String, String)>,
+) -> Result<StatusCode, StatusCode> {
+    if !validate_vortex_email(&email, &state.allowed_domains) {
+        tracing::warn!(email, "Invalid domain requested for deletion");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let deleted = state.store.delete(&email, &id).await.map_err(|e| {
+        tracing::error!(error = %e, "Failed to delete email");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    tracing::info!(email, id, "Email deleted");
+    Ok(StatusCode::NO_CONTENT)
+}
+
 fn validate_vortex_email(email: &str, allowed_domains: &[String]) -> bool {
     let Some(parsed) = EmailAddress::parse(email, None) else {
         return false;
@@ -293,20 +633,16 @@ fn validate_vortex_email(email: &str, allowed_domains: &[String]) -> bool {
         .any(|domain| parsed.domain() == *domain)
 }
 
-async fn validate_vortex_email_with_redis(email: &str, state: &AppState) -> bool {
+async fn validate_vortex_email_with_store(email: &str, state: &AppState) -> bool {
     // First check domain validity
     if !validate_vortex_email(email, &state.allowed_domains) {
         return false;
     }
 
-    // Then get the Redis connection
-    let key = format!("emails:{email}");
-    let mut conn = state.redis_manager.clone();
-
-    match conn.exists(&key).await {
+    match state.store.exists(email).await {
         Ok(exists) => exists,
         Err(e) => {
-            tracing::error!(email, error = %e, "Failed to check email existence in Redis");
+            tracing::error!(email, error = %e, "Failed to check email existence");
             false
         }
     }
@@ -337,3 +673,166 @@ fn main() -> Result<()> {
         .build()?
         .block_on(server_main())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(crate) fn test_state() -> AppState {
+        let store = Arc::new(MemoryStore::new());
+        AppState {
+            store: store.clone(),
+            forwarding: store,
+            allowed_domains: Arc::new(vec!["vortex.dev".to_string()]),
+            authenticator: Arc::new(MessageAuthenticator::new_cloudflare_tls().unwrap()),
+            submission_users: Arc::new(Vec::new()),
+            ttl: Ttl::default(),
+            quota: quota::Quota::default(),
+            relay: None,
+        }
+    }
+
+    fn test_email(id: &str) -> ExtendedEmail {
+        storage::test_email(id, "2025-01-01T00:00:00Z", "Subject: hi\r\n\r\nhello\r\n")
+    }
+
+    /// Lists `inbox` through the HTTP handler.
+    async fn list_with(
+        state: &AppState,
+        inbox: &str,
+        query: ListQuery,
+    ) -> Result<Vec<serde_json::Value>, StatusCode> {
+        let response =
+            get_emails(State(state.clone()), Path(inbox.to_string()), Query(query)).await?;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        Ok(serde_json::from_slice(&body).unwrap())
+    }
+
+    async fn list(state: &AppState, inbox: &str) -> Result<Vec<ExtendedEmail>, StatusCode> {
+        let query = ListQuery {
+            limit: None,
+            before: None,
+            summary: false,
+        };
+        let emails = list_with(state, inbox, query).await?;
+        Ok(emails
+            .into_iter()
+            .map(|email| serde_json::from_value(email).unwrap())
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn test_list_and_clear_emails() {
+        let state = test_state();
+        let inbox = "alice@vortex.dev";
+        // Nobody has opened it, so it doesn't take mail yet.
+        assert!(!validate_vortex_email_with_store(inbox, &state).await);
+        assert!(list(&state, inbox).await.unwrap().is_empty());
+        assert!(validate_vortex_email_with_store(inbox, &state).await);
+
+        state.store.store(inbox, &test_email("abc")).await.unwrap();
+        let emails = list(&state, inbox).await.unwrap();
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].email.id, "abc");
+        assert_eq!(
+            list(&state, "alice@elsewhere.com").await.unwrap_err(),
+            StatusCode::BAD_REQUEST
+        );
+
+        assert_eq!(
+            clear_emails(State(state.clone()), Path(inbox.to_string())).await,
+            Ok(StatusCode::NO_CONTENT)
+        );
+        assert!(!validate_vortex_email_with_store(inbox, &state).await);
+    }
+
+    #[tokio::test]
+    async fn test_list_pages() {
+        let mut state = test_state();
+        state.ttl.message = Some(Duration::from_secs(3600));
+        let inbox = "alice@vortex.dev";
+        // Long expired, but the sweeper hasn't been round yet.
+        state.store.store(inbox, &test_email("old")).await.unwrap();
+        let now = chrono::Utc::now();
+        for (id, age) in [("a", 3), ("b", 2), ("c", 1)] {
+            let timestamp = (now - chrono::Duration::seconds(age)).to_rfc3339();
+            let email = storage::test_email(id, &timestamp, "Subject: hi\r\n\r\nhello\r\n");
+            state.store.store(inbox, &email).await.unwrap();
+        }
+
+        let query = |limit, before: Option<&str>, summary| ListQuery {
+            limit,
+            before: before.map(str::to_string),
+            summary,
+        };
+        let ids = |emails: Vec<serde_json::Value>, field: &str| {
+            emails
+                .iter()
+                .map(|email| email.pointer(field).unwrap().as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let page = list_with(&state, inbox, query(Some(2), None, false)).await;
+        assert_eq!(ids(page.unwrap(), "/email/id"), ["c", "b"]);
+        let page = list_with(&state, inbox, query(Some(2), Some("b"), false)).await;
+        assert_eq!(ids(page.unwrap(), "/email/id"), ["a"]);
+        let page = list_with(&state, inbox, query(None, Some("old"), false)).await;
+        assert!(page.unwrap().is_empty());
+
+        let summaries = list_with(&state, inbox, query(None, None, true))
+            .await
+            .unwrap();
+        assert_eq!(ids(summaries.clone(), "/id"), ["c", "b", "a"]);
+        assert_eq!(summaries[0]["subject"], "hi");
+        assert_eq!(summaries[0]["snippet"], "hello");
+        assert!(summaries[0].get("email").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_email() {
+        let state = test_state();
+        let inbox = "alice@vortex.dev".to_string();
+        state.store.store(&inbox, &test_email("abc")).await.unwrap();
+
+        let path = |id: &str| Path((inbox.clone(), id.to_string()));
+        let Json(found) = get_email(State(state.clone()), path("abc")).await.unwrap();
+        assert_eq!(found.email.id, "abc");
+        assert_eq!(
+            get_email(State(state.clone()), path("nope"))
+                .await
+                .unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get_email(
+                State(state),
+                Path(("alice@elsewhere.com".to_string(), "abc".to_string()))
+            )
+            .await
+            .unwrap_err(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_email() {
+        let state = test_state();
+        let inbox = "alice@vortex.dev".to_string();
+        state.store.store(&inbox, &test_email("abc")).await.unwrap();
+        state.store.store(&inbox, &test_email("def")).await.unwrap();
+
+        let path = |id: &str| Path((inbox.clone(), id.to_string()));
+        assert_eq!(
+            delete_email(State(state.clone()), path("abc")).await,
+            Ok(StatusCode::NO_CONTENT)
+        );
+        assert_eq!(
+            delete_email(State(state.clone()), path("abc")).await,
+            Err(StatusCode::NOT_FOUND)
+        );
+        let remaining = state.store.list(&inbox).await.unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].email.id, "def");
+    }
+}
@@ -0,0 +1,179 @@
+//! Per-inbox limits, so one looping sender can't fill up the store.
+
+use std::env;
+
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use vortex_smtp::RecipientVerdict;
+
+use crate::storage::{MailStore, Usage};
+
+/// What happens to new mail once an inbox is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QuotaPolicy {
+    /// Take it, and delete the oldest mail to make room.
+    #[default]
+    Evict,
+    /// Refuse it: `452` at RCPT TO, or `552` if it doesn't fit. That's at RCPT TO if
+    /// the client said how big it is with `SIZE=`, and at the end of DATA otherwise.
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub max_messages: Option<usize>,
+    /// The most bytes of mail an inbox can hold, all messages together.
+    pub max_bytes: Option<usize>,
+    /// The largest single message an inbox takes. Everything's also capped by the
+    /// SMTP server's own maximum size.
+    pub max_message_size: Option<usize>,
+    pub policy: QuotaPolicy,
+}
+
+impl Quota {
+    /// Reads `INBOX_MAX_MESSAGES`, `INBOX_MAX_BYTES`, `INBOX_MAX_MESSAGE_SIZE` and
+    /// `INBOX_QUOTA_POLICY`. Inboxes are unlimited unless they're set.
+    pub fn from_env() -> Result<Self> {
+        let limit = |name| match env::var(name) {
+            Ok(value) => value
+                .parse()
+                .map(Some)
+                .wrap_err_with(|| format!("Invalid {name}")),
+            Err(_) => Ok(None),
+        };
+        let policy = match env::var("INBOX_QUOTA_POLICY").as_deref() {
+            Ok("evict") | Err(_) => QuotaPolicy::Evict,
+            Ok("reject") => QuotaPolicy::Reject,
+            Ok(other) => {
+                return Err(eyre!(
+                    "INBOX_QUOTA_POLICY must be evict or reject, not {other:?}"
+                ))
+            }
+        };
+
+        Ok(Self {
+            max_messages: limit("INBOX_MAX_MESSAGES")?,
+            max_bytes: limit("INBOX_MAX_BYTES")?,
+            max_message_size: limit("INBOX_MAX_MESSAGE_SIZE")?,
+            policy,
+        })
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_messages.is_none() && self.max_bytes.is_none() && self.max_message_size.is_none()
+    }
+
+    /// Decides whether an inbox holding `usage` can take another message, and how big.
+    pub fn check(&self, usage: Usage) -> RecipientVerdict {
+        let mut size_limit = self.max_message_size;
+        match self.policy {
+            QuotaPolicy::Reject => {
+                if self.max_messages.is_some_and(|max| usage.messages >= max)
+                    || self.max_bytes.is_some_and(|max| usage.bytes >= max)
+                {
+                    return RecipientVerdict::MailboxFull;
+                }
+                if let Some(max) = self.max_bytes {
+                    let room = max - usage.bytes;
+                    size_limit = Some(size_limit.map_or(room, |limit| limit.min(room)));
+                }
+            }
+            // Old mail makes way, but a message can't be bigger than the whole inbox.
+            QuotaPolicy::Evict => {
+                size_limit = match (size_limit, self.max_bytes) {
+                    (Some(limit), Some(max)) => Some(limit.min(max)),
+                    (limit, max) => limit.or(max),
+                };
+            }
+        }
+
+        match size_limit {
+            Some(limit) => RecipientVerdict::AcceptUpTo(limit),
+            None => RecipientVerdict::Accept,
+        }
+    }
+
+    /// With [`QuotaPolicy::Evict`], deletes the oldest mail in `inbox` until it fits
+    /// the limits again, returning how many emails went. The newest one always stays.
+    pub async fn enforce(&self, store: &dyn MailStore, inbox: &str) -> Result<usize> {
+        if self.policy != QuotaPolicy::Evict
+            || (self.max_messages.is_none() && self.max_bytes.is_none())
+        {
+            return Ok(0);
+        }
+
+        let mut kept = Usage::default();
+        let mut evicted = 0;
+        for (id, bytes) in store.sizes(inbox).await? {
+            let fits = self.max_messages.is_none_or(|max| kept.messages < max)
+                && self.max_bytes.is_none_or(|max| kept.bytes + bytes <= max);
+            if fits || kept.messages == 0 {
+                kept.messages += 1;
+                kept.bytes += bytes;
+            } else if store.delete(inbox, &id).await? {
+                evicted += 1;
+            }
+        }
+        Ok(evicted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let usage = |messages, bytes| Usage { messages, bytes };
+        let reject = Quota {
+            max_messages: Some(10),
+            max_bytes: Some(1000),
+            max_message_size: Some(300),
+            policy: QuotaPolicy::Reject,
+        };
+        assert_eq!(reject.check(usage(0, 0)), RecipientVerdict::AcceptUpTo(300));
+        assert_eq!(
+            reject.check(usage(5, 800)),
+            RecipientVerdict::AcceptUpTo(200)
+        );
+        assert_eq!(reject.check(usage(10, 0)), RecipientVerdict::MailboxFull);
+        assert_eq!(reject.check(usage(5, 1000)), RecipientVerdict::MailboxFull);
+
+        let evict = Quota {
+            policy: QuotaPolicy::Evict,
+            ..reject
+        };
+        assert_eq!(
+            evict.check(usage(10, 1000)),
+            RecipientVerdict::AcceptUpTo(300)
+        );
+        assert_eq!(
+            Quota::default().check(usage(1000, 0)),
+            RecipientVerdict::Accept
+        );
+    }
+
+    #[tokio::test]
+    async fn test_enforce() {
+        let store = crate::storage::MemoryStore::new();
+        let inbox = "alice@vortex.dev";
+        for (id, day) in [("a", 1), ("b", 2), ("c", 3)] {
+            let timestamp = format!("2025-01-0{day}T00:00:00Z");
+            let email = crate::storage::test_email(id, &timestamp, &"x".repeat(100));
+            store.store(inbox, &email).await.unwrap();
+        }
+
+        let quota = Quota {
+            max_bytes: Some(250),
+            ..Default::default()
+        };
+        assert_eq!(quota.enforce(&store, inbox).await.unwrap(), 1);
+        let ids = store.list(inbox).await.unwrap();
+        assert_eq!(
+            ids.iter().map(|e| e.email.id.as_str()).collect::<Vec<_>>(),
+            ["c", "b"]
+        );
+    }
+}
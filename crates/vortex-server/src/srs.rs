@@ -0,0 +1,149 @@
+//! The Sender Rewriting Scheme, so mail we forward still passes SPF.
+//!
+//! When we relay a message from `alice@example.com`, the receiving server checks
+//! SPF for `example.com`, which doesn't list us. So we send it from
+//! `SRS0=HHHH=TT=example.com=alice@<our domain>` instead, where `HHHH` is a hash
+//! only we can make and `TT` a timestamp. See
+//! <https://www.libsrs2.org/srs/srs.pdf>.
+//!
+//! If the sender has already been rewritten by another forwarder, we don't wrap it a
+//! second time. Instead it becomes `SRS1=HHHH=<their domain>==<their SRS0 part>@<our
+//! domain>`, and an `SRS1` address keeps the domain of the first forwarder, so a bounce
+//! only ever has to go back through that one.
+//!
+//! We never turn these back into the original address: bounces for forwarded mail
+//! are dropped, just like any other mail for an inbox that doesn't exist.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const HASH_LENGTH: usize = 4;
+
+pub struct Srs {
+    secret: Vec<u8>,
+    /// The domain rewritten addresses are on. Its SPF record should list the relay.
+    domain: String,
+}
+
+impl Srs {
+    pub fn new(secret: impl Into<Vec<u8>>, domain: impl Into<String>) -> Self {
+        Self {
+            secret: secret.into(),
+            domain: domain.into(),
+        }
+    }
+
+    /// Rewrites `sender`, as of `days` days since the Unix epoch.
+    ///
+    /// Addresses without a domain can't be rewritten, and are returned as-is.
+    pub fn forward(&self, sender: &str, days: u64) -> String {
+        let Some((local, domain)) = sender.rsplit_once('@') else {
+            return sender.to_string();
+        };
+
+        // Already rewritten by someone else: point bounces at the first forwarder.
+        if let Some(opaque) = strip_tag(local, "SRS0") {
+            return self.srs1(domain, opaque);
+        }
+        if let Some((first_hop, opaque)) = strip_tag(local, "SRS1")
+            .and_then(|tail| tail.get(1..)?.split_once('='))
+            .and_then(|(_, rest)| rest.split_once('='))
+        {
+            return self.srs1(first_hop, opaque);
+        }
+
+        let timestamp = days % (32 * 32);
+        let timestamp = [
+            BASE32[(timestamp / 32) as usize] as char,
+            BASE32[(timestamp % 32) as usize] as char,
+        ]
+        .iter()
+        .collect::<String>();
+
+        let hash = self.hash(&[&timestamp, domain, local]);
+        format!("SRS0={hash}={timestamp}={domain}={local}@{}", self.domain)
+    }
+
+    /// `opaque` is everything after the `SRS0` tag, separator included.
+    fn srs1(&self, first_hop: &str, opaque: &str) -> String {
+        let hash = self.hash(&[first_hop, opaque]);
+        format!("SRS1={hash}={first_hop}={opaque}@{}", self.domain)
+    }
+
+    fn hash(&self, parts: &[&str]) -> String {
+        let mut mac =
+            Hmac::<Sha1>::new_from_slice(&self.secret).expect("HMAC takes keys of any length");
+        for part in parts {
+            mac.update(part.to_lowercase().as_bytes());
+        }
+        let mut hash = BASE64.encode(mac.finalize().into_bytes());
+        hash.truncate(HASH_LENGTH);
+        hash
+    }
+}
+
+/// Strips the `SRS0`/`SRS1` tag off a local part, leaving the separator after it
+/// (`=`, `+` or `-`, as other implementations use all three).
+fn strip_tag<'a>(local: &'a str, tag: &str) -> Option<&'a str> {
+    let rest = local
+        .get(..tag.len())?
+        .eq_ignore_ascii_case(tag)
+        .then(|| &local[tag.len()..])?;
+    rest.starts_with(['=', '+', '-']).then_some(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forward() {
+        let srs = Srs::new("secret", "vortex.dev");
+
+        let rewritten = srs.forward("alice@example.com", 20_000);
+        let (hash, rest) = rewritten
+            .strip_prefix("SRS0=")
+            .unwrap()
+            .split_once('=')
+            .unwrap();
+        assert_eq!(hash.len(), HASH_LENGTH);
+        // 20000 % 1024 = 544 = 17 * 32 + 0
+        assert_eq!(rest, "RA=example.com=alice@vortex.dev");
+
+        // The hash depends on the secret and ignores case.
+        assert_eq!(
+            srs.forward("Alice@Example.com", 20_000).split('=').nth(1),
+            Some(hash)
+        );
+        let other = Srs::new("another secret", "vortex.dev");
+        assert_ne!(other.forward("alice@example.com", 20_000), rewritten);
+
+        assert_eq!(srs.forward("no-domain", 20_000), "no-domain");
+    }
+
+    #[test]
+    fn test_forward_rewritten() {
+        let srs = Srs::new("secret", "vortex.dev");
+
+        // Someone else's SRS0 becomes an SRS1 pointing back at them.
+        let srs1 = srs.forward("SRS0=abcd=RA=example.com=alice@forwarder.example", 20_000);
+        let (hash, rest) = srs1.strip_prefix("SRS1=").unwrap().split_once('=').unwrap();
+        assert_eq!(hash.len(), HASH_LENGTH);
+        assert_eq!(
+            rest,
+            "forwarder.example==abcd=RA=example.com=alice@vortex.dev"
+        );
+
+        // Someone else's SRS1 keeps the first forwarder, and gets our hash.
+        let other = Srs::new("another secret", "other.example");
+        let theirs = other.forward(&srs1, 20_000);
+        assert!(theirs.ends_with("=forwarder.example==abcd=RA=example.com=alice@other.example"));
+        assert_eq!(srs.forward(&theirs, 20_000), srs1);
+
+        assert!(srs
+            .forward("srs0+abcd=RA=example.com=alice@forwarder.example", 20_000)
+            .starts_with("SRS1="));
+    }
+}
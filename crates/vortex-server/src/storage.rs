@@ -0,0 +1,236 @@
+//! Where inboxes, the mail in them and their forwarding rules are kept.
+//!
+//! Everything goes through [`MailStore`] and [`ForwardingStore`], so the HTTP and
+//! SMTP sides don't care which backend is in use.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+
+use crate::{forwarding::Rule, summary::EmailSummary, ExtendedEmail};
+
+mod maildir;
+mod memory;
+mod redis;
+mod sqlite;
+
+pub use self::maildir::MaildirStore;
+pub use self::memory::MemoryStore;
+pub use self::redis::RedisStore;
+pub use self::sqlite::SqliteStore;
+
+#[async_trait]
+pub trait MailStore: Send + Sync {
+    /// Adds `email` to `inbox`.
+    async fn store(&self, inbox: &str, email: &ExtendedEmail) -> Result<()>;
+
+    /// Returns every email in `inbox`, newest first.
+    ///
+    /// This also opens the inbox if it doesn't exist yet, so it starts accepting mail.
+    async fn list(&self, inbox: &str) -> Result<Vec<ExtendedEmail>>;
+
+    /// Like [`list`](Self::list), but only the emails on `page`.
+    async fn list_page(&self, inbox: &str, page: &Page) -> Result<Vec<ExtendedEmail>> {
+        let mut emails = self.list(inbox).await?;
+        emails.retain(|email| page.includes(received(email)));
+        Ok(page.slice(emails, |email, id| email.email.id == id))
+    }
+
+    /// Like [`list_page`](Self::list_page), but just a summary of each email, which
+    /// some backends can get without fetching the whole message.
+    async fn list_summaries(&self, inbox: &str, page: &Page) -> Result<Vec<EmailSummary>> {
+        let emails = self.list_page(inbox, page).await?;
+        Ok(emails.iter().map(Into::into).collect())
+    }
+
+    async fn get(&self, inbox: &str, id: &str) -> Result<Option<ExtendedEmail>>;
+
+    /// Removes one email, returning whether it was there.
+    async fn delete(&self, inbox: &str, id: &str) -> Result<bool>;
+
+    /// Removes `inbox` and all the mail in it.
+    async fn clear(&self, inbox: &str) -> Result<()>;
+
+    /// Whether `inbox` has been opened, and so accepts mail.
+    async fn exists(&self, inbox: &str) -> Result<bool>;
+
+    /// Removes `inbox` and all the mail in it once `ttl` has passed.
+    async fn expire(&self, inbox: &str, ttl: Duration) -> Result<()>;
+
+    /// Removes every email that arrived before `cutoff` from every inbox, returning how
+    /// many there were.
+    async fn prune(&self, cutoff: DateTime<Utc>) -> Result<usize>;
+
+    /// Removes every inbox whose [`expire`](Self::expire) time has passed, returning
+    /// how many there were. Otherwise an inbox nobody opens again would stay forever.
+    async fn prune_inboxes(&self) -> Result<usize>;
+
+    /// The ID and size of every email in `inbox`, newest first, without fetching the
+    /// emails themselves. Unlike [`list`](Self::list), this doesn't open the inbox.
+    async fn sizes(&self, inbox: &str) -> Result<Vec<(String, usize)>>;
+
+    /// How much mail `inbox` holds.
+    async fn usage(&self, inbox: &str) -> Result<Usage> {
+        let sizes = self.sizes(inbox).await?;
+        Ok(Usage {
+            messages: sizes.len(),
+            bytes: sizes.iter().map(|(_, size)| size).sum(),
+        })
+    }
+
+    /// Finds emails in `inbox` matching every word in `query`, best match first, or
+    /// returns `None` if this backend can't search.
+    async fn search(&self, _inbox: &str, _query: &str) -> Result<Option<Vec<ExtendedEmail>>> {
+        Ok(None)
+    }
+}
+
+/// Where forwarding rules are kept, one list per inbox. Every [`MailStore`] backend
+/// is one of these too, but the two don't depend on each other.
+#[async_trait]
+pub trait ForwardingStore: Send + Sync {
+    async fn forwarding_rules(&self, inbox: &str) -> Result<Vec<Rule>>;
+
+    /// Adds `rule`, replacing any rule with the same target.
+    async fn add_forwarding_rule(&self, inbox: &str, rule: &Rule) -> Result<()>;
+
+    /// Removes the rule forwarding to `to`, returning whether there was one.
+    async fn delete_forwarding_rule(&self, inbox: &str, to: &str) -> Result<bool>;
+}
+
+/// Whether `id` could be an email's ID. They're nanoids, so anything else can't be
+/// one, and shouldn't end up in a key or a path.
+pub fn is_email_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Which part of an inbox to list. Listings are newest first, so pages go back in time.
+#[derive(Clone, Debug, Default)]
+pub struct Page {
+    pub limit: Option<usize>,
+    /// Start after the email with this ID. If there's no such email, the page is empty.
+    pub before: Option<String>,
+    /// Leave out mail that arrived before this. It's done before slicing, so
+    /// expired mail doesn't make pages come back short.
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl Page {
+    /// Whether mail received at `received` (in Unix seconds) is new enough.
+    pub fn includes(&self, received: i64) -> bool {
+        self.since.is_none_or(|since| received >= since.timestamp())
+    }
+
+    /// Cuts the page out of `items`, which are newest first and already filtered with
+    /// [`includes`](Self::includes). `has_id` says whether an item has a given ID.
+    pub fn slice<T>(&self, items: Vec<T>, has_id: impl Fn(&T, &str) -> bool) -> Vec<T> {
+        let start = match &self.before {
+            Some(before) => match items.iter().position(|item| has_id(item, before)) {
+                Some(index) => index + 1,
+                None => return Vec::new(),
+            },
+            None => 0,
+        };
+        items
+            .into_iter()
+            .skip(start)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+/// When `email` arrived, in Unix seconds.
+fn received(email: &ExtendedEmail) -> i64 {
+    DateTime::parse_from_rfc3339(&email.timestamp)
+        .map(|timestamp| timestamp.timestamp())
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub messages: usize,
+    /// The size of all the messages' data together.
+    pub bytes: usize,
+}
+
+/// How often [`sweep`] looks for old mail.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long mail and inboxes are kept. `None` keeps them forever.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ttl {
+    pub message: Option<Duration>,
+    /// Inboxes are deleted once nobody has read them or sent mail to them for this long.
+    pub inbox_idle: Option<Duration>,
+}
+
+impl Ttl {
+    /// Mail that arrived before this has expired.
+    pub fn cutoff(&self) -> Option<DateTime<Utc>> {
+        let ttl = chrono::TimeDelta::from_std(self.message?).ok()?;
+        Utc::now().checked_sub_signed(ttl)
+    }
+
+    pub fn is_expired(&self, email: &ExtendedEmail) -> bool {
+        let Some(cutoff) = self.cutoff() else {
+            return false;
+        };
+        DateTime::parse_from_rfc3339(&email.timestamp).is_ok_and(|timestamp| timestamp < cutoff)
+    }
+
+    /// Pushes back when `inbox` expires. Call this whenever it's read or written to.
+    pub async fn touch(&self, store: &dyn MailStore, inbox: &str) -> Result<()> {
+        match self.inbox_idle {
+            Some(ttl) => store.expire(inbox, ttl).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Prunes expired mail and idle inboxes, forever. Reads hide them anyway, this just
+/// makes sure they actually get deleted.
+pub async fn sweep(store: Arc<dyn MailStore>, ttl: Ttl) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Some(cutoff) = ttl.cutoff() {
+            match store.prune(cutoff).await {
+                Ok(0) => {}
+                Ok(pruned) => tracing::info!(pruned, "Pruned expired emails"),
+                Err(e) => tracing::error!(error = %e, "Failed to prune expired emails"),
+            }
+        }
+        if ttl.inbox_idle.is_some() {
+            match store.prune_inboxes().await {
+                Ok(0) => {}
+                Ok(pruned) => tracing::info!(pruned, "Deleted idle inboxes"),
+                Err(e) => tracing::error!(error = %e, "Failed to delete idle inboxes"),
+            }
+        }
+    }
+}
+
+/// An email from sender@example.com to alice@vortex.dev, for tests.
+#[cfg(test)]
+pub(crate) fn test_email(id: &str, timestamp: &str, data: &str) -> ExtendedEmail {
+    ExtendedEmail {
+        email: vortex_smtp::Email {
+            mail_from: "sender@example.com".to_string(),
+            rcpt_to: vec!["alice@vortex.dev".to_string()],
+            dsn: Default::default(),
+            data: data.to_string(),
+            remote_addr: None,
+            helo: None,
+            authenticated_as: None,
+            id: id.to_string(),
+        },
+        timestamp: timestamp.to_string(),
+        authentication: None,
+    }
+}
@@ -0,0 +1,572 @@
+//! Writes mail into a Maildir per inbox, so mutt, ripgrep and friends can read it
+//! directly, and backing up is just copying files.
+//!
+//! ```text
+//! <root>/<inbox>/{tmp,new,cur}/<received>.<id>.<host>   the message itself
+//! <root>/<inbox>/metadata/<id>.json                    everything else we know
+//! <root>/<inbox>/expires_at                            when to delete the inbox
+//! <root>/forwarding/<inbox>.json                       forwarding rules
+//! ```
+//!
+//! Other tools are free to move messages from `new` to `cur` and add flags to their
+//! names. If they delete one, it disappears from the inbox too.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use super::{is_email_id, ForwardingStore, MailStore, Page};
+use crate::{forwarding::Rule, ExtendedEmail};
+
+/// What goes in the metadata file: the email without its data.
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+    /// The message's name in `new` or `cur`, without any flags.
+    file: String,
+    #[serde(flatten)]
+    email: ExtendedEmail,
+}
+
+pub struct MaildirStore {
+    root: PathBuf,
+    /// The last part of every message's name, so names stay unique if several
+    /// machines deliver into the same Maildir.
+    host: String,
+}
+
+impl MaildirStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let host = std::env::var("HOSTNAME")
+            .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+            .map(|host| host.trim().to_string())
+            .unwrap_or_default();
+        let host = if host.is_empty() {
+            "localhost".to_string()
+        } else {
+            host
+        };
+        Self {
+            root: root.into(),
+            // The Maildir spec's escapes for the characters that would break a name.
+            host: host.replace('/', "\\057").replace(':', "\\072"),
+        }
+    }
+
+    fn inbox_dir(&self, inbox: &str) -> Result<PathBuf> {
+        // Inboxes are validated long before they get here, but they still end up
+        // in a path.
+        if inbox.is_empty() || inbox.starts_with('.') || inbox.contains(['/', '\\', '\0']) {
+            return Err(eyre!("Invalid inbox name {inbox:?}"));
+        }
+        Ok(self.root.join(inbox))
+    }
+
+    /// Where `id`'s metadata goes. Check it with [`is_email_id`] first, as it ends up
+    /// in the path.
+    fn sidecar_path(&self, inbox: &str, id: &str) -> Result<PathBuf> {
+        Ok(self
+            .inbox_dir(inbox)?
+            .join("metadata")
+            .join(format!("{id}.json")))
+    }
+
+    fn forwarding_path(&self, inbox: &str) -> Result<PathBuf> {
+        self.inbox_dir(inbox)?;
+        Ok(self.root.join("forwarding").join(format!("{inbox}.json")))
+    }
+
+    /// Returns the inbox's directory if it exists, deleting it first if it has expired.
+    async fn open_inbox(&self, inbox: &str) -> Result<Option<PathBuf>> {
+        let dir = self.inbox_dir(inbox)?;
+        if is_expired(&dir).await? {
+            remove_dir(&dir).await?;
+            return Ok(None);
+        }
+        Ok(fs::try_exists(dir.join("new")).await?.then_some(dir))
+    }
+
+    async fn create_inbox(&self, inbox: &str) -> Result<PathBuf> {
+        if let Some(dir) = self.open_inbox(inbox).await? {
+            return Ok(dir);
+        }
+        let dir = self.inbox_dir(inbox)?;
+        for subdir in ["tmp", "new", "cur", "metadata"] {
+            fs::create_dir_all(dir.join(subdir))
+                .await
+                .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+        }
+        Ok(dir)
+    }
+}
+
+/// Whether the inbox in `dir` has an `expires_at` that has passed.
+async fn is_expired(dir: &Path) -> Result<bool> {
+    let expires_at = match fs::read_to_string(dir.join("expires_at")).await {
+        Ok(expires_at) => expires_at.trim().parse::<i64>().ok(),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    Ok(expires_at.is_some_and(|expires_at| expires_at <= Utc::now().timestamp()))
+}
+
+/// Reads a sidecar, or returns `None` if it's gone.
+async fn read_sidecar(path: &Path) -> Result<Option<Sidecar>> {
+    match fs::read(path).await {
+        Ok(json) => serde_json::from_slice(&json)
+            .map(Some)
+            .wrap_err_with(|| format!("Invalid metadata in {}", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads the message a sidecar belongs to back into its email.
+async fn read_email(sidecar: Sidecar, message: &Path) -> Result<ExtendedEmail> {
+    let mut email = sidecar.email;
+    email.email.data = String::from_utf8_lossy(&fs::read(message).await?).into_owned();
+    Ok(email)
+}
+
+/// Finds a message in `new` or `cur`, where it may have picked up flags like `:2,S`.
+async fn find_message(dir: &Path, file: &str) -> Result<Option<PathBuf>> {
+    for subdir in ["new", "cur"] {
+        let mut entries = fs::read_dir(dir.join(subdir)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.split(':').next() == Some(file) {
+                return Ok(Some(entry.path()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Every message in `new` and `cur`, by its name without flags. For when we're after
+/// more than one, so we don't read the directories again for each.
+async fn messages(dir: &Path) -> Result<HashMap<String, PathBuf>> {
+    let mut messages = HashMap::new();
+    for subdir in ["new", "cur"] {
+        let mut entries = fs::read_dir(dir.join(subdir)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let file = name.split(':').next().unwrap_or_default().to_string();
+            messages.insert(file, entry.path());
+        }
+    }
+    Ok(messages)
+}
+
+async fn remove_dir(dir: &Path) -> Result<()> {
+    match fs::remove_dir_all(dir).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).wrap_err_with(|| format!("Failed to remove {}", dir.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Writes `contents` into the `tmp` directory and then renames it into place, so
+/// readers never see half a file.
+async fn write_atomically(tmp: &Path, path: &Path, contents: &[u8]) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| eyre!("{} has no file name", path.display()))?;
+    let tmp = tmp.join(file_name);
+    fs::write(&tmp, contents).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl MailStore for MaildirStore {
+    async fn store(&self, inbox: &str, email: &ExtendedEmail) -> Result<()> {
+        let received = DateTime::parse_from_rfc3339(&email.timestamp)
+            .wrap_err("Timestamp parse error")?
+            .timestamp();
+        let id = &email.email.id;
+        if !is_email_id(id) {
+            return Err(eyre!("Invalid email ID {id:?}"));
+        }
+        let sidecar_path = self.sidecar_path(inbox, id)?;
+        let dir = self.create_inbox(inbox).await?;
+
+        let file = format!("{received}.{}.{}", email.email.id, self.host);
+        let mut sidecar = Sidecar {
+            file: file.clone(),
+            email: email.clone(),
+        };
+        sidecar.email.email.data = String::new();
+
+        let tmp = dir.join("tmp");
+        write_atomically(&tmp, &sidecar_path, &serde_json::to_vec(&sidecar)?).await?;
+        write_atomically(
+            &tmp,
+            &dir.join("new").join(file),
+            email.email.data.as_bytes(),
+        )
+        .await
+    }
+
+    async fn list(&self, inbox: &str) -> Result<Vec<ExtendedEmail>> {
+        self.list_page(inbox, &Page::default()).await
+    }
+
+    async fn list_page(&self, inbox: &str, page: &Page) -> Result<Vec<ExtendedEmail>> {
+        let dir = self.create_inbox(inbox).await?;
+
+        // The sidecars are enough to find the page, so only its messages get read.
+        let mut messages = messages(&dir).await?;
+        let mut sidecars = Vec::new();
+        let mut entries = fs::read_dir(dir.join("metadata")).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(sidecar) = read_sidecar(&entry.path()).await? else {
+                continue;
+            };
+            // Someone deleted the message itself.
+            let Some(message) = messages.remove(&sidecar.file) else {
+                continue;
+            };
+            let received = DateTime::parse_from_rfc3339(&sidecar.email.timestamp)
+                .map(|timestamp| timestamp.timestamp())
+                .unwrap_or_default();
+            if page.includes(received) {
+                sidecars.push((received, sidecar, message));
+            }
+        }
+        sidecars.sort_by_key(|(received, _, _)| std::cmp::Reverse(*received));
+        let sidecars = page.slice(sidecars, |(_, sidecar, _), id| sidecar.email.email.id == id);
+
+        let mut emails = Vec::with_capacity(sidecars.len());
+        for (_, sidecar, message) in sidecars {
+            emails.push(read_email(sidecar, &message).await?);
+        }
+        Ok(emails)
+    }
+
+    async fn sizes(&self, inbox: &str) -> Result<Vec<(String, usize)>> {
+        let Some(dir) = self.open_inbox(inbox).await? else {
+            return Ok(Vec::new());
+        };
+        let messages = messages(&dir).await?;
+        let mut sizes = Vec::new();
+        let mut entries = fs::read_dir(dir.join("metadata")).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(sidecar) = read_sidecar(&entry.path()).await? else {
+                continue;
+            };
+            let Some(message) = messages.get(&sidecar.file) else {
+                continue;
+            };
+            let size = fs::metadata(message).await?.len() as usize;
+            let received = DateTime::parse_from_rfc3339(&sidecar.email.timestamp)
+                .map(|timestamp| timestamp.timestamp())
+                .unwrap_or_default();
+            sizes.push((received, sidecar.email.email.id, size));
+        }
+
+        sizes.sort_by_key(|(received, _, _)| std::cmp::Reverse(*received));
+        Ok(sizes.into_iter().map(|(_, id, size)| (id, size)).collect())
+    }
+
+    async fn get(&self, inbox: &str, id: &str) -> Result<Option<ExtendedEmail>> {
+        // Nothing we stored could have an ID like that.
+        if !is_email_id(id) {
+            return Ok(None);
+        }
+        let sidecar = self.sidecar_path(inbox, id)?;
+        let Some(dir) = self.open_inbox(inbox).await? else {
+            return Ok(None);
+        };
+        let Some(sidecar) = read_sidecar(&sidecar).await? else {
+            return Ok(None);
+        };
+        match find_message(&dir, &sidecar.file).await? {
+            Some(message) => read_email(sidecar, &message).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    async fn delete(&self, inbox: &str, id: &str) -> Result<bool> {
+        if !is_email_id(id) {
+            return Ok(false);
+        }
+        let sidecar_path = self.sidecar_path(inbox, id)?;
+        let Some(dir) = self.open_inbox(inbox).await? else {
+            return Ok(false);
+        };
+        let Some(sidecar) = read_sidecar(&sidecar_path).await? else {
+            return Ok(false);
+        };
+
+        fs::remove_file(&sidecar_path).await?;
+        match find_message(&dir, &sidecar.file).await? {
+            Some(path) => {
+                fs::remove_file(path).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn clear(&self, inbox: &str) -> Result<()> {
+        remove_dir(&self.inbox_dir(inbox)?).await
+    }
+
+    async fn exists(&self, inbox: &str) -> Result<bool> {
+        Ok(self.open_inbox(inbox).await?.is_some())
+    }
+
+    async fn expire(&self, inbox: &str, ttl: Duration) -> Result<()> {
+        let Some(dir) = self.open_inbox(inbox).await? else {
+            return Ok(());
+        };
+        let expires_at = Utc::now().timestamp() + ttl.as_secs() as i64;
+        write_atomically(
+            &dir.join("tmp"),
+            &dir.join("expires_at"),
+            expires_at.to_string().as_bytes(),
+        )
+        .await
+    }
+
+    async fn prune(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        let mut pruned = 0;
+        let mut inboxes = match fs::read_dir(&self.root).await {
+            Ok(inboxes) => inboxes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(inbox) = inboxes.next_entry().await? {
+            let dir = inbox.path();
+            let Ok(mut metadata) = fs::read_dir(dir.join("metadata")).await else {
+                continue;
+            };
+            let mut messages = messages(&dir).await?;
+
+            while let Some(entry) = metadata.next_entry().await? {
+                // Broken sidecars are left for a human to look at.
+                let Ok(Some(sidecar)) = read_sidecar(&entry.path()).await else {
+                    continue;
+                };
+                if !DateTime::parse_from_rfc3339(&sidecar.email.timestamp)
+                    .is_ok_and(|timestamp| timestamp < cutoff)
+                {
+                    continue;
+                }
+
+                fs::remove_file(entry.path()).await?;
+                if let Some(message) = messages.remove(&sidecar.file) {
+                    fs::remove_file(message).await?;
+                    pruned += 1;
+                }
+            }
+        }
+        Ok(pruned)
+    }
+
+    async fn prune_inboxes(&self) -> Result<usize> {
+        let mut pruned = 0;
+        let mut inboxes = match fs::read_dir(&self.root).await {
+            Ok(inboxes) => inboxes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(inbox) = inboxes.next_entry().await? {
+            let dir = inbox.path();
+            if is_expired(&dir).await? {
+                remove_dir(&dir).await?;
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+}
+
+#[async_trait]
+impl ForwardingStore for MaildirStore {
+    async fn forwarding_rules(&self, inbox: &str) -> Result<Vec<Rule>> {
+        match fs::read(self.forwarding_path(inbox)?).await {
+            Ok(json) => Ok(serde_json::from_slice(&json)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn add_forwarding_rule(&self, inbox: &str, rule: &Rule) -> Result<()> {
+        let mut rules = self.forwarding_rules(inbox).await?;
+        rules.retain(|other| other.to != rule.to);
+        rules.push(rule.clone());
+
+        let path = self.forwarding_path(inbox)?;
+        let tmp = self.root.join("forwarding").join("tmp");
+        fs::create_dir_all(&tmp).await?;
+        write_atomically(&tmp, &path, &serde_json::to_vec(&rules)?).await
+    }
+
+    async fn delete_forwarding_rule(&self, inbox: &str, to: &str) -> Result<bool> {
+        let mut rules = self.forwarding_rules(inbox).await?;
+        let before = rules.len();
+        rules.retain(|rule| rule.to != to);
+        if rules.len() == before {
+            return Ok(false);
+        }
+
+        write_atomically(
+            &self.root.join("forwarding").join("tmp"),
+            &self.forwarding_path(inbox)?,
+            &serde_json::to_vec(&rules)?,
+        )
+        .await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{test_email, Usage};
+
+    fn email(id: &str, timestamp: &str) -> ExtendedEmail {
+        test_email(id, timestamp, &format!("Subject: {id}\r\n\r\nhello\r\n"))
+    }
+
+    #[tokio::test]
+    async fn test_maildir_store() {
+        let root = std::env::temp_dir().join(format!("vortex-maildir-{}", std::process::id()));
+        let store = MaildirStore::new(&root);
+        let inbox = "alice@vortex.dev";
+        // Checking its quota doesn't count as opening it.
+        assert_eq!(store.usage(inbox).await.unwrap(), Usage::default());
+        assert!(!store.exists(inbox).await.unwrap());
+        assert!(store.list(inbox).await.unwrap().is_empty());
+        assert!(store.exists(inbox).await.unwrap());
+
+        store
+            .store(inbox, &email("a", "2025-01-01T00:00:00Z"))
+            .await
+            .unwrap();
+        store
+            .store(inbox, &email("b", "2025-01-02T00:00:00Z"))
+            .await
+            .unwrap();
+        let file = format!("1735689600.a.{}", store.host);
+        let message = root.join(inbox).join("new").join(&file);
+        assert_eq!(
+            std::fs::read_to_string(&message).unwrap(),
+            "Subject: a\r\n\r\nhello\r\n"
+        );
+
+        let size = "Subject: a\r\n\r\nhello\r\n".len();
+        assert_eq!(
+            store.sizes(inbox).await.unwrap(),
+            [("b".to_string(), size), ("a".to_string(), size)]
+        );
+
+        // A mail client marks it as read.
+        let read = root.join(inbox).join("cur").join(format!("{file}:2,S"));
+        std::fs::rename(&message, &read).unwrap();
+        let listed = store.list(inbox).await.unwrap();
+        assert_eq!(
+            listed
+                .iter()
+                .map(|e| e.email.id.as_str())
+                .collect::<Vec<_>>(),
+            ["b", "a"]
+        );
+        assert_eq!(listed[1].email.data, "Subject: a\r\n\r\nhello\r\n");
+        let page = Page {
+            limit: Some(1),
+            before: Some("b".to_string()),
+            since: None,
+        };
+        let page = store.list_page(inbox, &page).await.unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].email.id, "a");
+        let page = Page {
+            since: Some("2025-01-02T00:00:00Z".parse().unwrap()),
+            ..Page::default()
+        };
+        assert_eq!(store.list_page(inbox, &page).await.unwrap().len(), 1);
+
+        let cutoff = "2025-01-02T00:00:00Z".parse().unwrap();
+        assert_eq!(store.prune(cutoff).await.unwrap(), 1);
+        assert_eq!(store.prune(cutoff).await.unwrap(), 0);
+        assert!(!read.exists());
+        assert!(!root.join(inbox).join("metadata").join("a.json").exists());
+        assert!(store.get(inbox, "b").await.unwrap().is_some());
+        assert!(store.get(inbox, "a").await.unwrap().is_none());
+        assert!(!store.delete(inbox, "a").await.unwrap());
+
+        assert!(store.delete(inbox, "b").await.unwrap());
+        assert!(!store.delete(inbox, "b").await.unwrap());
+        assert!(store.list(inbox).await.unwrap().is_empty());
+        // IDs that can't be ours are just not found, but we won't store one.
+        assert!(store
+            .get(inbox, "../../etc/passwd")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!store.delete(inbox, "foo.bar").await.unwrap());
+        assert!(store
+            .store(inbox, &email("foo.bar", "2025-01-02T00:00:00Z"))
+            .await
+            .is_err());
+        assert!(store.list("../alice@vortex.dev").await.is_err());
+
+        store.clear(inbox).await.unwrap();
+        assert!(!store.exists(inbox).await.unwrap());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_inbox_expiry() {
+        let root = std::env::temp_dir().join(format!("vortex-expiry-{}", std::process::id()));
+        let store = MaildirStore::new(&root);
+        let inbox = "alice@vortex.dev";
+        store
+            .store(inbox, &email("a", "2025-01-01T00:00:00Z"))
+            .await
+            .unwrap();
+
+        store
+            .expire(inbox, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        let expires_at: i64 = std::fs::read_to_string(root.join(inbox).join("expires_at"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(expires_at > Utc::now().timestamp());
+        assert!(store.get(inbox, "a").await.unwrap().is_some());
+
+        // It's deleted the next time anyone looks at it.
+        store.expire(inbox, Duration::ZERO).await.unwrap();
+        assert!(!store.exists(inbox).await.unwrap());
+        assert!(!root.join(inbox).exists());
+
+        // Or by the sweeper, if nobody does.
+        let bob = "bob@vortex.dev";
+        store
+            .store(bob, &email("b", "2025-01-01T00:00:00Z"))
+            .await
+            .unwrap();
+        store.expire(bob, Duration::from_secs(3600)).await.unwrap();
+        assert_eq!(store.prune_inboxes().await.unwrap(), 0);
+        store.expire(bob, Duration::ZERO).await.unwrap();
+        assert_eq!(store.prune_inboxes().await.unwrap(), 1);
+        assert!(!root.join(bob).exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+//! Keeps everything in memory, so you can run the server without Redis. Nothing
+//! survives a restart.
+
+use std::collections::HashMap;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use tokio::time::Instant;
+
+use super::{ForwardingStore, MailStore, Page};
+use crate::{forwarding::Rule, ExtendedEmail};
+
+#[derive(Default)]
+struct Inbox {
+    /// Oldest first, so new mail is usually pushed onto the end.
+    emails: Vec<(i64, ExtendedEmail)>,
+    expires_at: Option<Instant>,
+}
+
+impl Inbox {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    inboxes: RwLock<HashMap<String, Inbox>>,
+    forwarding: RwLock<HashMap<String, Vec<Rule>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Nothing panics while holding these, but don't take the whole server down if
+    // something does.
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<String, Inbox>> {
+        self.inboxes.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Locks the inboxes for writing, dropping any that have expired.
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<String, Inbox>> {
+        let mut inboxes = self.inboxes.write().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        inboxes.retain(|_, inbox| !inbox.is_expired(now));
+        inboxes
+    }
+
+    /// Runs `f` on `inbox` if it exists and hasn't expired.
+    fn with_inbox<T>(&self, inbox: &str, f: impl FnOnce(&Inbox) -> T) -> Option<T> {
+        self.read()
+            .get(inbox)
+            .filter(|inbox| !inbox.is_expired(Instant::now()))
+            .map(f)
+    }
+}
+
+#[async_trait]
+impl MailStore for MemoryStore {
+    async fn store(&self, inbox: &str, email: &ExtendedEmail) -> Result<()> {
+        let score = DateTime::parse_from_rfc3339(&email.timestamp)?.timestamp();
+
+        let mut inboxes = self.write();
+        let emails = &mut inboxes.entry(inbox.to_string()).or_default().emails;
+        let index = emails.partition_point(|(other, _)| *other <= score);
+        emails.insert(index, (score, email.clone()));
+        Ok(())
+    }
+
+    async fn list(&self, inbox: &str) -> Result<Vec<ExtendedEmail>> {
+        if let Some(emails) = self.with_inbox(inbox, |inbox| {
+            inbox
+                .emails
+                .iter()
+                .rev()
+                .map(|(_, email)| email.clone())
+                .collect()
+        }) {
+            return Ok(emails);
+        }
+
+        self.write().entry(inbox.to_string()).or_default();
+        Ok(Vec::new())
+    }
+
+    async fn list_page(&self, inbox: &str, page: &Page) -> Result<Vec<ExtendedEmail>> {
+        // Only the page gets cloned, not the whole inbox.
+        if let Some(emails) = self.with_inbox(inbox, |inbox| {
+            let emails = inbox
+                .emails
+                .iter()
+                .rev()
+                .filter(|(received, _)| page.includes(*received))
+                .collect();
+            page.slice(emails, |(_, email), id| email.email.id == id)
+                .into_iter()
+                .map(|(_, email)| email.clone())
+                .collect()
+        }) {
+            return Ok(emails);
+        }
+
+        self.write().entry(inbox.to_string()).or_default();
+        Ok(Vec::new())
+    }
+
+    async fn get(&self, inbox: &str, id: &str) -> Result<Option<ExtendedEmail>> {
+        Ok(self
+            .with_inbox(inbox, |inbox| {
+                inbox
+                    .emails
+                    .iter()
+                    .find(|(_, email)| email.email.id == id)
+                    .map(|(_, email)| email.clone())
+            })
+            .flatten())
+    }
+
+    async fn delete(&self, inbox: &str, id: &str) -> Result<bool> {
+        let mut inboxes = self.write();
+        let Some(inbox) = inboxes.get_mut(inbox) else {
+            return Ok(false);
+        };
+        let before = inbox.emails.len();
+        inbox.emails.retain(|(_, email)| email.email.id != id);
+        Ok(inbox.emails.len() < before)
+    }
+
+    async fn clear(&self, inbox: &str) -> Result<()> {
+        self.write().remove(inbox);
+        Ok(())
+    }
+
+    async fn exists(&self, inbox: &str) -> Result<bool> {
+        Ok(self.with_inbox(inbox, |_| ()).is_some())
+    }
+
+    async fn expire(&self, inbox: &str, ttl: Duration) -> Result<()> {
+        if let Some(inbox) = self.write().get_mut(inbox) {
+            inbox.expires_at = Some(Instant::now() + ttl);
+        }
+        Ok(())
+    }
+
+    async fn sizes(&self, inbox: &str) -> Result<Vec<(String, usize)>> {
+        Ok(self
+            .with_inbox(inbox, |inbox| {
+                inbox
+                    .emails
+                    .iter()
+                    .rev()
+                    .map(|(_, email)| (email.email.id.clone(), email.email.data.len()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn prune(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        let cutoff = cutoff.timestamp();
+        let mut pruned = 0;
+        for inbox in self.write().values_mut() {
+            let before = inbox.emails.len();
+            inbox.emails.retain(|(score, _)| *score >= cutoff);
+            pruned += before - inbox.emails.len();
+        }
+        Ok(pruned)
+    }
+
+    async fn prune_inboxes(&self) -> Result<usize> {
+        let mut inboxes = self.inboxes.write().unwrap_or_else(|e| e.into_inner());
+        let before = inboxes.len();
+        let now = Instant::now();
+        inboxes.retain(|_, inbox| !inbox.is_expired(now));
+        Ok(before - inboxes.len())
+    }
+}
+
+#[async_trait]
+impl ForwardingStore for MemoryStore {
+    async fn forwarding_rules(&self, inbox: &str) -> Result<Vec<Rule>> {
+        let forwarding = self.forwarding.read().unwrap_or_else(|e| e.into_inner());
+        Ok(forwarding.get(inbox).cloned().unwrap_or_default())
+    }
+
+    async fn add_forwarding_rule(&self, inbox: &str, rule: &Rule) -> Result<()> {
+        let mut forwarding = self.forwarding.write().unwrap_or_else(|e| e.into_inner());
+        let rules = forwarding.entry(inbox.to_string()).or_default();
+        rules.retain(|other| other.to != rule.to);
+        rules.push(rule.clone());
+        Ok(())
+    }
+
+    async fn delete_forwarding_rule(&self, inbox: &str, to: &str) -> Result<bool> {
+        let mut forwarding = self.forwarding.write().unwrap_or_else(|e| e.into_inner());
+        let Some(rules) = forwarding.get_mut(inbox) else {
+            return Ok(false);
+        };
+        let before = rules.len();
+        rules.retain(|rule| rule.to != to);
+        Ok(rules.len() < before)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_email;
+
+    fn email(id: &str, timestamp: &str) -> ExtendedEmail {
+        test_email(id, timestamp, "Subject: hi\r\n\r\nhello\r\n")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_memory_store() {
+        let store = MemoryStore::new();
+        let inbox = "alice@vortex.dev";
+        assert!(!store.exists(inbox).await.unwrap());
+
+        // Listing opens the inbox.
+        assert!(store.list(inbox).await.unwrap().is_empty());
+        assert!(store.exists(inbox).await.unwrap());
+
+        store
+            .store(inbox, &email("b", "2025-01-02T00:00:00Z"))
+            .await
+            .unwrap();
+        store
+            .store(inbox, &email("a", "2025-01-01T00:00:00Z"))
+            .await
+            .unwrap();
+        store
+            .store(inbox, &email("c", "2025-01-03T00:00:00Z"))
+            .await
+            .unwrap();
+        let ids =
+            |emails: Vec<ExtendedEmail>| emails.into_iter().map(|e| e.email.id).collect::<Vec<_>>();
+        assert_eq!(ids(store.list(inbox).await.unwrap()), ["c", "b", "a"]);
+
+        assert!(store.get(inbox, "b").await.unwrap().is_some());
+        assert!(store.delete(inbox, "b").await.unwrap());
+        assert!(!store.delete(inbox, "b").await.unwrap());
+        assert!(store.get(inbox, "b").await.unwrap().is_none());
+
+        let cutoff = "2025-01-02T00:00:00Z".parse().unwrap();
+        assert_eq!(store.prune(cutoff).await.unwrap(), 1);
+        assert_eq!(ids(store.list(inbox).await.unwrap()), ["c"]);
+
+        store.expire(inbox, Duration::from_secs(60)).await.unwrap();
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(store.prune_inboxes().await.unwrap(), 1);
+        assert_eq!(store.prune_inboxes().await.unwrap(), 0);
+        assert!(!store.exists(inbox).await.unwrap());
+        assert!(store.list(inbox).await.unwrap().is_empty());
+    }
+}
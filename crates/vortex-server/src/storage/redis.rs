@@ -0,0 +1,771 @@
+//! Each inbox is a sorted set at `emails:<inbox>` of message IDs, scored by when
+//! they arrived. A message's metadata is in the hash `email:<inbox>:<id>`, and its
+//! data in `email:<inbox>:<id>:body`, so we can work with one message at a time
+//! and skip the bodies when we don't need them. Forwarding rules live in the hash
+//! `forwarding:<inbox>`, keyed by target address.
+//!
+//! Older versions put each email's whole JSON in the sorted set instead. Those
+//! members still get read, and go away on their own as inboxes expire or get cleared.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use redis::{aio::ConnectionManager, AsyncCommands, Client};
+
+use super::{is_email_id, ForwardingStore, MailStore, Page};
+use crate::{forwarding::Rule, summary::EmailSummary, ExtendedEmail};
+
+// lua script that ensures the key exists as an empty sorted set and returns the email IDs
+// with a score (arrival time) of at least ARGV[2]
+// what's the point, you ask? this way we can ensure that the key exists and is a sorted set
+// - *in a single roundtrip!*
+//
+// you might also ask - why the sentinel? because we want to ensure that the key is not empty
+// as otherwise redis will remove the key when we try to read it
+const ENSURE_ZSET_SCRIPT: &str = r#"
+    local key = KEYS[1]
+    local sentinel = ARGV[1]
+    local min = ARGV[2]
+
+    -- Add sentinel member if it doesn't exist (and thus create the key)
+    redis.call('ZADD', key, 'NX', -1, sentinel)
+
+    -- Fetch everything newest-first
+    local all = redis.call('ZREVRANGEBYSCORE', key, '+inf', min)
+
+    -- Remove sentinel from the result set
+    for i = #all, 1, -1 do
+        if all[i] == sentinel then
+            table.remove(all, i)
+        end
+    end
+
+    return all
+"#;
+const SENTINEL: &str = "__empty__";
+
+// Writes one message. It's a script rather than MULTI because the message's keys
+// have to expire along with the inbox, and that means reading the inbox's TTL.
+const STORE_SCRIPT: &str = r#"
+    local inbox, metadata, body = KEYS[1], KEYS[2], KEYS[3]
+    local id, score = ARGV[1], ARGV[2]
+
+    -- Everything between the score and the body is the metadata's fields and values
+    redis.call('DEL', metadata)
+    redis.call('HSET', metadata, unpack(ARGV, 3, #ARGV - 1))
+    redis.call('SET', body, ARGV[#ARGV])
+    redis.call('ZADD', inbox, score, id)
+
+    local ttl = redis.call('PTTL', inbox)
+    if ttl > 0 then
+        redis.call('PEXPIRE', metadata, ttl)
+        redis.call('PEXPIRE', body, ttl)
+    end
+"#;
+
+// Sets the TTL of an inbox and every message in it. Each message has two keys, so
+// this is still a lot of EXPIREs for a big inbox, but they all happen inside Redis
+// instead of us fetching the IDs and sending them back.
+const EXPIRE_SCRIPT: &str = r#"
+    local inbox = KEYS[1]
+    local ttl, sentinel, prefix = ARGV[1], ARGV[2], ARGV[3]
+
+    redis.call('EXPIRE', inbox, ttl)
+    for _, id in ipairs(redis.call('ZRANGE', inbox, 0, -1)) do
+        -- Neither the sentinel nor old whole-JSON members have keys of their own
+        if id ~= sentinel and string.sub(id, 1, 1) ~= '{' then
+            redis.call('EXPIRE', prefix .. id, ttl)
+            redis.call('EXPIRE', prefix .. id .. ':body', ttl)
+        end
+    end
+"#;
+
+// Removes members from an inbox, along with the keys of the ones that were really
+// there. Checking ZREM's reply first means an ID can't take out some other message's
+// keys, or the sentinel.
+const REMOVE_SCRIPT: &str = r#"
+    local inbox = KEYS[1]
+    local sentinel, prefix = ARGV[1], ARGV[2]
+
+    local removed = 0
+    for i = 3, #ARGV do
+        local member = ARGV[i]
+        if member ~= sentinel and redis.call('ZREM', inbox, member) == 1 then
+            removed = removed + 1
+            -- Old whole-JSON members don't have keys of their own
+            if string.sub(member, 1, 1) ~= '{' then
+                redis.call('DEL', prefix .. member, prefix .. member .. ':body')
+            end
+        end
+    end
+    return removed
+"#;
+
+#[derive(Clone)]
+pub struct RedisStore {
+    // ConnectionManager handles reconnection automatically :D
+    conn: ConnectionManager,
+}
+
+fn inbox_key(inbox: &str) -> String {
+    format!("emails:{inbox}")
+}
+
+/// What every message key in `inbox` starts with. [`EXPIRE_SCRIPT`] builds them too.
+fn message_prefix(inbox: &str) -> String {
+    format!("email:{inbox}:")
+}
+
+fn metadata_key(inbox: &str, id: &str) -> String {
+    format!("{}{id}", message_prefix(inbox))
+}
+
+fn body_key(inbox: &str, id: &str) -> String {
+    format!("{}{id}:body", message_prefix(inbox))
+}
+
+/// Whether a sorted set member is a whole email from the old layout, not an ID.
+fn is_legacy(member: &str) -> bool {
+    member.starts_with('{')
+}
+
+/// Turns a metadata hash and body back into an email, or `None` if the hash is gone.
+fn read_email(
+    metadata: HashMap<String, String>,
+    body: Option<String>,
+) -> Result<Option<ExtendedEmail>> {
+    let (Some(email), Some(timestamp)) = (metadata.get("email"), metadata.get("timestamp")) else {
+        return Ok(None);
+    };
+
+    let mut email: vortex_smtp::Email = serde_json::from_str(email)?;
+    email.data = body.unwrap_or_default();
+    Ok(Some(ExtendedEmail {
+        email,
+        timestamp: timestamp.clone(),
+        authentication: metadata
+            .get("authentication")
+            .map(|json| serde_json::from_str(json))
+            .transpose()?,
+    }))
+}
+
+/// Adds removing `members` from `inbox` to `pipe`. Its reply is how many were
+/// actually removed.
+fn queue_removal(pipe: &mut redis::Pipeline, inbox: &str, members: &[String]) {
+    // EVAL rather than EVALSHA, since a pipeline can't load the script if it's missing.
+    pipe.cmd("EVAL")
+        .arg(REMOVE_SCRIPT)
+        .arg(1)
+        .arg(inbox_key(inbox))
+        .arg(SENTINEL)
+        .arg(message_prefix(inbox))
+        .arg(members);
+}
+
+/// Whether `id` could be one of our messages, and so is safe to build keys from.
+fn is_message_id(id: &str) -> bool {
+    is_email_id(id) && id != SENTINEL
+}
+
+/// Turns a metadata hash into a summary of the email, or `None` if the hash is gone.
+fn read_summary(id: &str, metadata: HashMap<String, String>) -> Result<Option<EmailSummary>> {
+    let Some(timestamp) = metadata.get("timestamp") else {
+        return Ok(None);
+    };
+    let from = match metadata.get("from") {
+        Some(from) => from.clone(),
+        // Stored before summaries were, so all we have is the envelope.
+        None => match metadata.get("email") {
+            Some(email) => serde_json::from_str::<vortex_smtp::Email>(email)?.mail_from,
+            None => return Ok(None),
+        },
+    };
+
+    Ok(Some(EmailSummary {
+        id: id.to_string(),
+        from,
+        subject: metadata.get("subject").cloned(),
+        date: timestamp.clone(),
+        size: metadata
+            .get("size")
+            .and_then(|size| size.parse().ok())
+            .unwrap_or_default(),
+        snippet: metadata.get("snippet").cloned().unwrap_or_default(),
+    }))
+}
+
+impl RedisStore {
+    /// The members of `inbox` on `page`, which is where listing it starts.
+    async fn page(&self, inbox: &str, page: &Page) -> Result<Vec<String>> {
+        let since = page.since.map(|since| since.timestamp());
+        let members = self.members(inbox, since).await?;
+        Ok(page.slice(members, |member, id| {
+            if is_legacy(member) {
+                serde_json::from_str::<ExtendedEmail>(member)
+                    .is_ok_and(|email| email.email.id == id)
+            } else {
+                member == id
+            }
+        }))
+    }
+
+    pub async fn connect(redis_url: &str) -> Result<Self> {
+        let client = Client::open(redis_url)
+            .wrap_err_with(|| format!("Failed to connect to Redis at {redis_url}"))?;
+        let conn = ConnectionManager::new(client)
+            .await
+            .wrap_err("Failed to establish Redis connection")?;
+
+        // test the connection!
+        let mut test_conn = conn.clone();
+        let _: String = redis::cmd("PING")
+            .query_async(&mut test_conn)
+            .await
+            .wrap_err("Failed to ping Redis server")?;
+
+        Ok(Self { conn })
+    }
+
+    /// The members of `inbox`'s sorted set from the old layout. Unlike
+    /// [`members`](Self::members), this doesn't create the inbox.
+    async fn legacy_members(&self, inbox: &str) -> Result<Vec<String>> {
+        let mut conn = self.conn.clone();
+        let members: Vec<String> = conn.zrange(inbox_key(inbox), 0, -1).await?;
+        Ok(members
+            .into_iter()
+            .filter(|member| is_legacy(member))
+            .collect())
+    }
+
+    /// Every member of `inbox`'s sorted set that arrived at or after `since` (in Unix
+    /// seconds), newest first. Creates the inbox.
+    async fn members(&self, inbox: &str, since: Option<i64>) -> Result<Vec<String>> {
+        let mut conn = self.conn.clone();
+        redis::Script::new(ENSURE_ZSET_SCRIPT)
+            .key(inbox_key(inbox))
+            .arg(SENTINEL)
+            .arg(since.map_or("-inf".to_string(), |since| since.to_string()))
+            .invoke_async(&mut conn)
+            .await
+            .wrap_err("Failed to execute Redis script")
+    }
+
+    /// Fetches the emails for `members`, in the same order, in one round trip.
+    async fn load(&self, inbox: &str, members: &[String]) -> Result<Vec<ExtendedEmail>> {
+        let ids: Vec<&String> = members.iter().filter(|m| !is_legacy(m)).collect();
+        let mut pipe = redis::pipe();
+        for id in &ids {
+            pipe.hgetall(metadata_key(inbox, id))
+                .get(body_key(inbox, id));
+        }
+        let mut conn = self.conn.clone();
+        let values: Vec<redis::Value> = if ids.is_empty() {
+            Vec::new()
+        } else {
+            pipe.query_async(&mut conn).await?
+        };
+
+        let mut values = values.into_iter();
+        let mut emails = Vec::with_capacity(members.len());
+        for member in members {
+            if is_legacy(member) {
+                emails.extend(serde_json::from_str(member).ok());
+                continue;
+            }
+            let (Some(metadata), Some(body)) = (values.next(), values.next()) else {
+                return Err(eyre!("Redis returned too few replies"));
+            };
+            // The keys can expire on their own, if the inbox did.
+            if let Some(email) = read_email(
+                redis::from_redis_value(&metadata)?,
+                redis::from_redis_value(&body)?,
+            )? {
+                emails.push(email);
+            }
+        }
+        Ok(emails)
+    }
+
+    /// Removes `members` from `inbox`, along with their metadata and bodies.
+    async fn remove(&self, inbox: &str, members: &[String]) -> Result<usize> {
+        if members.is_empty() {
+            return Ok(0);
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        queue_removal(&mut pipe, inbox, members);
+        let mut conn = self.conn.clone();
+        let (removed,): (usize,) = pipe.query_async(&mut conn).await?;
+        Ok(removed)
+    }
+}
+
+#[async_trait]
+impl MailStore for RedisStore {
+    async fn store(&self, inbox: &str, email: &ExtendedEmail) -> Result<()> {
+        let score = DateTime::parse_from_rfc3339(&email.timestamp)
+            .wrap_err("Timestamp parse error")?
+            .timestamp();
+        let id = &email.email.id;
+        let envelope = vortex_smtp::Email {
+            data: String::new(),
+            ..email.email.clone()
+        };
+
+        let mut metadata = vec![
+            ("email", serde_json::to_string(&envelope)?),
+            ("timestamp", email.timestamp.clone()),
+            ("size", email.email.data.len().to_string()),
+        ];
+        if let Some(authentication) = &email.authentication {
+            metadata.push(("authentication", serde_json::to_string(authentication)?));
+        }
+        // So summaries don't need the body.
+        let summary = EmailSummary::from(email);
+        metadata.push(("from", summary.from));
+        metadata.push(("snippet", summary.snippet));
+        if let Some(subject) = summary.subject {
+            metadata.push(("subject", subject));
+        }
+
+        let script = redis::Script::new(STORE_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(inbox_key(inbox))
+            .key(metadata_key(inbox, id))
+            .key(body_key(inbox, id))
+            .arg(id)
+            .arg(score);
+        for (field, value) in &metadata {
+            invocation.arg(field).arg(value);
+        }
+        invocation.arg(&email.email.data);
+
+        let mut conn = self.conn.clone();
+        let _: () = invocation
+            .invoke_async(&mut conn)
+            .await
+            .wrap_err("Failed to execute Redis script")?;
+        Ok(())
+    }
+
+    async fn list(&self, inbox: &str) -> Result<Vec<ExtendedEmail>> {
+        let members = self.members(inbox, None).await?;
+        self.load(inbox, &members).await
+    }
+
+    async fn list_page(&self, inbox: &str, page: &Page) -> Result<Vec<ExtendedEmail>> {
+        // The sorted set is just IDs, so slice it here and only fetch the page.
+        let members = self.page(inbox, page).await?;
+        self.load(inbox, &members).await
+    }
+
+    async fn list_summaries(&self, inbox: &str, page: &Page) -> Result<Vec<EmailSummary>> {
+        let members = self.page(inbox, page).await?;
+        let ids: Vec<&String> = members.iter().filter(|m| !is_legacy(m)).collect();
+        let mut pipe = redis::pipe();
+        for id in &ids {
+            pipe.hgetall(metadata_key(inbox, id));
+        }
+        let mut conn = self.conn.clone();
+        let metadata: Vec<HashMap<String, String>> = if ids.is_empty() {
+            Vec::new()
+        } else {
+            pipe.query_async(&mut conn).await?
+        };
+
+        let mut metadata = metadata.into_iter();
+        let mut summaries = Vec::with_capacity(members.len());
+        for member in &members {
+            if is_legacy(member) {
+                if let Ok(email) = serde_json::from_str::<ExtendedEmail>(member) {
+                    summaries.push((&email).into());
+                }
+                continue;
+            }
+            let Some(metadata) = metadata.next() else {
+                return Err(eyre!("Redis returned too few replies"));
+            };
+            summaries.extend(read_summary(member, metadata)?);
+        }
+        Ok(summaries)
+    }
+
+    async fn get(&self, inbox: &str, id: &str) -> Result<Option<ExtendedEmail>> {
+        if !is_message_id(id) {
+            return Ok(None);
+        }
+        let mut conn = self.conn.clone();
+        let (metadata, body): (HashMap<String, String>, Option<String>) = redis::pipe()
+            .hgetall(metadata_key(inbox, id))
+            .get(body_key(inbox, id))
+            .query_async(&mut conn)
+            .await?;
+        if let Some(email) = read_email(metadata, body)? {
+            return Ok(Some(email));
+        }
+
+        let legacy = self.legacy_members(inbox).await?;
+        Ok(self
+            .load(inbox, &legacy)
+            .await?
+            .into_iter()
+            .find(|email| email.email.id == id))
+    }
+
+    async fn delete(&self, inbox: &str, id: &str) -> Result<bool> {
+        if !is_message_id(id) {
+            return Ok(false);
+        }
+        if self.remove(inbox, &[id.to_string()]).await? > 0 {
+            return Ok(true);
+        }
+
+        // It might be from before emails had their own keys.
+        let member = self
+            .legacy_members(inbox)
+            .await?
+            .into_iter()
+            .find(|member| {
+                serde_json::from_str::<ExtendedEmail>(member)
+                    .is_ok_and(|email| email.email.id == id)
+            });
+        match member {
+            Some(member) => Ok(self.remove(inbox, &[member]).await? > 0),
+            None => Ok(false),
+        }
+    }
+
+    async fn clear(&self, inbox: &str) -> Result<()> {
+        let mut conn = self.conn.clone();
+        let members: Vec<String> = conn.zrange(inbox_key(inbox), 0, -1).await?;
+        let mut keys: Vec<String> = members
+            .iter()
+            .filter(|member| *member != SENTINEL && !is_legacy(member))
+            .flat_map(|id| [metadata_key(inbox, id), body_key(inbox, id)])
+            .collect();
+        keys.push(inbox_key(inbox));
+
+        let _: () = conn.del(keys).await?;
+        Ok(())
+    }
+
+    async fn exists(&self, inbox: &str) -> Result<bool> {
+        let mut conn = self.conn.clone();
+        Ok(conn.exists(inbox_key(inbox)).await?)
+    }
+
+    async fn expire(&self, inbox: &str, ttl: Duration) -> Result<()> {
+        let mut conn = self.conn.clone();
+        let _: () = redis::Script::new(EXPIRE_SCRIPT)
+            .key(inbox_key(inbox))
+            .arg(ttl.as_secs().max(1))
+            .arg(SENTINEL)
+            .arg(message_prefix(inbox))
+            .invoke_async(&mut conn)
+            .await
+            .wrap_err("Failed to execute Redis script")?;
+        Ok(())
+    }
+
+    async fn sizes(&self, inbox: &str) -> Result<Vec<(String, usize)>> {
+        // Not members(), since that would open the inbox.
+        let mut conn = self.conn.clone();
+        let members: Vec<String> = conn.zrevrange(inbox_key(inbox), 0, -1).await?;
+        let members: Vec<String> = members
+            .into_iter()
+            .filter(|member| member != SENTINEL)
+            .collect();
+
+        let mut pipe = redis::pipe();
+        for id in members.iter().filter(|member| !is_legacy(member)) {
+            pipe.hget(metadata_key(inbox, id), "size");
+        }
+        let sizes: Vec<Option<usize>> = if pipe.cmd_iter().next().is_none() {
+            Vec::new()
+        } else {
+            pipe.query_async(&mut conn).await?
+        };
+
+        let mut sizes = sizes.into_iter();
+        let mut result = Vec::with_capacity(members.len());
+        for member in members {
+            if is_legacy(&member) {
+                if let Ok(email) = serde_json::from_str::<ExtendedEmail>(&member) {
+                    result.push((email.email.id, email.email.data.len()));
+                }
+            } else if let Some(size) = sizes.next().flatten() {
+                // The metadata can expire on its own, if the inbox did.
+                result.push((member, size));
+            }
+        }
+        Ok(result)
+    }
+
+    async fn prune(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        let mut conn = self.conn.clone();
+        let keys: Vec<String> = {
+            let mut iter = conn.scan_match::<_, String>("emails:*").await?;
+            let mut keys = Vec::new();
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+            keys
+        };
+
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        // One round trip to find what's expired everywhere, and one to remove it.
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            // Start from 0 so the sentinel (at -1) stays, and the inbox with it.
+            pipe.zrangebyscore(key, 0, format!("({}", cutoff.timestamp()));
+        }
+        let expired: Vec<Vec<String>> = pipe.query_async(&mut conn).await?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (key, members) in keys.iter().zip(&expired) {
+            if let (Some(inbox), false) = (key.strip_prefix("emails:"), members.is_empty()) {
+                queue_removal(&mut pipe, inbox, members);
+            }
+        }
+        if pipe.cmd_iter().next().is_none() {
+            return Ok(0);
+        }
+        let removed: Vec<usize> = pipe.query_async(&mut conn).await?;
+        Ok(removed.into_iter().sum())
+    }
+
+    async fn prune_inboxes(&self) -> Result<usize> {
+        // Redis deletes idle inboxes itself, since `expire` sets a TTL on their keys.
+        Ok(0)
+    }
+}
+
+#[async_trait]
+impl ForwardingStore for RedisStore {
+    async fn forwarding_rules(&self, inbox: &str) -> Result<Vec<Rule>> {
+        let mut conn = self.conn.clone();
+        let rules: Vec<String> = conn.hvals(format!("forwarding:{inbox}")).await?;
+        Ok(rules
+            .into_iter()
+            .filter_map(|json| serde_json::from_str(&json).ok())
+            .collect())
+    }
+
+    async fn add_forwarding_rule(&self, inbox: &str, rule: &Rule) -> Result<()> {
+        let json = serde_json::to_string(rule)?;
+        let mut conn = self.conn.clone();
+        let _: () = conn
+            .hset(format!("forwarding:{inbox}"), &rule.to, json)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_forwarding_rule(&self, inbox: &str, to: &str) -> Result<bool> {
+        let mut conn = self.conn.clone();
+        let removed: u32 = conn.hdel(format!("forwarding:{inbox}"), to).await?;
+        Ok(removed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_email;
+
+    #[test]
+    fn test_is_legacy() {
+        let email = test_email("a", "2025-01-01T00:00:00Z", "hello");
+        assert!(is_legacy(&serde_json::to_string(&email).unwrap()));
+        assert!(!is_legacy("V1StGXR8_Z5jdHi6B-myT"));
+        assert!(!is_legacy(SENTINEL));
+    }
+
+    #[test]
+    fn test_is_message_id() {
+        assert!(is_message_id("V1StGXR8_Z5jdHi6B-myT"));
+        assert!(!is_message_id("V1StGXR8_Z5jdHi6B-myT:body"));
+        assert!(!is_message_id(SENTINEL));
+        assert!(!is_message_id(""));
+    }
+
+    #[test]
+    fn test_read_email() {
+        assert!(read_email(HashMap::new(), Some("hello".to_string()))
+            .unwrap()
+            .is_none());
+
+        let email = test_email("a", "2025-01-01T00:00:00Z", "");
+        let metadata = HashMap::from([
+            (
+                "email".to_string(),
+                serde_json::to_string(&email.email).unwrap(),
+            ),
+            ("timestamp".to_string(), email.timestamp.clone()),
+            ("size".to_string(), "5".to_string()),
+        ]);
+        let read = read_email(metadata.clone(), Some("hello".to_string()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.email.id, "a");
+        assert_eq!(read.email.data, "hello");
+        assert_eq!(read.timestamp, email.timestamp);
+        assert!(read.authentication.is_none());
+
+        // The body can expire a moment before the metadata.
+        let read = read_email(metadata.clone(), None).unwrap().unwrap();
+        assert_eq!(read.email.data, "");
+
+        let mut broken = metadata;
+        broken.insert("email".to_string(), "{".to_string());
+        assert!(read_email(broken, None).is_err());
+    }
+
+    #[test]
+    fn test_read_summary() {
+        assert!(read_summary("a", HashMap::new()).unwrap().is_none());
+
+        let email = test_email("a", "2025-01-01T00:00:00Z", "");
+        let mut metadata = HashMap::from([
+            (
+                "email".to_string(),
+                serde_json::to_string(&email.email).unwrap(),
+            ),
+            ("timestamp".to_string(), email.timestamp.clone()),
+            ("size".to_string(), "5".to_string()),
+        ]);
+        // Stored before summaries were, so it falls back on the envelope.
+        let summary = read_summary("a", metadata.clone()).unwrap().unwrap();
+        assert_eq!(summary.from, "sender@example.com");
+        assert_eq!(summary.size, 5);
+        assert!(summary.subject.is_none());
+
+        metadata.insert("from".to_string(), "shop@example.com".to_string());
+        metadata.insert("subject".to_string(), "Your receipt".to_string());
+        metadata.insert("snippet".to_string(), "Thanks!".to_string());
+        let summary = read_summary("a", metadata).unwrap().unwrap();
+        assert_eq!(summary.from, "shop@example.com");
+        assert_eq!(summary.subject.as_deref(), Some("Your receipt"));
+        assert_eq!(summary.snippet, "Thanks!");
+        assert_eq!(summary.date, email.timestamp);
+    }
+
+    /// Needs a Redis server to scribble on, at `REDIS_URL` or on localhost.
+    #[tokio::test]
+    #[ignore]
+    async fn test_redis_store() {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".into());
+        let store = RedisStore::connect(&url).await.unwrap();
+        let inbox = &format!("test-{}@vortex.dev", std::process::id());
+        let mut conn = store.conn.clone();
+        store.clear(inbox).await.unwrap();
+
+        // Looking for mail doesn't open the inbox.
+        assert!(store.get(inbox, "a").await.unwrap().is_none());
+        assert!(!store.delete(inbox, "a").await.unwrap());
+        assert!(store.sizes(inbox).await.unwrap().is_empty());
+        assert!(!store.exists(inbox).await.unwrap());
+        assert!(store.list(inbox).await.unwrap().is_empty());
+        assert!(store.exists(inbox).await.unwrap());
+
+        store
+            .expire(inbox, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        let email = |id, day| test_email(id, &format!("2025-01-0{day}T00:00:00Z"), "hello");
+        store.store(inbox, &email("a", 1)).await.unwrap();
+        store.store(inbox, &email("b", 2)).await.unwrap();
+        // An email from before messages had their own keys.
+        let legacy = email("c", 3);
+        let _: () = conn
+            .zadd(
+                inbox_key(inbox),
+                serde_json::to_string(&legacy).unwrap(),
+                1735862400,
+            )
+            .await
+            .unwrap();
+
+        // New messages expire with the inbox they're in.
+        for key in [metadata_key(inbox, "a"), body_key(inbox, "b")] {
+            let ttl: i64 = conn.ttl(key).await.unwrap();
+            assert!(ttl > 0 && ttl <= 3600);
+        }
+
+        let ids =
+            |emails: Vec<ExtendedEmail>| emails.into_iter().map(|e| e.email.id).collect::<Vec<_>>();
+        assert_eq!(ids(store.list(inbox).await.unwrap()), ["c", "b", "a"]);
+        assert_eq!(
+            ids(store
+                .list_page(
+                    inbox,
+                    &Page {
+                        limit: Some(1),
+                        before: Some("c".to_string()),
+                        since: None,
+                    }
+                )
+                .await
+                .unwrap()),
+            ["b"]
+        );
+        let summaries = store.list_summaries(inbox, &Page::default()).await.unwrap();
+        assert_eq!(
+            summaries.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(),
+            ["c", "b", "a"]
+        );
+        assert_eq!(summaries[1].snippet, "hello");
+        assert_eq!(
+            store.get(inbox, "b").await.unwrap().unwrap().email.data,
+            "hello"
+        );
+        assert_eq!(store.get(inbox, "c").await.unwrap().unwrap().email.id, "c");
+        assert_eq!(
+            store.sizes(inbox).await.unwrap(),
+            [
+                ("c".to_string(), 5),
+                ("b".to_string(), 5),
+                ("a".to_string(), 5)
+            ]
+        );
+
+        assert!(store.delete(inbox, "c").await.unwrap());
+        assert!(!store.delete(inbox, "c").await.unwrap());
+
+        // IDs that aren't ours can't reach other messages' keys, or the sentinel.
+        assert!(!store.delete(inbox, "b:body").await.unwrap());
+        assert!(store.get(inbox, "b:body").await.unwrap().is_none());
+        assert_eq!(
+            store.get(inbox, "b").await.unwrap().unwrap().email.data,
+            "hello"
+        );
+        assert!(!store.delete(inbox, SENTINEL).await.unwrap());
+        let sentinel: Option<f64> = conn.zscore(inbox_key(inbox), SENTINEL).await.unwrap();
+        assert!(sentinel.is_some());
+        assert_eq!(
+            store
+                .prune("2025-01-02T00:00:00Z".parse().unwrap())
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(ids(store.list(inbox).await.unwrap()), ["b"]);
+        assert!(store.get(inbox, "a").await.unwrap().is_none());
+
+        store.clear(inbox).await.unwrap();
+        assert!(!store.exists(inbox).await.unwrap());
+        let left: bool = conn.exists(metadata_key(inbox, "b")).await.unwrap();
+        assert!(!left);
+    }
+}
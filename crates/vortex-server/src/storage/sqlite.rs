@@ -0,0 +1,636 @@
+//! Keeps mail in a SQLite database, for single-node deployments that want to keep
+//! their history around and search it.
+//!
+//! Each message's envelope lives in its own table, and an FTS5 index covers the
+//! subject, sender and text body.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use mail_parser::MessageParser;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{ForwardingStore, MailStore, Page, Usage};
+use crate::{forwarding::Rule, ExtendedEmail};
+
+const SCHEMA: &str = r#"
+    PRAGMA journal_mode = WAL;
+    PRAGMA foreign_keys = ON;
+
+    CREATE TABLE IF NOT EXISTS inboxes (
+        address TEXT PRIMARY KEY,
+        -- Unix seconds, or NULL to keep the inbox forever.
+        expires_at INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS messages (
+        rowid INTEGER PRIMARY KEY,
+        inbox TEXT NOT NULL REFERENCES inboxes (address) ON DELETE CASCADE,
+        id TEXT NOT NULL,
+        received_at INTEGER NOT NULL,
+        timestamp TEXT NOT NULL,
+        data TEXT NOT NULL,
+        authentication TEXT,
+        UNIQUE (inbox, id)
+    );
+    CREATE INDEX IF NOT EXISTS messages_by_inbox ON messages (inbox, received_at);
+
+    CREATE TABLE IF NOT EXISTS envelopes (
+        message INTEGER PRIMARY KEY REFERENCES messages (rowid) ON DELETE CASCADE,
+        mail_from TEXT NOT NULL,
+        rcpt_to TEXT NOT NULL,
+        dsn TEXT NOT NULL,
+        remote_addr TEXT,
+        helo TEXT,
+        authenticated_as TEXT
+    );
+
+    CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5 (
+        subject, sender, body,
+        content = '', contentless_delete = 1
+    );
+    CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+        DELETE FROM messages_fts WHERE rowid = old.rowid;
+    END;
+
+    CREATE TABLE IF NOT EXISTS forwarding (
+        inbox TEXT NOT NULL,
+        target TEXT NOT NULL,
+        rule TEXT NOT NULL,
+        PRIMARY KEY (inbox, target)
+    );
+"#;
+
+const SELECT_MESSAGES: &str = "
+    SELECT m.id, m.timestamp, m.data, m.authentication,
+           e.mail_from, e.rcpt_to, e.dsn, e.remote_addr, e.helo, e.authenticated_as
+    FROM messages m JOIN envelopes e ON e.message = m.rowid";
+
+#[derive(Clone)]
+pub struct SqliteStore {
+    // rusqlite is blocking, so every query runs on the blocking pool.
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let conn = Connection::open(path)
+            .wrap_err_with(|| format!("Failed to open SQLite database at {}", path.display()))?;
+        Self::init(conn)
+    }
+
+    #[cfg(test)]
+    fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)
+            .wrap_err("Failed to create SQLite schema")?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` on the connection, once expired inboxes are out of the way.
+    async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        self.call_unpurged(move |conn| {
+            purge_expired(conn)?;
+            f(conn)
+        })
+        .await
+    }
+
+    /// Like [`call`](Self::call), but leaves expired inboxes alone.
+    async fn call_unpurged<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn
+                .lock()
+                .map_err(|_| eyre!("SQLite connection poisoned"))?;
+            f(&mut conn)
+        })
+        .await?
+    }
+}
+
+fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+fn purge_expired(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.execute("DELETE FROM inboxes WHERE expires_at <= ?1", params![now()])
+}
+
+/// Reads a row of [`SELECT_MESSAGES`]. The JSON columns are decoded afterwards, since
+/// rusqlite can't return our errors from here.
+fn read_email(row: &rusqlite::Row<'_>) -> rusqlite::Result<Result<ExtendedEmail>> {
+    let id: String = row.get(0)?;
+    let timestamp: String = row.get(1)?;
+    let data: String = row.get(2)?;
+    let authentication: Option<String> = row.get(3)?;
+    let mail_from: String = row.get(4)?;
+    let rcpt_to: String = row.get(5)?;
+    let dsn: String = row.get(6)?;
+    let remote_addr: Option<String> = row.get(7)?;
+    let helo: Option<String> = row.get(8)?;
+    let authenticated_as: Option<String> = row.get(9)?;
+
+    Ok((|| {
+        Ok(ExtendedEmail {
+            email: vortex_smtp::Email {
+                mail_from,
+                rcpt_to: serde_json::from_str(&rcpt_to)?,
+                dsn: serde_json::from_str(&dsn)?,
+                data,
+                remote_addr: remote_addr.map(|addr| addr.parse()).transpose()?,
+                helo,
+                authenticated_as,
+                id,
+            },
+            timestamp,
+            authentication: authentication
+                .map(|json| serde_json::from_str(&json))
+                .transpose()?,
+        })
+    })())
+}
+
+/// The subject, sender and text body, for the search index.
+fn index_text(email: &vortex_smtp::Email) -> (String, String, String) {
+    let Some(message) = MessageParser::default().parse(email.data.as_bytes()) else {
+        return (String::new(), email.mail_from.clone(), email.data.clone());
+    };
+
+    let subject = message.subject().unwrap_or_default().to_string();
+    let mut sender = email.mail_from.clone();
+    if let Some(from) = message.from().and_then(|from| from.first()) {
+        for part in [from.name(), from.address()].into_iter().flatten() {
+            sender.push(' ');
+            sender.push_str(part);
+        }
+    }
+    let body = (0..)
+        .map_while(|pos| message.body_text(pos))
+        .collect::<Vec<_>>()
+        .join("\n");
+    (subject, sender, body)
+}
+
+#[async_trait]
+impl MailStore for SqliteStore {
+    async fn store(&self, inbox: &str, email: &ExtendedEmail) -> Result<()> {
+        let received_at = DateTime::parse_from_rfc3339(&email.timestamp)
+            .wrap_err("Timestamp parse error")?
+            .timestamp();
+        let authentication = email
+            .authentication
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+        let rcpt_to = serde_json::to_string(&email.email.rcpt_to)?;
+        let dsn = serde_json::to_string(&email.email.dsn)?;
+        let (subject, sender, body) = index_text(&email.email);
+        let inbox = inbox.to_string();
+        let email = email.clone();
+
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT OR IGNORE INTO inboxes (address) VALUES (?1)",
+                params![inbox],
+            )?;
+            // REPLACE would delete the old row without firing messages_fts_delete,
+            // leaving it in the search index.
+            tx.execute(
+                "DELETE FROM messages WHERE inbox = ?1 AND id = ?2",
+                params![inbox, email.email.id],
+            )?;
+            tx.execute(
+                "INSERT INTO messages
+                    (inbox, id, received_at, timestamp, data, authentication)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    inbox,
+                    email.email.id,
+                    received_at,
+                    email.timestamp,
+                    email.email.data,
+                    authentication,
+                ],
+            )?;
+            let message = tx.last_insert_rowid();
+            tx.execute(
+                "INSERT INTO envelopes
+                    (message, mail_from, rcpt_to, dsn, remote_addr, helo, authenticated_as)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    message,
+                    email.email.mail_from,
+                    rcpt_to,
+                    dsn,
+                    email.email.remote_addr.map(|addr| addr.to_string()),
+                    email.email.helo,
+                    email.email.authenticated_as,
+                ],
+            )?;
+            tx.execute(
+                "INSERT INTO messages_fts (rowid, subject, sender, body) VALUES (?1, ?2, ?3, ?4)",
+                params![message, subject, sender, body],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list(&self, inbox: &str) -> Result<Vec<ExtendedEmail>> {
+        let inbox = inbox.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO inboxes (address) VALUES (?1)",
+                params![inbox],
+            )?;
+            let mut statement = conn.prepare(&format!(
+                "{SELECT_MESSAGES} WHERE m.inbox = ?1 ORDER BY m.received_at DESC, m.rowid DESC"
+            ))?;
+            let emails = statement
+                .query_map(params![inbox], read_email)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            emails.into_iter().collect()
+        })
+        .await
+    }
+
+    async fn list_page(&self, inbox: &str, page: &Page) -> Result<Vec<ExtendedEmail>> {
+        let inbox = inbox.to_string();
+        let before = page.before.clone();
+        // A negative LIMIT means no limit.
+        let limit = page
+            .limit
+            .map_or(-1, |limit| i64::try_from(limit).unwrap_or(i64::MAX));
+        let since = page.since.map(|since| since.timestamp());
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO inboxes (address) VALUES (?1)",
+                params![inbox],
+            )?;
+            let mut statement = conn.prepare(&format!(
+                "{SELECT_MESSAGES}
+                WHERE m.inbox = ?1 AND (?2 IS NULL OR (m.received_at, m.rowid) < (
+                    SELECT received_at, rowid FROM messages WHERE inbox = ?1 AND id = ?2
+                ))
+                AND (?4 IS NULL OR m.received_at >= ?4)
+                ORDER BY m.received_at DESC, m.rowid DESC
+                LIMIT ?3"
+            ))?;
+            let emails = statement
+                .query_map(params![inbox, before, limit, since], read_email)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            emails.into_iter().collect()
+        })
+        .await
+    }
+
+    async fn get(&self, inbox: &str, id: &str) -> Result<Option<ExtendedEmail>> {
+        let (inbox, id) = (inbox.to_string(), id.to_string());
+        self.call(move |conn| {
+            conn.query_row(
+                &format!("{SELECT_MESSAGES} WHERE m.inbox = ?1 AND m.id = ?2"),
+                params![inbox, id],
+                read_email,
+            )
+            .optional()?
+            .transpose()
+        })
+        .await
+    }
+
+    async fn delete(&self, inbox: &str, id: &str) -> Result<bool> {
+        let (inbox, id) = (inbox.to_string(), id.to_string());
+        self.call(move |conn| {
+            let deleted = conn.execute(
+                "DELETE FROM messages WHERE inbox = ?1 AND id = ?2",
+                params![inbox, id],
+            )?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    async fn clear(&self, inbox: &str) -> Result<()> {
+        let inbox = inbox.to_string();
+        self.call(move |conn| {
+            conn.execute("DELETE FROM inboxes WHERE address = ?1", params![inbox])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn exists(&self, inbox: &str) -> Result<bool> {
+        let inbox = inbox.to_string();
+        self.call(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT 1 FROM inboxes WHERE address = ?1",
+                    params![inbox],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some())
+        })
+        .await
+    }
+
+    async fn expire(&self, inbox: &str, ttl: Duration) -> Result<()> {
+        let inbox = inbox.to_string();
+        let expires_at = now() + ttl.as_secs() as i64;
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE inboxes SET expires_at = ?2 WHERE address = ?1",
+                params![inbox, expires_at],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn search(&self, inbox: &str, query: &str) -> Result<Option<Vec<ExtendedEmail>>> {
+        let inbox = inbox.to_string();
+        // Quote each word, so FTS5 doesn't try to parse its query syntax out of them.
+        let query = query
+            .split_whitespace()
+            .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if query.is_empty() {
+            return Ok(Some(Vec::new()));
+        }
+
+        self.call(move |conn| {
+            let mut statement = conn.prepare(&format!(
+                "{SELECT_MESSAGES}
+                JOIN messages_fts f ON f.rowid = m.rowid
+                WHERE m.inbox = ?1 AND messages_fts MATCH ?2
+                ORDER BY f.rank"
+            ))?;
+            let emails = statement
+                .query_map(params![inbox, query], read_email)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            emails.into_iter().collect::<Result<_>>().map(Some)
+        })
+        .await
+    }
+
+    async fn sizes(&self, inbox: &str) -> Result<Vec<(String, usize)>> {
+        let inbox = inbox.to_string();
+        self.call(move |conn| {
+            let mut statement = conn.prepare(
+                "SELECT id, LENGTH(CAST(data AS BLOB)) FROM messages
+                WHERE inbox = ?1 ORDER BY received_at DESC, rowid DESC",
+            )?;
+            let sizes = statement
+                .query_map(params![inbox], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as usize))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(sizes)
+        })
+        .await
+    }
+
+    async fn usage(&self, inbox: &str) -> Result<Usage> {
+        let inbox = inbox.to_string();
+        self.call(move |conn| {
+            let (messages, bytes) = conn.query_row(
+                "SELECT COUNT(*), COALESCE(SUM(LENGTH(CAST(data AS BLOB))), 0)
+                FROM messages WHERE inbox = ?1",
+                params![inbox],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+            )?;
+            Ok(Usage {
+                messages: messages as usize,
+                bytes: bytes as usize,
+            })
+        })
+        .await
+    }
+
+    async fn prune(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        let cutoff = cutoff.timestamp();
+        self.call(move |conn| {
+            Ok(conn.execute(
+                "DELETE FROM messages WHERE received_at < ?1",
+                params![cutoff],
+            )?)
+        })
+        .await
+    }
+
+    async fn prune_inboxes(&self) -> Result<usize> {
+        self.call_unpurged(|conn| Ok(purge_expired(conn)?)).await
+    }
+}
+
+#[async_trait]
+impl ForwardingStore for SqliteStore {
+    async fn forwarding_rules(&self, inbox: &str) -> Result<Vec<Rule>> {
+        let inbox = inbox.to_string();
+        self.call(move |conn| {
+            let mut statement = conn.prepare("SELECT rule FROM forwarding WHERE inbox = ?1")?;
+            let rules = statement
+                .query_map(params![inbox], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(rules
+                .into_iter()
+                .filter_map(|json| serde_json::from_str(&json).ok())
+                .collect())
+        })
+        .await
+    }
+
+    async fn add_forwarding_rule(&self, inbox: &str, rule: &Rule) -> Result<()> {
+        let json = serde_json::to_string(rule)?;
+        let (inbox, target) = (inbox.to_string(), rule.to.clone());
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO forwarding (inbox, target, rule) VALUES (?1, ?2, ?3)",
+                params![inbox, target, json],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_forwarding_rule(&self, inbox: &str, to: &str) -> Result<bool> {
+        let (inbox, to) = (inbox.to_string(), to.to_string());
+        self.call(move |conn| {
+            let deleted = conn.execute(
+                "DELETE FROM forwarding WHERE inbox = ?1 AND target = ?2",
+                params![inbox, to],
+            )?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_email;
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let inbox = "alice@vortex.dev";
+        assert!(!store.exists(inbox).await.unwrap());
+        assert!(store.list(inbox).await.unwrap().is_empty());
+        assert!(store.exists(inbox).await.unwrap());
+
+        let mut receipt = test_email(
+            "a",
+            "2025-01-01T00:00:00Z",
+            "From: Shop <shop@example.com>\r\nSubject: Your receipt\r\n\r\nThanks for your order!\r\n",
+        );
+        let code = test_email(
+            "b",
+            "2025-01-02T00:00:00Z",
+            "From: Bank <otp@bank.example>\r\nSubject: Login code\r\n\r\nYour code is 123456\r\n",
+        );
+        // These only make it back out if the columns are read right.
+        receipt.email.remote_addr = Some("192.0.2.1:1234".parse().unwrap());
+        receipt.email.helo = Some("mx.example.com".to_string());
+        store.store(inbox, &receipt).await.unwrap();
+        store.store(inbox, &code).await.unwrap();
+
+        let listed = store.list(inbox).await.unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].email.id, "b");
+        assert_eq!(listed[1].email.remote_addr, receipt.email.remote_addr);
+        assert_eq!(
+            store.get(inbox, "a").await.unwrap().unwrap().email.data,
+            receipt.email.data
+        );
+
+        async fn search(store: &SqliteStore, inbox: &str, query: &str) -> Vec<ExtendedEmail> {
+            store.search(inbox, query).await.unwrap().unwrap()
+        }
+        let ids =
+            |emails: Vec<ExtendedEmail>| emails.into_iter().map(|e| e.email.id).collect::<Vec<_>>();
+        assert_eq!(ids(search(&store, inbox, "receipt").await), ["a"]);
+        assert_eq!(ids(search(&store, inbox, "bank code").await), ["b"]);
+        assert_eq!(
+            ids(search(&store, inbox, "\"unbalanced").await),
+            Vec::<String>::new()
+        );
+        assert!(search(&store, "bob@vortex.dev", "receipt").await.is_empty());
+
+        // Storing the same ID again replaces the message, search index included.
+        let resent = test_email(
+            "b",
+            "2025-01-02T00:00:00Z",
+            "From: Bank <otp@bank.example>\r\nSubject: New login code\r\n\r\nYour code is 654321\r\n",
+        );
+        store.store(inbox, &resent).await.unwrap();
+        assert_eq!(store.list(inbox).await.unwrap().len(), 2);
+        let indexed = store
+            .call(|conn| {
+                Ok(
+                    conn.query_row("SELECT COUNT(*) FROM messages_fts", [], |row| {
+                        row.get::<_, i64>(0)
+                    })?,
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(indexed, 2);
+        assert!(search(&store, inbox, "123456").await.is_empty());
+        assert_eq!(ids(search(&store, inbox, "654321").await), ["b"]);
+
+        let cutoff = "2025-01-02T00:00:00Z".parse().unwrap();
+        assert_eq!(store.prune(cutoff).await.unwrap(), 1);
+        assert_eq!(store.prune(cutoff).await.unwrap(), 0);
+        assert_eq!(ids(store.list(inbox).await.unwrap()), ["b"]);
+        assert!(search(&store, inbox, "receipt").await.is_empty());
+        assert!(!store.delete(inbox, "a").await.unwrap());
+
+        assert!(store.delete(inbox, "b").await.unwrap());
+        assert!(!store.delete(inbox, "b").await.unwrap());
+        assert!(store.get(inbox, "b").await.unwrap().is_none());
+        assert!(search(&store, inbox, "code").await.is_empty());
+        store.store(inbox, &code).await.unwrap();
+
+        store.clear(inbox).await.unwrap();
+        assert!(!store.exists(inbox).await.unwrap());
+        assert!(search(&store, inbox, "code").await.is_empty());
+
+        // The sweeper deletes idle inboxes, even if nobody looks at them again.
+        assert!(store.list(inbox).await.unwrap().is_empty());
+        store.expire(inbox, Duration::ZERO).await.unwrap();
+        assert_eq!(store.prune_inboxes().await.unwrap(), 1);
+        assert_eq!(store.prune_inboxes().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_pagination() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let inbox = "alice@vortex.dev";
+        for (id, timestamp) in [
+            ("a", "2025-01-01T00:00:00Z"),
+            ("b", "2025-01-02T00:00:00Z"),
+            ("c", "2025-01-03T00:00:00Z"),
+        ] {
+            store
+                .store(
+                    inbox,
+                    &test_email(id, timestamp, "Subject: Hi\r\n\r\nHello\r\n"),
+                )
+                .await
+                .unwrap();
+        }
+
+        async fn page(store: &SqliteStore, page: Page) -> Vec<String> {
+            let emails = store.list_page("alice@vortex.dev", &page).await.unwrap();
+            emails.into_iter().map(|e| e.email.id).collect()
+        }
+        let page_of = |limit, before: Option<&str>| Page {
+            limit,
+            before: before.map(str::to_string),
+            since: None,
+        };
+        assert_eq!(page(&store, page_of(Some(2), None)).await, ["c", "b"]);
+        assert_eq!(page(&store, page_of(Some(2), Some("b"))).await, ["a"]);
+        assert!(page(&store, page_of(None, Some("gone"))).await.is_empty());
+
+        // Old mail is left out before the limit, so the page is still full.
+        let since = Page {
+            limit: Some(2),
+            before: None,
+            since: Some("2025-01-02T00:00:00Z".parse().unwrap()),
+        };
+        assert_eq!(page(&store, since).await, ["c", "b"]);
+        let since = Page {
+            limit: Some(2),
+            before: Some("c".to_string()),
+            since: Some("2025-01-02T00:00:00Z".parse().unwrap()),
+        };
+        assert_eq!(page(&store, since).await, ["b"]);
+    }
+}
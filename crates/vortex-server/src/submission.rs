@@ -0,0 +1,104 @@
+use color_eyre::{eyre::eyre, Result};
+use subtle::ConstantTimeEq;
+
+/// An account that can deposit mail through the authenticated submission listener.
+#[derive(Clone, Debug)]
+pub struct SubmissionUser {
+    pub username: String,
+    password: String,
+    /// Either full addresses, or `*@domain` to own every inbox on a domain.
+    inboxes: Vec<String>,
+}
+
+impl SubmissionUser {
+    pub fn owns(&self, inbox: &str) -> bool {
+        let inbox = inbox.to_lowercase();
+        self.inboxes
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*@") {
+                Some(domain) => inbox
+                    .rsplit_once('@')
+                    .is_some_and(|(_, inbox_domain)| inbox_domain == domain),
+                None => *pattern == inbox,
+            })
+    }
+}
+
+/// Parses the `SMTP_USERS` env var, which looks like
+/// `alice:hunter2:alice@vortex.dev|*@qa.vortex.dev,bob:correcthorse:bob@vortex.dev`.
+pub fn parse_users(spec: &str) -> Result<Vec<SubmissionUser>> {
+    spec.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let mut parts = entry.trim().splitn(3, ':');
+            let (Some(username), Some(password), Some(inboxes)) =
+                (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(eyre!(
+                    "SMTP_USERS entry {entry:?} must look like user:password:inbox|inbox"
+                ));
+            };
+            if username.is_empty() || password.is_empty() {
+                return Err(eyre!(
+                    "SMTP_USERS entry {entry:?} has an empty user or password"
+                ));
+            }
+
+            Ok(SubmissionUser {
+                username: username.to_string(),
+                password: password.to_string(),
+                inboxes: inboxes
+                    .split('|')
+                    .filter(|inbox| !inbox.is_empty())
+                    .map(str::to_lowercase)
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+pub fn find_user<'a>(
+    users: &'a [SubmissionUser],
+    username: &str,
+    password: &str,
+) -> Option<&'a SubmissionUser> {
+    // Compared in constant time, so how long we take doesn't give the password away.
+    users.iter().find(|user| {
+        let matches = user.username.as_bytes().ct_eq(username.as_bytes())
+            & user.password.as_bytes().ct_eq(password.as_bytes());
+        matches.into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_users() {
+        let users =
+            parse_users("alice:hunter2:alice@vortex.dev|*@qa.vortex.dev,bob:pw:bob@vortex.dev")
+                .unwrap();
+        assert_eq!(users.len(), 2);
+        assert!(find_user(&users, "alice", "hunter2").is_some());
+        assert!(find_user(&users, "alice", "wrong").is_none());
+        assert!(find_user(&users, "bob", "pw").is_some());
+    }
+
+    #[test]
+    fn test_parse_users_rejects_malformed_entries() {
+        assert!(parse_users("alice:hunter2").is_err());
+        assert!(parse_users(":hunter2:alice@vortex.dev").is_err());
+    }
+
+    #[test]
+    fn test_owns() {
+        let users = parse_users("alice:hunter2:alice@vortex.dev|*@qa.vortex.dev").unwrap();
+        let alice = &users[0];
+        assert!(alice.owns("alice@vortex.dev"));
+        assert!(alice.owns("Alice@Vortex.dev"));
+        assert!(alice.owns("anything@qa.vortex.dev"));
+        assert!(!alice.owns("bob@vortex.dev"));
+        assert!(!alice.owns("alice@evil.qa.vortex.dev"));
+    }
+}
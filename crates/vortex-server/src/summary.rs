@@ -0,0 +1,79 @@
+//! The short form of an email that inbox listings can ask for, so polling a busy
+//! inbox doesn't mean downloading every attachment in it.
+
+use mail_parser::MessageParser;
+use serde::Serialize;
+
+use crate::ExtendedEmail;
+
+/// How much of the body goes in a snippet, in characters.
+const SNIPPET_LENGTH: usize = 200;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct EmailSummary {
+    pub id: String,
+    /// The `From` header's address, or the envelope sender if there isn't one.
+    pub from: String,
+    pub subject: Option<String>,
+    /// When we received it, not the `Date` header.
+    pub date: String,
+    pub size: usize,
+    /// The start of the text body, with whitespace squashed.
+    pub snippet: String,
+}
+
+impl From<&ExtendedEmail> for EmailSummary {
+    fn from(email: &ExtendedEmail) -> Self {
+        let message = MessageParser::default().parse(email.email.data.as_bytes());
+        let from = message
+            .as_ref()
+            .and_then(|message| message.from()?.first()?.address())
+            .unwrap_or(&email.email.mail_from)
+            .to_string();
+        let subject = message
+            .as_ref()
+            .and_then(|message| message.subject())
+            .map(String::from);
+        let snippet = message
+            .as_ref()
+            .and_then(|message| message.body_text(0))
+            .map(|body| {
+                body.split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+                    .chars()
+                    .take(SNIPPET_LENGTH)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            id: email.email.id.clone(),
+            from,
+            subject,
+            date: email.timestamp.clone(),
+            size: email.email.data.len(),
+            snippet,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let email = crate::storage::test_email(
+            "abc",
+            "2025-01-01T00:00:00Z",
+            "From: Shop <shop@example.com>\r\nSubject: Your order\r\n\r\nThanks   for\r\nyour order!\r\n",
+        );
+
+        let summary = EmailSummary::from(&email);
+        assert_eq!(summary.from, "shop@example.com");
+        assert_eq!(summary.subject.as_deref(), Some("Your order"));
+        assert_eq!(summary.snippet, "Thanks for your order!");
+        assert_eq!(summary.size, email.email.data.len());
+    }
+}
@@ -0,0 +1,701 @@
+//! An SMTP client, for handing mail we've captured over to a smarthost.
+//!
+//! It speaks ESMTP with STARTTLS (or implicit TLS), AUTH PLAIN/LOGIN, PIPELINING
+//! and CHUNKING, and uses the same [`Reply`] type as the server side in
+//! `vortex-smtp`.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+pub use vortex_smtp::messages::Reply;
+
+mod reply;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("SMTP network error: {0}")]
+    NetworkError(#[from] std::io::Error),
+
+    #[error("server closed the connection")]
+    ConnectionClosed,
+
+    #[error("server timed out")]
+    Timeout,
+
+    #[error("malformed reply from server: {0:?}")]
+    MalformedReply(String),
+
+    #[error("server replied to {command} with {}", .reply.to_string().trim_end())]
+    UnexpectedReply { command: &'static str, reply: Reply },
+
+    #[error("server doesn't support STARTTLS")]
+    StartTlsUnsupported,
+
+    #[error("server doesn't support AUTH PLAIN or LOGIN")]
+    AuthUnsupported,
+
+    #[error("{0:?} isn't a valid TLS server name")]
+    InvalidServerName(String),
+
+    #[error("server rejected every recipient")]
+    AllRecipientsRejected(Vec<Rejection>),
+}
+
+/// How the connection to the server is secured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Security {
+    /// Upgrade with STARTTLS, and give up if the server doesn't offer it.
+    #[default]
+    StartTls,
+    /// TLS from the start (RFC 8314), usually on port 465.
+    ImplicitTls,
+    /// No TLS at all. Only for servers on the same machine or network!
+    Plain,
+}
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where to send mail, and how.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub security: Security,
+    /// If set, we AUTH with these after EHLO (and STARTTLS).
+    pub credentials: Option<Credentials>,
+    /// What we call ourselves in EHLO.
+    pub helo_name: String,
+    /// Who we trust to sign the server's certificate. Defaults to the Mozilla root
+    /// certificates.
+    pub tls: Option<Arc<ClientConfig>>,
+    /// How long we wait for any one reply.
+    pub timeout: Duration,
+}
+
+impl Config {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            security: Security::default(),
+            credentials: None,
+            helo_name: "mail.vortex.skyfall.dev".to_string(),
+            tls: None,
+            timeout: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// A recipient the server wouldn't take, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub recipient: String,
+    pub reply: Reply,
+}
+
+/// What happened to a message the server accepted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Delivery {
+    /// Recipients the server rejected. The message went to everyone else.
+    pub rejected: Vec<Rejection>,
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// A connection to an SMTP server that's ready to send mail.
+///
+/// If a method returns an error other than [`Error::UnexpectedReply`] or
+/// [`Error::AllRecipientsRejected`], the connection is in an unknown state and
+/// should be dropped.
+pub struct Client {
+    stream: BufReader<Box<dyn Stream>>,
+    /// The EHLO keywords the server sent, with their parameters.
+    extensions: Vec<String>,
+    timeout: Duration,
+}
+
+impl Client {
+    /// Connects to the server, says EHLO, and secures and authenticates the
+    /// connection as `config` says.
+    pub async fn connect(config: &Config) -> Result<Self, Error> {
+        let socket = with_timeout(
+            config.timeout,
+            TcpStream::connect((config.host.as_str(), config.port)),
+        )
+        .await??;
+        socket.set_nodelay(true)?;
+        tracing::debug!("connected to {}:{}", config.host, config.port);
+
+        let stream: Box<dyn Stream> = match config.security {
+            Security::ImplicitTls => Box::new(start_tls(config, socket).await?),
+            Security::StartTls | Security::Plain => Box::new(socket),
+        };
+        let mut client = Self {
+            stream: BufReader::new(stream),
+            extensions: Vec::new(),
+            timeout: config.timeout,
+        };
+
+        client.expect("greeting", 220).await?;
+        client.ehlo(&config.helo_name).await?;
+
+        if config.security == Security::StartTls {
+            if !client.supports("STARTTLS") {
+                return Err(Error::StartTlsUnsupported);
+            }
+            client.command("STARTTLS", "STARTTLS", 220).await?;
+            // Anything the server sent after its reply was sent in plain text, and
+            // could have been injected by someone in the middle.
+            if !client.stream.buffer().is_empty() {
+                return Err(Error::MalformedReply(
+                    "data after the STARTTLS reply".to_string(),
+                ));
+            }
+
+            let socket = client.stream.into_inner();
+            client.stream = BufReader::new(Box::new(start_tls(config, socket).await?));
+            // RFC 3207 section 4.2: forget everything we learnt before the handshake.
+            client.ehlo(&config.helo_name).await?;
+        }
+
+        if let Some(credentials) = &config.credentials {
+            client.authenticate(credentials).await?;
+        }
+
+        Ok(client)
+    }
+
+    /// Whether the server advertised `extension` in its EHLO reply.
+    pub fn supports(&self, extension: &str) -> bool {
+        self.extension(extension).is_some()
+    }
+
+    /// The parameters of `extension`, if the server advertised it.
+    fn extension(&self, extension: &str) -> Option<&str> {
+        self.extensions.iter().find_map(|line| {
+            let (keyword, parameters) = line.split_once(' ').unwrap_or((line, ""));
+            keyword
+                .eq_ignore_ascii_case(extension)
+                .then_some(parameters)
+        })
+    }
+
+    async fn ehlo(&mut self, helo_name: &str) -> Result<(), Error> {
+        let reply = self
+            .command("EHLO", &format!("EHLO {helo_name}"), 250)
+            .await?;
+        // The first line is the server's name and greeting.
+        self.extensions = reply.lines().iter().skip(1).cloned().collect();
+        tracing::trace!("server extensions: {:?}", self.extensions);
+        Ok(())
+    }
+
+    async fn authenticate(&mut self, credentials: &Credentials) -> Result<(), Error> {
+        let mechanisms: Vec<String> = self
+            .extension("AUTH")
+            .ok_or(Error::AuthUnsupported)?
+            .split_whitespace()
+            .map(str::to_uppercase)
+            .collect();
+
+        if mechanisms.iter().any(|m| m == "PLAIN") {
+            let response = BASE64.encode(format!(
+                "\0{}\0{}",
+                credentials.username, credentials.password
+            ));
+            self.command("AUTH", &format!("AUTH PLAIN {response}"), 235)
+                .await?;
+        } else if mechanisms.iter().any(|m| m == "LOGIN") {
+            self.command("AUTH", "AUTH LOGIN", 334).await?;
+            self.command("AUTH", &BASE64.encode(&credentials.username), 334)
+                .await?;
+            self.command("AUTH", &BASE64.encode(&credentials.password), 235)
+                .await?;
+        } else {
+            return Err(Error::AuthUnsupported);
+        }
+
+        tracing::debug!("authenticated as {}", credentials.username);
+        Ok(())
+    }
+
+    /// Sends `message` from `from` to every address in `to`.
+    ///
+    /// `message` is the whole message, headers included; bare LFs in it are turned
+    /// into CRLFs. Succeeds as long as the server accepted at least one recipient.
+    pub async fn send<S>(&mut self, from: &str, to: &[S], message: &[u8]) -> Result<Delivery, Error>
+    where
+        S: AsRef<str>,
+    {
+        let chunking = self.supports("CHUNKING");
+        let smtputf8 = self.supports("SMTPUTF8")
+            && (!from.is_ascii() || to.iter().any(|rcpt| !rcpt.as_ref().is_ascii()));
+
+        let mut commands = vec![format!(
+            "MAIL FROM:<{from}>{}",
+            if smtputf8 { " SMTPUTF8" } else { "" }
+        )];
+        commands.extend(to.iter().map(|rcpt| format!("RCPT TO:<{}>", rcpt.as_ref())));
+        if !chunking {
+            commands.push("DATA".to_string());
+        }
+
+        let mut replies = self.transaction(&commands).await?.into_iter();
+        let mail_reply = replies.next().expect("one reply per command");
+        let rejected: Vec<Rejection> = to
+            .iter()
+            .zip(replies.by_ref())
+            .filter(|(_, reply)| !is_success(reply))
+            .map(|(rcpt, reply)| Rejection {
+                recipient: rcpt.as_ref().to_string(),
+                reply,
+            })
+            .collect();
+        let data_reply = replies.next();
+
+        let data_accepted = data_reply.as_ref().is_some_and(|reply| reply.code() == 354);
+
+        if !is_success(&mail_reply) {
+            self.abort(data_accepted).await?;
+            return Err(Error::UnexpectedReply {
+                command: "MAIL FROM",
+                reply: mail_reply,
+            });
+        }
+        if rejected.len() == to.len() {
+            self.abort(data_accepted).await?;
+            return Err(Error::AllRecipientsRejected(rejected));
+        }
+
+        let message = normalise_line_endings(message);
+        if chunking {
+            // RFC 3030: the whole message in one chunk, no dot-stuffing needed.
+            let mut chunk = format!("BDAT {} LAST\r\n", message.len()).into_bytes();
+            chunk.extend_from_slice(&message);
+            self.stream.write_all(&chunk).await?;
+            self.stream.flush().await?;
+            self.expect("BDAT", 250).await?;
+        } else {
+            let data_reply = data_reply.expect("DATA was sent");
+            if !data_accepted {
+                self.reset().await?;
+                return Err(Error::UnexpectedReply {
+                    command: "DATA",
+                    reply: data_reply,
+                });
+            }
+
+            let mut data = dot_stuff(&message);
+            data.extend_from_slice(b".\r\n");
+            self.stream.write_all(&data).await?;
+            self.stream.flush().await?;
+            self.expect("DATA", 250).await?;
+        }
+
+        tracing::debug!(
+            "message accepted for {} of {} recipients",
+            to.len() - rejected.len(),
+            to.len()
+        );
+        Ok(Delivery { rejected })
+    }
+
+    /// Says goodbye and closes the connection.
+    pub async fn quit(mut self) -> Result<(), Error> {
+        self.command("QUIT", "QUIT", 221).await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Sends every command in `commands` and returns their replies, in one go if the
+    /// server supports PIPELINING (RFC 2920).
+    async fn transaction(&mut self, commands: &[String]) -> Result<Vec<Reply>, Error> {
+        let mut replies = Vec::with_capacity(commands.len());
+        if self.supports("PIPELINING") {
+            let batch: String = commands.iter().map(|c| format!("{c}\r\n")).collect();
+            tracing::trace!("sending pipelined {batch:?}");
+            self.stream.write_all(batch.as_bytes()).await?;
+            self.stream.flush().await?;
+            for _ in commands {
+                replies.push(self.read_reply().await?);
+            }
+        } else {
+            for command in commands {
+                self.write_line(command).await?;
+                replies.push(self.read_reply().await?);
+            }
+        }
+        Ok(replies)
+    }
+
+    /// Gives up on a transaction so the connection can be used for the next one.
+    ///
+    /// A server that says 354 to our pipelined DATA after rejecting everything before
+    /// it is broken, but it's now waiting for a message. A lone dot ends that
+    /// (RFC 2920 section 3.1), and whatever it says to it doesn't matter.
+    async fn abort(&mut self, data_accepted: bool) -> Result<(), Error> {
+        if data_accepted {
+            self.write_line(".").await?;
+            self.read_reply().await?;
+        }
+        self.reset().await
+    }
+
+    async fn reset(&mut self) -> Result<(), Error> {
+        self.command("RSET", "RSET", 250).await.map(|_| ())
+    }
+
+    /// Sends `line` and expects a `code` reply.
+    async fn command(&mut self, name: &'static str, line: &str, code: u16) -> Result<Reply, Error> {
+        self.write_line(line).await?;
+        self.expect(name, code).await
+    }
+
+    async fn write_line(&mut self, line: &str) -> Result<(), Error> {
+        // Don't log anyone's password.
+        if !line.starts_with("AUTH") {
+            tracing::trace!("sending {line:?}");
+        }
+        self.stream
+            .write_all(format!("{line}\r\n").as_bytes())
+            .await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn expect(&mut self, name: &'static str, code: u16) -> Result<Reply, Error> {
+        let reply = self.read_reply().await?;
+        if reply.code() == code {
+            Ok(reply)
+        } else {
+            Err(Error::UnexpectedReply {
+                command: name,
+                reply,
+            })
+        }
+    }
+
+    async fn read_reply(&mut self) -> Result<Reply, Error> {
+        let reply = with_timeout(self.timeout, reply::read_reply(&mut self.stream)).await??;
+        tracing::trace!("received {reply:?}");
+        Ok(reply)
+    }
+}
+
+async fn with_timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Error> {
+    timeout(duration, future).await.map_err(|_| Error::Timeout)
+}
+
+async fn start_tls<S>(
+    config: &Config,
+    socket: S,
+) -> Result<tokio_rustls::client::TlsStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let server_name = ServerName::try_from(config.host.clone())
+        .map_err(|_| Error::InvalidServerName(config.host.clone()))?;
+    let tls = config.tls.clone().unwrap_or_else(default_tls_config);
+
+    let socket = with_timeout(
+        config.timeout,
+        TlsConnector::from(tls).connect(server_name, socket),
+    )
+    .await??;
+    tracing::debug!("TLS established");
+    Ok(socket)
+}
+
+fn default_tls_config() -> Arc<ClientConfig> {
+    let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    )
+}
+
+const fn is_success(reply: &Reply) -> bool {
+    reply.code() >= 200 && reply.code() < 300
+}
+
+/// Turns bare LFs into CRLFs, and makes sure the message ends with a line break.
+fn normalise_line_endings(message: &[u8]) -> Vec<u8> {
+    let mut normalised = Vec::with_capacity(message.len() + 2);
+    let mut previous = None;
+    for &byte in message {
+        if byte == b'\n' && previous != Some(b'\r') {
+            normalised.push(b'\r');
+        }
+        normalised.push(byte);
+        previous = Some(byte);
+    }
+    if !normalised.ends_with(b"\r\n") {
+        normalised.extend_from_slice(b"\r\n");
+    }
+    normalised
+}
+
+/// Doubles the dot at the start of every line, so none of them can end DATA early
+/// (RFC 5321 section 4.5.2). `message` must already end with a CRLF.
+fn dot_stuff(message: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(message.len());
+    let mut line_start = true;
+    for &byte in message {
+        if line_start && byte == b'.' {
+            stuffed.push(b'.');
+        }
+        stuffed.push(byte);
+        line_start = byte == b'\n';
+    }
+    stuffed
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio_rustls::TlsAcceptor;
+    use vortex_smtp::tls::ServerConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_line_endings_and_dot_stuffing() {
+        let message = normalise_line_endings(b"Subject: hi\n\n.hidden\r\n..two\nend");
+        assert_eq!(message, b"Subject: hi\r\n\r\n.hidden\r\n..two\r\nend\r\n");
+        assert_eq!(
+            dot_stuff(&message),
+            b"Subject: hi\r\n\r\n..hidden\r\n...two\r\nend\r\n"
+        );
+    }
+
+    /// A self-signed certificate for `localhost`, and a client config that trusts it.
+    fn localhost_tls() -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = CertificateDer::from(certified.cert.der().to_vec());
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+            certified.signing_key.serialize_der(),
+        ));
+
+        let server_tls = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_tls = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (Arc::new(server_tls), Arc::new(client_tls))
+    }
+
+    #[tokio::test]
+    async fn test_send_to_vortex() {
+        let (server_tls, client_tls) = localhost_tls();
+        let mut sink = vortex_smtp::testing::spawn_with_config(vortex_smtp::Config {
+            auth: vortex_smtp::auth::AuthMode::Required,
+            starttls: Some(server_tls),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let config = Config {
+            security: Security::StartTls,
+            credentials: Some(Credentials {
+                username: "user".to_string(),
+                password: "hunter2".to_string(),
+            }),
+            tls: Some(client_tls),
+            ..Config::new("localhost", sink.addr().port())
+        };
+        let mut client = Client::connect(&config).await.unwrap();
+        let delivery = client
+            .send(
+                "sender@example.com",
+                &["someone@example.org"],
+                b"Subject: hello\n\nhi there\n.signed, a dot\n",
+            )
+            .await
+            .unwrap();
+        assert!(delivery.rejected.is_empty());
+        client.quit().await.unwrap();
+
+        let email = sink.recv().await.unwrap();
+        assert_eq!(email.mail_from, "sender@example.com");
+        assert_eq!(email.rcpt_to, ["someone@example.org"]);
+        assert_eq!(email.authenticated_as.as_deref(), Some("user"));
+        // Stuffed on the way out and unstuffed on the way in, so it arrives as sent.
+        assert_eq!(
+            email.data,
+            "Subject: hello\r\n\r\nhi there\r\n.signed, a dot"
+        );
+    }
+
+    /// Reads a line from the client and checks it's `expected`.
+    async fn expect_line<S: AsyncBufRead + Unpin>(socket: &mut S, expected: &str) {
+        let mut line = String::new();
+        socket.read_line(&mut line).await.unwrap();
+        assert_eq!(line, format!("{expected}\r\n"));
+    }
+
+    /// A stand-in smarthost with PIPELINING, CHUNKING and only AUTH LOGIN, none of which
+    /// vortex-smtp does, following a fixed script.
+    async fn smarthost(listener: TcpListener, tls: Arc<ServerConfig>) {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut socket = BufReader::new(socket);
+
+        socket.write_all(b"220 smarthost ESMTP\r\n").await.unwrap();
+        expect_line(&mut socket, "EHLO client.example").await;
+        socket
+            .write_all(b"250-smarthost\r\n250-AUTH LOGIN\r\n250 STARTTLS\r\n")
+            .await
+            .unwrap();
+        expect_line(&mut socket, "STARTTLS").await;
+        socket.write_all(b"220 Go ahead\r\n").await.unwrap();
+
+        let socket = TlsAcceptor::from(tls)
+            .accept(socket.into_inner())
+            .await
+            .unwrap();
+        let mut socket = BufReader::new(socket);
+        expect_line(&mut socket, "EHLO client.example").await;
+        socket
+            .write_all(b"250-smarthost\r\n250-PIPELINING\r\n250-CHUNKING\r\n250 AUTH LOGIN\r\n")
+            .await
+            .unwrap();
+
+        expect_line(&mut socket, "AUTH LOGIN").await;
+        socket.write_all(b"334 VXNlcm5hbWU6\r\n").await.unwrap();
+        expect_line(&mut socket, &BASE64.encode("user")).await;
+        socket.write_all(b"334 UGFzc3dvcmQ6\r\n").await.unwrap();
+        expect_line(&mut socket, &BASE64.encode("hunter2")).await;
+        socket.write_all(b"235 OK\r\n").await.unwrap();
+
+        // Pipelined: we don't reply until we've got the whole batch.
+        expect_line(&mut socket, "MAIL FROM:<sender@example.com>").await;
+        expect_line(&mut socket, "RCPT TO:<good@example.org>").await;
+        expect_line(&mut socket, "RCPT TO:<bad@example.org>").await;
+        socket
+            .write_all(b"250 OK\r\n250 OK\r\n550 No such user\r\n")
+            .await
+            .unwrap();
+
+        let message = b"Subject: hi\r\n\r\n.not stuffed\r\n";
+        expect_line(&mut socket, &format!("BDAT {} LAST", message.len())).await;
+        let mut chunk = vec![0; message.len()];
+        socket.read_exact(&mut chunk).await.unwrap();
+        assert_eq!(chunk, message);
+        socket.write_all(b"250 Queued\r\n").await.unwrap();
+
+        expect_line(&mut socket, "QUIT").await;
+        socket.write_all(b"221 Bye\r\n").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_to_smarthost() {
+        let (server_tls, client_tls) = localhost_tls();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(smarthost(listener, server_tls));
+
+        let config = Config {
+            credentials: Some(Credentials {
+                username: "user".to_string(),
+                password: "hunter2".to_string(),
+            }),
+            helo_name: "client.example".to_string(),
+            tls: Some(client_tls),
+            ..Config::new("localhost", port)
+        };
+        let mut client = Client::connect(&config).await.unwrap();
+        let delivery = client
+            .send(
+                "sender@example.com",
+                &["good@example.org", "bad@example.org"],
+                b"Subject: hi\n\n.not stuffed",
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            delivery.rejected,
+            [Rejection {
+                recipient: "bad@example.org".to_string(),
+                reply: Reply::new(550, "No such user"),
+            }]
+        );
+        client.quit().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_data_accepted_without_recipients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = BufReader::new(socket);
+            socket.write_all(b"220 broken ESMTP\r\n").await.unwrap();
+            expect_line(&mut socket, "EHLO client.example").await;
+            socket
+                .write_all(b"250-broken\r\n250 PIPELINING\r\n")
+                .await
+                .unwrap();
+
+            expect_line(&mut socket, "MAIL FROM:<sender@example.com>").await;
+            expect_line(&mut socket, "RCPT TO:<bad@example.org>").await;
+            expect_line(&mut socket, "DATA").await;
+            socket
+                .write_all(b"250 OK\r\n550 No such user\r\n354 Go ahead\r\n")
+                .await
+                .unwrap();
+            // The client ends the message straight away, rather than hanging up.
+            expect_line(&mut socket, ".").await;
+            socket
+                .write_all(b"554 No valid recipients\r\n")
+                .await
+                .unwrap();
+            expect_line(&mut socket, "RSET").await;
+            socket.write_all(b"250 OK\r\n").await.unwrap();
+
+            expect_line(&mut socket, "QUIT").await;
+            socket.write_all(b"221 Bye\r\n").await.unwrap();
+        });
+
+        let config = Config {
+            security: Security::Plain,
+            helo_name: "client.example".to_string(),
+            ..Config::new("localhost", port)
+        };
+        let mut client = Client::connect(&config).await.unwrap();
+        let error = client
+            .send("sender@example.com", &["bad@example.org"], b"Subject: hi\n")
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::AllRecipientsRejected(rejected) if rejected.len() == 1));
+        client.quit().await.unwrap();
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+use vortex_smtp::messages::Reply;
+
+use crate::Error;
+
+/// The longest reply line we'll put up with. RFC 5321 section 4.5.3.1.5 says 512
+/// octets, but some servers send longer ones.
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// Reads one (possibly multi-line) reply from the server.
+pub async fn read_reply<R>(reader: &mut R) -> Result<Reply, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut reply: Option<Reply> = None;
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        let n = (&mut *reader)
+            .take(MAX_LINE_LENGTH as u64)
+            .read_line(&mut line)
+            .await?;
+        if n == 0 {
+            return Err(Error::ConnectionClosed);
+        }
+
+        let (code, last, text) = parse_line(&line)?;
+        reply = Some(match reply {
+            None => Reply::new(code, text),
+            Some(reply) if reply.code() == code => reply.line(text),
+            Some(_) => return Err(Error::MalformedReply(line)),
+        });
+
+        if last {
+            return Ok(reply.expect("just set"));
+        }
+    }
+}
+
+/// Splits a reply line into its code, whether it's the last line, and its text.
+fn parse_line(line: &str) -> Result<(u16, bool, &str), Error> {
+    let malformed = || Error::MalformedReply(line.to_string());
+
+    let line = line.strip_suffix('\n').ok_or_else(malformed)?;
+    let line = line.strip_suffix('\r').unwrap_or(line);
+
+    let code = line
+        .get(..3)
+        .filter(|code| code.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(malformed)?;
+    let last = match line.as_bytes().get(3) {
+        None | Some(b' ') => true,
+        Some(b'-') => false,
+        Some(_) => return Err(malformed()),
+    };
+
+    Ok((code, last, line.get(4..).unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_multi_line_reply() {
+        let mut input: &[u8] =
+            b"250-mail.example.org\r\n250-PIPELINING\r\n250 CHUNKING\r\n220 next";
+        let reply = read_reply(&mut input).await.unwrap();
+        assert_eq!(reply.code(), 250);
+        assert_eq!(
+            reply.lines(),
+            ["mail.example.org", "PIPELINING", "CHUNKING"]
+        );
+        assert_eq!(input, b"220 next");
+    }
+
+    #[tokio::test]
+    async fn test_read_malformed_reply() {
+        for input in [
+            &b"hello\r\n"[..],
+            b"250-a\r\n251 b\r\n",
+            b"250_x\r\n",
+            b"250-unfinished",
+        ] {
+            let mut input = input;
+            assert!(read_reply(&mut input).await.is_err(), "{input:?}");
+        }
+
+        let mut input: &[u8] = b"250\r\n";
+        assert_eq!(read_reply(&mut input).await.unwrap(), Reply::new(250, ""));
+    }
+}
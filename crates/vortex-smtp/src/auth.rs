@@ -0,0 +1,87 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+/// Whether clients have to authenticate (RFC 4954) before they can send mail.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AuthMode {
+    /// AUTH isn't advertised or accepted. This is what you want on port 25.
+    #[default]
+    Disabled,
+    /// AUTH is advertised, and MAIL FROM is refused until the client has authenticated.
+    /// This is what you want on a submission port (587).
+    Required,
+}
+
+/// Where we are in a multi-step AUTH exchange.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthStep {
+    /// `AUTH PLAIN` was sent without an initial response, so we're waiting for it.
+    PlainResponse,
+    /// `AUTH LOGIN`, waiting for the username.
+    LoginUsername,
+    /// `AUTH LOGIN`, waiting for the password.
+    LoginPassword { username: String },
+}
+
+/// The `334` challenges for `AUTH LOGIN` are just base64-encoded prompts.
+pub const USERNAME_CHALLENGE: &str = "VXNlcm5hbWU6"; // "Username:"
+pub const PASSWORD_CHALLENGE: &str = "UGFzc3dvcmQ6"; // "Password:"
+
+pub fn decode_base64(response: &str) -> Option<String> {
+    let bytes = STANDARD.decode(response.trim()).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Decodes a SASL PLAIN response (RFC 4616) into a username and password.
+///
+/// The authorization identity has to be empty or match the username, as we
+/// don't support acting on behalf of other users.
+pub fn decode_plain(response: &str) -> Option<(String, String)> {
+    let decoded = decode_base64(response)?;
+    let mut parts = decoded.split('\0');
+    let (authzid, authcid, passwd) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() || authcid.is_empty() {
+        return None;
+    }
+    if !authzid.is_empty() && authzid != authcid {
+        return None;
+    }
+
+    Some((authcid.to_string(), passwd.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_plain() {
+        // "\0alice\0hunter2"
+        assert_eq!(
+            decode_plain("AGFsaWNlAGh1bnRlcjI="),
+            Some(("alice".to_string(), "hunter2".to_string()))
+        );
+        // "alice\0alice\0hunter2"
+        assert_eq!(
+            decode_plain("YWxpY2UAYWxpY2UAaHVudGVyMg=="),
+            Some(("alice".to_string(), "hunter2".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_decode_plain_rejects_other_authzid() {
+        // "bob\0alice\0hunter2"
+        assert_eq!(decode_plain("Ym9iAGFsaWNlAGh1bnRlcjI="), None);
+    }
+
+    #[test]
+    fn test_decode_plain_rejects_garbage() {
+        assert_eq!(decode_plain("not base64!"), None);
+        // "alice"
+        assert_eq!(decode_plain("YWxpY2U="), None);
+    }
+
+    #[test]
+    fn test_decode_login() {
+        assert_eq!(decode_base64("YWxpY2U="), Some("alice".to_string()));
+    }
+}
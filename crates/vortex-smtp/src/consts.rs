@@ -1 +1,5 @@
+/// What we call ourselves.
+pub const HOSTNAME: &str = "mail.vortex.skyfall.dev";
 pub const MAX_SIZE: usize = 15_728_640; // ~15 MB
+/// How much we read from the socket at once. Big messages just take more reads.
+pub const READ_BUFFER_SIZE: usize = 64 * 1024;
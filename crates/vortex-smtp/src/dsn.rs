@@ -0,0 +1,153 @@
+//! Delivery Status Notification parameters (RFC 3461).
+//!
+//! We never send DSNs ourselves, but we keep what the client asked for on the
+//! [`Email`](crate::Email) so tests can check their apps ask for the right thing.
+
+use serde::{Deserialize, Serialize};
+
+/// How much of the message a DSN should include (`RET=`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ret {
+    Full,
+    Hdrs,
+}
+
+/// When a DSN should be sent for a recipient (`NOTIFY=`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Notify {
+    Never,
+    Success,
+    Failure,
+    Delay,
+}
+
+/// The DSN parameters of a whole mail transaction.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Dsn {
+    pub ret: Option<Ret>,
+    /// The envelope ID (`ENVID=`), xtext-decoded.
+    pub envid: Option<String>,
+    /// One entry per recipient, in the same order as `rcpt_to`.
+    pub recipients: Vec<RecipientDsn>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecipientDsn {
+    pub notify: Option<Vec<Notify>>,
+    /// The original recipient (`ORCPT=`), like `rfc822;alice@example.com`,
+    /// xtext-decoded.
+    pub orcpt: Option<String>,
+}
+
+/// Reads `RET=` and `ENVID=` out of MAIL FROM's parameters. Other parameters are
+/// ignored.
+pub fn parse_mail_parameters(parameters: &[&str]) -> Option<(Option<Ret>, Option<String>)> {
+    let mut ret = None;
+    let mut envid = None;
+    for (keyword, value) in parameters.iter().filter_map(|p| p.split_once('=')) {
+        if keyword.eq_ignore_ascii_case("RET") {
+            if ret.is_some() {
+                return None;
+            }
+            ret = Some(match value.to_uppercase().as_str() {
+                "FULL" => Ret::Full,
+                "HDRS" => Ret::Hdrs,
+                _ => return None,
+            });
+        } else if keyword.eq_ignore_ascii_case("ENVID") {
+            if envid.is_some() || value.len() > 100 {
+                return None;
+            }
+            envid = Some(decode_xtext(value)?);
+        }
+    }
+    Some((ret, envid))
+}
+
+/// Reads `NOTIFY=` and `ORCPT=` out of RCPT TO's parameters. Other parameters are
+/// ignored.
+pub fn parse_rcpt_parameters(parameters: &[&str]) -> Option<RecipientDsn> {
+    let mut dsn = RecipientDsn::default();
+    for (keyword, value) in parameters.iter().filter_map(|p| p.split_once('=')) {
+        if keyword.eq_ignore_ascii_case("NOTIFY") {
+            if dsn.notify.is_some() {
+                return None;
+            }
+            let notify = value
+                .split(',')
+                .map(|value| match value.to_uppercase().as_str() {
+                    "NEVER" => Some(Notify::Never),
+                    "SUCCESS" => Some(Notify::Success),
+                    "FAILURE" => Some(Notify::Failure),
+                    "DELAY" => Some(Notify::Delay),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()?;
+            // NEVER can't be combined with anything else.
+            if notify.contains(&Notify::Never) && notify.len() > 1 {
+                return None;
+            }
+            dsn.notify = Some(notify);
+        } else if keyword.eq_ignore_ascii_case("ORCPT") {
+            let (address_type, _) = value.split_once(';')?;
+            if dsn.orcpt.is_some() || address_type.is_empty() {
+                return None;
+            }
+            dsn.orcpt = Some(decode_xtext(value)?);
+        }
+    }
+    Some(dsn)
+}
+
+/// Decodes xtext (RFC 3461 section 4), where `+XX` is the byte with hex value XX.
+fn decode_xtext(xtext: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(xtext.len());
+    let mut bytes = xtext.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'+' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mail_parameters() {
+        assert_eq!(
+            parse_mail_parameters(&["SIZE=100", "RET=hdrs", "ENVID=QQ314159+2Bx"]),
+            Some((Some(Ret::Hdrs), Some("QQ314159+x".to_string())))
+        );
+        assert_eq!(
+            parse_mail_parameters(&["BODY=8BITMIME"]),
+            Some((None, None))
+        );
+        assert_eq!(parse_mail_parameters(&["RET=SOME"]), None);
+        assert_eq!(parse_mail_parameters(&["RET=FULL", "RET=HDRS"]), None);
+        assert_eq!(parse_mail_parameters(&["ENVID=bad+G0"]), None);
+    }
+
+    #[test]
+    fn test_rcpt_parameters() {
+        assert_eq!(
+            parse_rcpt_parameters(&["NOTIFY=SUCCESS,failure", "ORCPT=rfc822;Alice+40example.com"]),
+            Some(RecipientDsn {
+                notify: Some(vec![Notify::Success, Notify::Failure]),
+                orcpt: Some("rfc822;Alice@example.com".to_string()),
+            })
+        );
+        assert_eq!(parse_rcpt_parameters(&[]), Some(RecipientDsn::default()));
+        assert_eq!(parse_rcpt_parameters(&["NOTIFY=NEVER,DELAY"]), None);
+        assert_eq!(parse_rcpt_parameters(&["NOTIFY=SOMETIMES"]), None);
+        assert_eq!(parse_rcpt_parameters(&["ORCPT=alice@example.com"]), None);
+    }
+}
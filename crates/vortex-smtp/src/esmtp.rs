@@ -1,6 +1,25 @@
 use const_format::concatcp;
 
-pub const SUPPORTED_EXTENSIONS: &[&str; 1] = &[
-    // "HELP",
+pub const SUPPORTED_EXTENSIONS: &[&str; 4] = &[
+    "DSN",
+    "HELP",
     concatcp!("SIZE ", crate::consts::MAX_SIZE),
+    "SMTPUTF8",
 ];
+
+/// Only advertised on plaintext connections, when the listener has a certificate.
+pub const STARTTLS: &str = "STARTTLS";
+
+/// Only advertised when AUTH is enabled on the listener, and the connection is
+/// encrypted.
+pub const AUTH: &str = "AUTH PLAIN LOGIN";
+
+/// Reads `SIZE=` (RFC 1870) out of MAIL FROM's parameters, if the client sent a
+/// valid one.
+pub fn declared_size(parameters: &[&str]) -> Option<usize> {
+    parameters
+        .iter()
+        .filter_map(|p| p.split_once('='))
+        .find(|(keyword, _)| keyword.eq_ignore_ascii_case("SIZE"))
+        .and_then(|(_, value)| value.parse().ok())
+}
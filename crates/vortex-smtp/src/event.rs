@@ -3,4 +3,20 @@ pub enum Event {
     EmailReceived(crate::Email),
 }
 
-pub type EventHandler = fn(Event);
+/// What the event handler managed to do with an [`Event::EmailReceived`].
+///
+/// The client is only told the message was delivered once the handler is done with
+/// it, so it can try again later if we lost it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Delivery {
+    /// The recipients the message couldn't be delivered to. With LMTP, each of them
+    /// gets a `451`; with SMTP the client only gets one if nobody got the message.
+    pub failed: Vec<String>,
+}
+
+/// For handlers that can't fail.
+impl From<()> for Delivery {
+    fn from((): ()) -> Self {
+        Self::default()
+    }
+}
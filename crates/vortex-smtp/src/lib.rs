@@ -1,19 +1,39 @@
 use std::fmt::Display;
 use std::future::Future;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::Arc;
 
 use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
-use tokio::time::{timeout, Duration};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::time::{timeout, Duration, Instant};
+use tokio_rustls::{rustls::ServerConfig, server::TlsStream, TlsAcceptor};
+use tracing::Instrument;
 
+pub mod auth;
 mod consts;
+pub mod dsn;
 mod esmtp;
 pub mod event;
-mod messages;
+pub mod messages;
+pub mod milter;
+mod proxy;
+pub mod tarpit;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod tls;
 
-use event::Event;
+use auth::{AuthMode, AuthStep};
+use dsn::Dsn;
+use event::{Delivery, Event};
 use messages::Command;
+use milter::{Filters, Milter, Verdict};
+use tarpit::Tarpit;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -28,47 +48,278 @@ pub enum Error {
 
     #[error("data missing")]
     DataMissing,
+
+    #[error("TLS configuration error: {0}")]
+    TlsConfig(String),
+
+    #[error("invalid PROXY protocol header: {0}")]
+    InvalidProxyHeader(&'static str),
+
+    #[error("client timed out")]
+    Timeout,
+}
+
+/// Per-listener settings.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub auth: AuthMode,
+    /// If set, every accepted connection is wrapped in TLS before the greeting is sent
+    /// (implicit TLS, RFC 8314), which is what clients on port 465 expect.
+    pub implicit_tls: Option<Arc<ServerConfig>>,
+    /// If set, plaintext clients can upgrade to TLS with STARTTLS (RFC 3207), which is
+    /// what clients on ports 25 and 587 expect.
+    pub starttls: Option<Arc<ServerConfig>>,
+    /// If set, every connection has to start with a HAProxy PROXY protocol (v1 or v2)
+    /// header, which is where we take the client's address from. Connections without
+    /// one are dropped. Only turn this on behind a load balancer that sends it!
+    pub proxy_protocol: bool,
+    pub protocol: Protocol,
+    pub verify: VerifyPolicy,
+    pub timeouts: Timeouts,
+    /// If set, clients that keep sending unknown recipients or unrecognised commands
+    /// get slower and slower replies, and are eventually disconnected.
+    pub tarpit: Option<Tarpit>,
+    /// Milters to run every connection past, in order.
+    pub milters: Vec<Milter>,
+}
+
+impl Config {
+    /// The commands a client can use on this listener, for HELP.
+    fn commands(&self, tls: bool) -> Vec<&'static str> {
+        let mut commands = match self.protocol {
+            Protocol::Smtp => vec!["HELO", "EHLO"],
+            Protocol::Lmtp => vec!["LHLO"],
+        };
+        if self.offers_starttls(tls) {
+            commands.push("STARTTLS");
+        }
+        if self.offers_auth(tls) {
+            commands.push("AUTH");
+        }
+        commands.extend([
+            "MAIL", "RCPT", "DATA", "RSET", "VRFY", "EXPN", "NOOP", "HELP", "QUIT",
+        ]);
+        commands
+    }
+
+    fn offers_starttls(&self, tls: bool) -> bool {
+        !tls && self.starttls.is_some()
+    }
+
+    /// We never take passwords in plaintext, so AUTH waits until the connection is
+    /// encrypted.
+    fn offers_auth(&self, tls: bool) -> bool {
+        tls && self.auth != AuthMode::Disabled
+    }
+}
+
+/// How a call to [`process`] ended.
+enum Outcome {
+    Closed,
+    /// The client asked for STARTTLS and has been told to go ahead.
+    StartTls,
+}
+
+/// How long we wait for the client in each phase of the session before giving up
+/// with a `421`. The defaults are the ones RFC 5321 section 4.5.3.2 recommends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// From accepting the connection (PROXY header and TLS handshake included)
+    /// until the client's first command.
+    pub greeting: Duration,
+    /// Between commands. Also how long the client has to read each of our replies.
+    pub command: Duration,
+    /// Between two reads while the client is sending DATA.
+    pub data_block: Duration,
+    /// From the `354` until the terminating `.`, however quickly the blocks come in.
+    pub data_terminator: Duration,
 }
 
-#[derive(Debug, Clone)]
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            greeting: Duration::from_secs(5 * 60),
+            command: Duration::from_secs(5 * 60),
+            data_block: Duration::from_secs(3 * 60),
+            data_terminator: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// How we answer VRFY and EXPN.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerifyPolicy {
+    /// Don't say whether mailboxes exist: VRFY gets a `252` and EXPN a `502`,
+    /// as RFC 5321 section 3.5.3 suggests.
+    #[default]
+    CannotVerify,
+    /// Look the mailbox up with the same callback that validates RCPT TO.
+    Check,
+}
+
+/// What to do with a recipient, as decided by the callback that validates RCPT TO.
+/// That callback can also just return a `bool`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecipientVerdict {
+    Accept,
+    /// Accept the recipient, but only for messages up to this many bytes, like when
+    /// their mailbox is nearly full.
+    AcceptUpTo(usize),
+    /// There's no such mailbox.
+    Unknown,
+    /// The mailbox exists, but can't take any more mail right now.
+    MailboxFull,
+}
+
+impl From<bool> for RecipientVerdict {
+    fn from(valid: bool) -> Self {
+        if valid {
+            Self::Accept
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Smtp,
+    /// LMTP (RFC 2033): LHLO instead of HELO/EHLO, and one reply per recipient
+    /// after DATA. Meant for an MTA handing over mail, usually on a Unix socket.
+    Lmtp,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct State {
+    /// The address of the connecting client, if it was known.
+    remote_addr: Option<SocketAddr>,
+    /// Whether the connection is encrypted, either from the start or after STARTTLS.
+    tls: bool,
+
     esmtp: bool,
     greeting_done: bool,
-    finished: bool,
+
+    helo: Option<String>,
+    auth_step: Option<AuthStep>,
+    authenticated_as: Option<String>,
 
     mail_from: Option<String>,
     rcpt_to: Vec<String>,
+    /// The largest message each recipient can take, in the same order as `rcpt_to`.
+    size_limits: Vec<Option<usize>>,
+    /// How big the client said the message would be, with MAIL FROM's `SIZE=`.
+    declared_size: Option<usize>,
+    dsn: Dsn,
     waiting_for_data: bool,
+    /// When the client has to be done sending DATA by.
+    data_deadline: Option<Instant>,
     data: Vec<u8>, // We can't use a &[u8], as that could cause a stack overflow
+
+    /// Unknown recipients, unrecognised commands and failed AUTHs so far, for the
+    /// tarpit. If we know the client's address, this includes its other connections.
+    errors: u32,
+
+    /// A milter said to throw away everything this client sends.
+    discard_connection: bool,
+    /// A milter said to throw away the current message.
+    discard_message: bool,
+    /// The message being sent is over [`consts::MAX_SIZE`], so it'll be refused once
+    /// the client is done sending it.
+    too_large: bool,
 }
 
-async fn process<T, F>(mut socket: TcpStream, is_email_valid: T) -> Result<State, Error>
-where
-    T: Fn(&str) -> F + Send,
-    F: Future<Output = bool> + Send,
-{
-    let mut buf = vec![0; consts::MAX_SIZE];
-    tracing::debug!("processing connection");
+impl State {
+    /// RFC 3207 section 4.2: after STARTTLS, we forget everything the client told us
+    /// in plaintext, except how badly it's behaved.
+    fn reset_for_tls(&mut self) {
+        *self = Self {
+            remote_addr: self.remote_addr,
+            tls: true,
+            errors: self.errors,
+            discard_connection: self.discard_connection,
+            ..Self::default()
+        };
+    }
 
-    let mut state = State {
-        esmtp: false,
-        greeting_done: false,
-        finished: false,
-
-        mail_from: None,
-        rcpt_to: Vec::new(),
-        waiting_for_data: false,
-        data: Vec::new(),
-    };
+    /// How long we'll wait for the client's next read, depending on where it is in
+    /// the session.
+    fn read_timeout(&self, timeouts: &Timeouts) -> Duration {
+        if self.waiting_for_data {
+            let left = self
+                .data_deadline
+                .map_or(timeouts.data_terminator, |deadline| {
+                    deadline.saturating_duration_since(Instant::now())
+                });
+            timeouts.data_block.min(left)
+        } else if !self.greeting_done {
+            timeouts.greeting
+        } else {
+            timeouts.command
+        }
+    }
 
-    socket.write_all(messages::GREETING).await?;
+    /// Turns the finished mail transaction into an [`Email`], and resets the
+    /// transaction so the client can send another one on the same connection.
+    fn take_email(&mut self) -> Result<Email, Error> {
+        let mail_from = self.mail_from.take().ok_or(Error::MailFromMissing)?;
+        if self.rcpt_to.is_empty() {
+            return Err(Error::RcptToMissing);
+        }
+        self.size_limits.clear();
+        self.declared_size = None;
+
+        Ok(Email {
+            mail_from,
+            rcpt_to: std::mem::take(&mut self.rcpt_to),
+            dsn: std::mem::take(&mut self.dsn),
+            data: String::from_utf8_lossy(&std::mem::take(&mut self.data)).to_string(), // FIXME: this is inefficient.
+            remote_addr: self.remote_addr,
+            helo: self.helo.clone(),
+            authenticated_as: self.authenticated_as.clone(),
+            id: nanoid!(),
+        })
+    }
+}
+
+async fn process<S, T, F, C, CF, G, GF>(
+    mut socket: S,
+    config: &Config,
+    state: &mut State,
+    milters: &mut Filters,
+    is_email_valid: &T,
+    are_credentials_valid: &C,
+    handle_event: &G,
+) -> Result<Outcome, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    T: Fn(&str, Option<&str>) -> F + Send,
+    F: Future<Output: Into<RecipientVerdict>> + Send,
+    C: Fn(&str, &str) -> CF + Send,
+    CF: Future<Output = bool> + Send,
+    G: Fn(Event) -> GF,
+    GF: Future<Output: Into<Delivery>> + Send,
+{
+    let mut buf = vec![0; consts::READ_BUFFER_SIZE];
+    tracing::debug!(tls = state.tls, "processing connection");
 
     loop {
-        let n = match socket.read(&mut buf).await {
+        let read = timeout(state.read_timeout(&config.timeouts), socket.read(&mut buf));
+        let n = match read.await {
+            Err(_) => {
+                tracing::debug!("client timed out");
+                send(&mut socket, &config.timeouts, messages::TIMEOUT).await?;
+                return Err(Error::Timeout);
+            }
             // socket closed
-            Ok(0) => return Ok(state),
-            Ok(n) => n,
-            Err(e) => {
+            Ok(Ok(0)) => {
+                if state.mail_from.is_some() {
+                    tracing::debug!("connection closed before finishing");
+                }
+                return Ok(Outcome::Closed);
+            }
+            Ok(Ok(n)) => n,
+            Ok(Err(e)) => {
                 return Err(Error::NetworkError(e));
             }
         };
@@ -78,194 +329,1517 @@ where
         tracing::trace!("received: {:?}", msg);
 
         if state.waiting_for_data {
-            // TODO: Implement dot stuffing
-            // tracing::debug!("data: {:?}", msg);
             // TODO: is this correct?
-            if msg.ends_with("\r\n.\r\n") {
-                // -5 for \r\n.\r\n
-                if state.data.len() + n - 5 > consts::MAX_SIZE {
-                    socket.write_all(messages::MESSAGE_TOO_LARGE).await?;
-                    continue;
+            let finished = msg.ends_with("\r\n.\r\n");
+            // Don't include the \r\n.\r\n
+            let chunk = if finished { &buf[..n - 5] } else { &buf[..n] };
+            if state.data.len() + chunk.len() > consts::MAX_SIZE {
+                // Keep reading until the end, but there's no point holding on to it.
+                state.too_large = true;
+                state.data = Vec::new();
+            } else if !state.too_large {
+                tracing::trace!("adding {} bytes to data", chunk.len());
+                state.data.extend_from_slice(chunk);
+            }
+            if !finished {
+                continue;
+            }
+
+            state.waiting_for_data = false;
+            state.data_deadline = None;
+            tracing::trace!("got . in data, ending");
+
+            if std::mem::take(&mut state.too_large) {
+                tracing::debug!("message too large");
+                let email = state.take_email()?;
+                state.discard_message = false;
+                milters.abort().await;
+                // RFC 2033 section 4.2: with LMTP, every recipient gets a reply.
+                let replies = match config.protocol {
+                    Protocol::Smtp => 1,
+                    Protocol::Lmtp => email.rcpt_to.len(),
+                };
+                send(
+                    &mut socket,
+                    &config.timeouts,
+                    &messages::MESSAGE_TOO_LARGE.repeat(replies),
+                )
+                .await?;
+                continue;
+            }
+
+            // The client doubled the dot at the start of every line (RFC 5321 section
+            // 4.5.2), so take it back off.
+            state.data = dot_unstuff(&state.data);
+
+            // Which recipients don't have room for this message.
+            let over_quota: Vec<bool> = state
+                .size_limits
+                .iter()
+                .map(|limit| limit.is_some_and(|limit| state.data.len() > limit))
+                .collect();
+            if config.protocol == Protocol::Smtp && over_quota.contains(&true) {
+                // There's only one reply, so nobody gets it. Clients that send SIZE=
+                // are told at RCPT TO instead.
+                tracing::debug!("message too large for a recipient's mailbox");
+                state.take_email()?;
+                state.discard_message = false;
+                milters.abort().await;
+                send(&mut socket, &config.timeouts, messages::OVER_QUOTA).await?;
+                continue;
+            }
+
+            let (verdict, changes) = milters.message(&state.data).await;
+            milter::apply_changes(&mut state.data, &changes);
+            let mut email = state.take_email()?;
+            let recipients = email.rcpt_to.clone();
+            // With LMTP, the recipients over quota are refused on their own below.
+            let mut over = over_quota.iter();
+            email.rcpt_to.retain(|_| over.next() != Some(&true));
+            let mut over = over_quota.iter();
+            email.dsn.recipients.retain(|_| over.next() != Some(&true));
+            let discard = state.discard_connection || state.discard_message;
+            state.discard_message = false;
+            let (refusal, delivery) = match verdict {
+                Verdict::Continue if !discard && !email.rcpt_to.is_empty() => {
+                    let delivered_to = email.rcpt_to.len();
+                    let delivery: Delivery = handle_event(Event::EmailReceived(email)).await.into();
+                    // Only if nobody got it is it worth the client trying again.
+                    let refusal = (delivery.failed.len() >= delivered_to)
+                        .then(|| messages::LOCAL_ERROR.to_vec());
+                    (refusal, delivery)
                 }
+                Verdict::Continue => (None, Delivery::default()),
+                Verdict::Discard => {
+                    tracing::debug!("message discarded by milter");
+                    (None, Delivery::default())
+                }
+                Verdict::Reject(reply) | Verdict::TempFail(reply) => {
+                    tracing::debug!("message refused by milter");
+                    (Some(reply), Delivery::default())
+                }
+            };
 
-                state.waiting_for_data = false;
-                state.finished = true;
-                tracing::trace!("got . in data, ending");
+            match config.protocol {
+                Protocol::Smtp => {
+                    let reply = refusal.as_deref().unwrap_or(messages::OK);
+                    send(&mut socket, &config.timeouts, reply).await?;
+                }
+                Protocol::Lmtp => {
+                    // Every recipient gets its own reply, in RCPT TO order.
+                    let mut response = Vec::new();
+                    for (recipient, over_quota) in recipients.iter().zip(over_quota) {
+                        if over_quota {
+                            response.extend_from_slice(messages::OVER_QUOTA);
+                        } else if delivery.failed.contains(recipient) {
+                            response.extend_from_slice(messages::LOCAL_ERROR);
+                        } else if let Some(reply) = &refusal {
+                            response.extend_from_slice(reply);
+                        } else {
+                            response
+                                .extend_from_slice(messages::lmtp_delivered(recipient).as_bytes());
+                        }
+                    }
+                    send(&mut socket, &config.timeouts, &response).await?;
+                }
+            }
+        } else if let Some(step) = state.auth_step.take() {
+            let response = msg.trim();
+            if response == "*" {
+                tracing::trace!("AUTH cancelled by client");
+                send(&mut socket, &config.timeouts, messages::AUTH_CANCELLED).await?;
+                continue;
+            }
 
-                state.data.extend_from_slice(&buf[0..n - 5]); // Don't include the \r\n.\r\n
-                socket.write_all(messages::OK).await?;
-            } else {
-                if state.data.len() + n > consts::MAX_SIZE {
-                    socket.write_all(messages::MESSAGE_TOO_LARGE).await?;
+            let credentials = match step {
+                AuthStep::PlainResponse => auth::decode_plain(response),
+                AuthStep::LoginUsername => {
+                    let Some(username) = auth::decode_base64(response) else {
+                        send(&mut socket, &config.timeouts, messages::AUTH_MALFORMED).await?;
+                        continue;
+                    };
+                    state.auth_step = Some(AuthStep::LoginPassword { username });
+                    send(
+                        &mut socket,
+                        &config.timeouts,
+                        messages::auth_challenge(auth::PASSWORD_CHALLENGE).as_bytes(),
+                    )
+                    .await?;
                     continue;
                 }
+                AuthStep::LoginPassword { username } => {
+                    auth::decode_base64(response).map(|password| (username, password))
+                }
+            };
 
-                tracing::trace!("adding {n} bytes to data");
-                state.data.extend_from_slice(&buf[0..n]);
+            let Some((username, password)) = credentials else {
+                send(&mut socket, &config.timeouts, messages::AUTH_MALFORMED).await?;
+                continue;
+            };
+            if !authenticate(
+                &mut socket,
+                state,
+                config,
+                username,
+                &password,
+                are_credentials_valid,
+            )
+            .await?
+            {
+                return Ok(Outcome::Closed);
             }
         } else {
             let Some(command) = Command::from_smtp_message(msg.trim()) else {
                 tracing::trace!("command unrecognised");
-                socket.write_all(messages::UNRECOGNIZED_COMMAND).await?;
+                if !reply_to_error(&mut socket, state, config, messages::UNRECOGNIZED_COMMAND)
+                    .await?
+                {
+                    return Ok(Outcome::Closed);
+                }
                 continue;
             };
             match command {
+                // RFC 2033 section 4.1: LMTP servers mustn't accept HELO or EHLO,
+                // and SMTP servers have no business accepting LHLO.
+                Command::Helo { .. } | Command::Ehlo { .. }
+                    if config.protocol == Protocol::Lmtp =>
+                {
+                    if !reply_to_error(&mut socket, state, config, messages::UNRECOGNIZED_COMMAND)
+                        .await?
+                    {
+                        return Ok(Outcome::Closed);
+                    }
+                }
+                Command::Lhlo { .. } if config.protocol == Protocol::Smtp => {
+                    if !reply_to_error(&mut socket, state, config, messages::UNRECOGNIZED_COMMAND)
+                        .await?
+                    {
+                        return Ok(Outcome::Closed);
+                    }
+                }
                 Command::Helo { fqdn } => {
                     tracing::trace!("HELO");
+                    match milters.helo(fqdn).await {
+                        Verdict::Continue => {}
+                        Verdict::Discard => state.discard_connection = true,
+                        Verdict::Reject(reply) | Verdict::TempFail(reply) => {
+                            send(&mut socket, &config.timeouts, &reply).await?;
+                            continue;
+                        }
+                    }
                     state.greeting_done = true;
                     state.esmtp = false;
-                    socket
-                        .write_all(messages::helo_response(fqdn).as_bytes())
-                        .await?;
+                    state.helo = Some(fqdn.to_string());
+                    send(
+                        &mut socket,
+                        &config.timeouts,
+                        messages::helo_response(fqdn).as_bytes(),
+                    )
+                    .await?;
                 }
-                Command::Ehlo { fqdn } => {
-                    tracing::trace!("EHLO");
+                Command::Ehlo { fqdn } | Command::Lhlo { fqdn } => {
+                    tracing::trace!("EHLO/LHLO");
+                    match milters.helo(fqdn).await {
+                        Verdict::Continue => {}
+                        Verdict::Discard => state.discard_connection = true,
+                        Verdict::Reject(reply) | Verdict::TempFail(reply) => {
+                            send(&mut socket, &config.timeouts, &reply).await?;
+                            continue;
+                        }
+                    }
                     state.greeting_done = true;
                     state.esmtp = true;
+                    state.helo = Some(fqdn.to_string());
 
-                    let mut response = Vec::new();
-                    response.extend_from_slice(messages::helo_response(fqdn).as_bytes());
-
-                    for ext in esmtp::SUPPORTED_EXTENSIONS {
-                        response.extend_from_slice(format!("250-{ext}\r\n").as_bytes());
+                    let mut extensions = esmtp::SUPPORTED_EXTENSIONS.to_vec();
+                    if config.offers_starttls(state.tls) {
+                        extensions.push(esmtp::STARTTLS);
+                    }
+                    if config.offers_auth(state.tls) {
+                        extensions.push(esmtp::AUTH);
                     }
-                    response.extend_from_slice(b"250 SMTPUTF8\r\n");
 
-                    socket.write_all(&response).await?;
+                    send(
+                        &mut socket,
+                        &config.timeouts,
+                        &messages::ehlo_response(fqdn, &extensions).to_bytes(),
+                    )
+                    .await?;
                 }
 
-                Command::MailFrom { email } => {
+                Command::MailFrom { email, parameters } => {
                     if !state.greeting_done {
                         tracing::trace!("MAIL FROM in wrong order");
-                        socket.write_all(messages::BAD_COMMAND_SEQUENCE).await?;
+                        send(
+                            &mut socket,
+                            &config.timeouts,
+                            messages::BAD_COMMAND_SEQUENCE,
+                        )
+                        .await?;
+                        continue;
+                    }
+                    if config.auth == AuthMode::Required && state.authenticated_as.is_none() {
+                        tracing::trace!("MAIL FROM before AUTH");
+                        let reply = if state.tls {
+                            messages::AUTH_REQUIRED
+                        } else {
+                            messages::STARTTLS_REQUIRED
+                        };
+                        send(&mut socket, &config.timeouts, reply).await?;
+                        continue;
+                    }
+                    let Some((ret, envid)) = dsn::parse_mail_parameters(&parameters) else {
+                        send(
+                            &mut socket,
+                            &config.timeouts,
+                            messages::INVALID_DSN_PARAMETERS,
+                        )
+                        .await?;
+                        continue;
+                    };
+                    // RFC 1870 section 6.1: no point taking a message we'd refuse anyway.
+                    let declared_size = esmtp::declared_size(&parameters);
+                    if declared_size.is_some_and(|size| size > consts::MAX_SIZE) {
+                        send(&mut socket, &config.timeouts, messages::MESSAGE_TOO_LARGE).await?;
                         continue;
                     }
+                    match milters
+                        .mail_from(email, state.authenticated_as.as_deref())
+                        .await
+                    {
+                        Verdict::Continue => {}
+                        Verdict::Discard => state.discard_message = true,
+                        Verdict::Reject(reply) | Verdict::TempFail(reply) => {
+                            milters.abort().await;
+                            send(&mut socket, &config.timeouts, &reply).await?;
+                            continue;
+                        }
+                    }
 
                     state.mail_from = Some(email.to_string());
-                    socket.write_all(messages::OK).await?;
+                    state.rcpt_to.clear();
+                    state.size_limits.clear();
+                    state.declared_size = declared_size;
+                    state.dsn = Dsn {
+                        ret,
+                        envid,
+                        recipients: Vec::new(),
+                    };
+                    send(&mut socket, &config.timeouts, messages::OK).await?;
                     tracing::trace!("MAIL FROM sent");
                 }
-                Command::RcptTo { email } => {
+                Command::RcptTo { email, parameters } => {
                     if !state.greeting_done || state.mail_from.is_none() {
                         tracing::trace!("RCPT TO in wrong order");
-                        socket.write_all(messages::BAD_COMMAND_SEQUENCE).await?;
+                        send(
+                            &mut socket,
+                            &config.timeouts,
+                            messages::BAD_COMMAND_SEQUENCE,
+                        )
+                        .await?;
                         continue;
                     }
+                    let Some(recipient_dsn) = dsn::parse_rcpt_parameters(&parameters) else {
+                        send(
+                            &mut socket,
+                            &config.timeouts,
+                            messages::INVALID_DSN_PARAMETERS,
+                        )
+                        .await?;
+                        continue;
+                    };
 
                     let email = email.to_string();
                     let email = email.trim();
-                    if !is_email_valid(email).await {
-                        tracing::trace!("email incoming, but recipient is invalid");
-                        socket.write_all(messages::USER_UNKNOWN).await?;
-                        continue;
+                    let size_limit = match is_email_valid(email, state.authenticated_as.as_deref())
+                        .await
+                        .into()
+                    {
+                        RecipientVerdict::Accept => None,
+                        RecipientVerdict::AcceptUpTo(limit) => Some(limit),
+                        RecipientVerdict::Unknown => {
+                            tracing::trace!("email incoming, but recipient is invalid");
+                            if !reply_to_error(&mut socket, state, config, messages::USER_UNKNOWN)
+                                .await?
+                            {
+                                return Ok(Outcome::Closed);
+                            }
+                            continue;
+                        }
+                        RecipientVerdict::MailboxFull => {
+                            tracing::trace!("email incoming, but recipient's mailbox is full");
+                            send(&mut socket, &config.timeouts, messages::MAILBOX_FULL).await?;
+                            continue;
+                        }
+                    };
+                    // RFC 1870 section 6.2: if we know it won't fit, only this
+                    // recipient has to miss out, rather than the whole transaction.
+                    if let (Some(limit), Some(size)) = (size_limit, state.declared_size) {
+                        if size > limit {
+                            tracing::trace!("email incoming, but too large for recipient");
+                            send(&mut socket, &config.timeouts, messages::OVER_QUOTA).await?;
+                            continue;
+                        }
+                    }
+
+                    match milters.rcpt_to(email).await {
+                        Verdict::Continue => {}
+                        Verdict::Discard => state.discard_message = true,
+                        Verdict::Reject(reply) | Verdict::TempFail(reply) => {
+                            send(&mut socket, &config.timeouts, &reply).await?;
+                            continue;
+                        }
                     }
 
                     tracing::trace!("added new recipient");
                     state.rcpt_to.push(email.to_string());
-                    socket.write_all(messages::OK).await?;
+                    state.size_limits.push(size_limit);
+                    state.dsn.recipients.push(recipient_dsn);
+                    send(&mut socket, &config.timeouts, messages::OK).await?;
                 }
                 Command::Data => {
                     if !state.greeting_done || state.mail_from.is_none() || state.rcpt_to.is_empty()
                     {
                         tracing::trace!("DATA sent, but in wrong order");
-                        socket.write_all(messages::BAD_COMMAND_SEQUENCE).await?;
+                        send(
+                            &mut socket,
+                            &config.timeouts,
+                            messages::BAD_COMMAND_SEQUENCE,
+                        )
+                        .await?;
                         continue;
                     }
 
                     state.waiting_for_data = true;
+                    state.data_deadline = Some(Instant::now() + config.timeouts.data_terminator);
                     tracing::trace!("waiting for data");
-                    socket.write_all(messages::DATA_RESPONSE).await?;
+                    send(&mut socket, &config.timeouts, messages::DATA_RESPONSE).await?;
                 }
 
-                Command::Help => {
-                    socket.write_all(messages::HELP_RESPONSE).await?;
+                Command::Auth {
+                    mechanism,
+                    initial_response,
+                } => {
+                    if config.auth == AuthMode::Disabled {
+                        send(&mut socket, &config.timeouts, messages::NOT_IMPLEMENTED).await?;
+                        continue;
+                    }
+                    if !state.tls {
+                        tracing::trace!("AUTH before STARTTLS");
+                        send(&mut socket, &config.timeouts, messages::ENCRYPTION_REQUIRED).await?;
+                        continue;
+                    }
+                    // RFC 4954 section 4: AUTH needs EHLO, can only succeed once,
+                    // and isn't allowed in the middle of a mail transaction.
+                    if !state.esmtp || state.authenticated_as.is_some() || state.mail_from.is_some()
+                    {
+                        tracing::trace!("AUTH in wrong order");
+                        send(
+                            &mut socket,
+                            &config.timeouts,
+                            messages::BAD_COMMAND_SEQUENCE,
+                        )
+                        .await?;
+                        continue;
+                    }
+
+                    match (mechanism.to_uppercase().as_str(), initial_response) {
+                        ("PLAIN", Some(response)) => {
+                            let Some((username, password)) = auth::decode_plain(response) else {
+                                send(&mut socket, &config.timeouts, messages::AUTH_MALFORMED)
+                                    .await?;
+                                continue;
+                            };
+                            if !authenticate(
+                                &mut socket,
+                                state,
+                                config,
+                                username,
+                                &password,
+                                are_credentials_valid,
+                            )
+                            .await?
+                            {
+                                return Ok(Outcome::Closed);
+                            }
+                        }
+                        ("PLAIN", None) => {
+                            state.auth_step = Some(AuthStep::PlainResponse);
+                            send(
+                                &mut socket,
+                                &config.timeouts,
+                                messages::auth_challenge("").as_bytes(),
+                            )
+                            .await?;
+                        }
+                        ("LOGIN", Some(response)) => {
+                            let Some(username) = auth::decode_base64(response) else {
+                                send(&mut socket, &config.timeouts, messages::AUTH_MALFORMED)
+                                    .await?;
+                                continue;
+                            };
+                            state.auth_step = Some(AuthStep::LoginPassword { username });
+                            send(
+                                &mut socket,
+                                &config.timeouts,
+                                messages::auth_challenge(auth::PASSWORD_CHALLENGE).as_bytes(),
+                            )
+                            .await?;
+                        }
+                        ("LOGIN", None) => {
+                            state.auth_step = Some(AuthStep::LoginUsername);
+                            send(
+                                &mut socket,
+                                &config.timeouts,
+                                messages::auth_challenge(auth::USERNAME_CHALLENGE).as_bytes(),
+                            )
+                            .await?;
+                        }
+                        _ => {
+                            send(
+                                &mut socket,
+                                &config.timeouts,
+                                messages::AUTH_MECHANISM_UNSUPPORTED,
+                            )
+                            .await?;
+                        }
+                    }
+                }
+
+                Command::Vrfy { address: None } | Command::Expn { address: None } => {
+                    send(&mut socket, &config.timeouts, messages::SYNTAX_ERROR).await?;
+                }
+                Command::Vrfy {
+                    address: Some(address),
+                } => match config.verify {
+                    VerifyPolicy::CannotVerify => {
+                        send(&mut socket, &config.timeouts, messages::CANNOT_VRFY).await?;
+                    }
+                    VerifyPolicy::Check => {
+                        let verdict: RecipientVerdict =
+                            is_email_valid(address, state.authenticated_as.as_deref())
+                                .await
+                                .into();
+                        if verdict != RecipientVerdict::Unknown {
+                            send(
+                                &mut socket,
+                                &config.timeouts,
+                                messages::vrfy_response(address).as_bytes(),
+                            )
+                            .await?;
+                        } else if !reply_to_error(
+                            &mut socket,
+                            state,
+                            config,
+                            messages::USER_UNKNOWN,
+                        )
+                        .await?
+                        {
+                            return Ok(Outcome::Closed);
+                        }
+                    }
+                },
+                // We don't have any mailing lists, so there's never anything to expand.
+                Command::Expn {
+                    address: Some(address),
+                } => match config.verify {
+                    VerifyPolicy::CannotVerify => {
+                        send(&mut socket, &config.timeouts, messages::EXPN_NOT_SUPPORTED).await?;
+                    }
+                    VerifyPolicy::Check => {
+                        let verdict: RecipientVerdict =
+                            is_email_valid(address, state.authenticated_as.as_deref())
+                                .await
+                                .into();
+                        if verdict != RecipientVerdict::Unknown {
+                            send(&mut socket, &config.timeouts, messages::NOT_A_LIST).await?;
+                        } else if !reply_to_error(
+                            &mut socket,
+                            state,
+                            config,
+                            messages::USER_UNKNOWN,
+                        )
+                        .await?
+                        {
+                            return Ok(Outcome::Closed);
+                        }
+                    }
+                },
+
+                Command::Help { topic: None } => {
+                    send(
+                        &mut socket,
+                        &config.timeouts,
+                        &messages::help_response(&config.commands(state.tls)).to_bytes(),
+                    )
+                    .await?;
+                }
+                Command::Help { topic: Some(topic) } => {
+                    match messages::help_topic(&config.commands(state.tls), topic) {
+                        Some(reply) => {
+                            send(&mut socket, &config.timeouts, &reply.to_bytes()).await?
+                        }
+                        None => {
+                            send(&mut socket, &config.timeouts, messages::UNKNOWN_HELP_TOPIC)
+                                .await?
+                        }
+                    }
                 }
                 Command::NoOp => {
-                    socket.write_all(messages::OK).await?;
+                    send(&mut socket, &config.timeouts, messages::OK).await?;
                 }
                 Command::Rset => {
+                    if state.mail_from.is_some() {
+                        milters.abort().await;
+                    }
                     state.mail_from = None;
                     state.rcpt_to.clear();
+                    state.size_limits.clear();
+                    state.declared_size = None;
+                    state.dsn = Dsn::default();
                     state.data = Vec::new();
-                    socket.write_all(messages::OK).await?;
+                    state.discard_message = false;
+                    send(&mut socket, &config.timeouts, messages::OK).await?;
+                }
+                Command::StartTls => {
+                    if !config.offers_starttls(state.tls) {
+                        send(&mut socket, &config.timeouts, messages::NOT_IMPLEMENTED).await?;
+                        continue;
+                    }
+                    if state.mail_from.is_some() {
+                        tracing::trace!("STARTTLS in the middle of a transaction");
+                        send(
+                            &mut socket,
+                            &config.timeouts,
+                            messages::BAD_COMMAND_SEQUENCE,
+                        )
+                        .await?;
+                        continue;
+                    }
+                    send(&mut socket, &config.timeouts, messages::READY_FOR_TLS).await?;
+                    return Ok(Outcome::StartTls);
                 }
                 Command::Quit => {
-                    state.finished = true;
-                    socket.write_all(messages::BYE).await?;
+                    milters.quit().await;
+                    send(&mut socket, &config.timeouts, messages::BYE).await?;
                     socket.shutdown().await?;
-                    return Ok(state);
+                    return Ok(Outcome::Closed);
                 }
             }
         }
     }
 }
 
+/// Checks the credentials the client sent with AUTH. A wrong password counts as an
+/// error for the tarpit, so guessing passwords gets slow quickly.
+///
+/// Returns `false` if the client should be disconnected, like [`reply_to_error`].
+async fn authenticate<S, C, CF>(
+    socket: &mut S,
+    state: &mut State,
+    config: &Config,
+    username: String,
+    password: &str,
+    are_credentials_valid: &C,
+) -> Result<bool, Error>
+where
+    S: AsyncWrite + Unpin,
+    C: Fn(&str, &str) -> CF + Send,
+    CF: Future<Output = bool> + Send,
+{
+    if are_credentials_valid(&username, password).await {
+        tracing::debug!(username, "client authenticated");
+        state.authenticated_as = Some(username);
+        send(socket, &config.timeouts, messages::AUTH_SUCCESSFUL).await?;
+        Ok(true)
+    } else {
+        tracing::debug!(username, "client failed to authenticate");
+        reply_to_error(socket, state, config, messages::AUTH_FAILED).await
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Email {
     pub mail_from: String,
     pub rcpt_to: Vec<String>,
+    /// The DSN parameters the client sent with MAIL FROM and RCPT TO.
+    #[serde(default)]
+    pub dsn: Dsn,
     pub data: String,
+    /// The address of the connecting client, if it was known.
+    #[serde(default)]
+    pub remote_addr: Option<SocketAddr>,
+    /// The domain the client announced in HELO/EHLO.
+    #[serde(default)]
+    pub helo: Option<String>,
+    /// The username the client authenticated as with AUTH, if any.
+    #[serde(default)]
+    pub authenticated_as: Option<String>,
     // FIXME: this is *probably* the wrong place to put this.
     // However, it's also the easiest way.
     // Get rid of this ASAP.
     pub id: String,
 }
 
-pub async fn listen<A, F, Fut, G>(addr: A, validate_email: F, handle_event: G) -> Result<(), Error>
+/// Sends `reply` to the client, giving up if it stops reading for as long as we'd
+/// wait for it to send a command.
+/// Drops the extra dot from the start of every line that has one, undoing the client's
+/// dot stuffing.
+fn dot_unstuff(data: &[u8]) -> Vec<u8> {
+    let mut unstuffed = Vec::with_capacity(data.len());
+    let mut line_start = true;
+    for &byte in data {
+        if !(line_start && byte == b'.') {
+            unstuffed.push(byte);
+        }
+        line_start = byte == b'\n';
+    }
+    unstuffed
+}
+
+async fn send<S>(socket: &mut S, timeouts: &Timeouts, reply: &[u8]) -> Result<(), Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    timeout(timeouts.command, socket.write_all(reply))
+        .await
+        .map_err(|_| Error::Timeout)??;
+    Ok(())
+}
+
+/// Replies to something the client got wrong, after however long the tarpit says.
+///
+/// Returns `false` if the client has run out of errors, in which case it has been
+/// told so and the connection should be closed.
+async fn reply_to_error<S>(
+    socket: &mut S,
+    state: &mut State,
+    config: &Config,
+    reply: &[u8],
+) -> Result<bool, Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    state.errors += 1;
+    let Some(tarpit) = &config.tarpit else {
+        send(socket, &config.timeouts, reply).await?;
+        return Ok(true);
+    };
+    if let Some(remote_addr) = state.remote_addr {
+        state.errors = tarpit.record_error(remote_addr.ip());
+    }
+
+    if tarpit.should_disconnect(state.errors) {
+        tracing::debug!("too many errors ({}), disconnecting", state.errors);
+        send(socket, &config.timeouts, messages::TOO_MANY_ERRORS).await?;
+        return Ok(false);
+    }
+
+    let delay = tarpit.delay(state.errors);
+    if !delay.is_zero() {
+        tracing::debug!("tarpitting for {delay:?} after {} errors", state.errors);
+        tokio::time::sleep(delay).await;
+    }
+    send(socket, &config.timeouts, reply).await?;
+    Ok(true)
+}
+
+/// Speaks SMTP over an already-accepted connection until the client goes away.
+///
+/// This is what [`listen`] and [`listen_unix`] run for every connection, and it works
+/// with any transport. `remote_addr` is the client's address, if the transport has
+/// one. The PROXY header and implicit TLS in `config` are handled here, so `socket`
+/// should be the raw stream.
+pub async fn serve_connection<S, F, Fut, C, CFut, G, GFut>(
+    socket: S,
+    remote_addr: Option<SocketAddr>,
+    config: &Config,
+    validate_email: F,
+    validate_credentials: C,
+    handle_event: G,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(&str, Option<&str>) -> Fut + Send,
+    Fut: Future<Output: Into<RecipientVerdict>> + Send,
+    C: Fn(&str, &str) -> CFut + Send,
+    CFut: Future<Output = bool> + Send,
+    G: Fn(Event) -> GFut,
+    GFut: Future<Output: Into<Delivery>> + Send,
+{
+    let mut socket = socket;
+    // There's no point sending a 421 before the TLS handshake, so if the client is too
+    // slow to get that far we just hang up.
+    let handshake_deadline = Instant::now() + config.timeouts.greeting;
+
+    let remote_addr = if config.proxy_protocol {
+        tokio::time::timeout_at(handshake_deadline, proxy::read_header(&mut socket))
+            .await
+            .map_err(|_| Error::Timeout)??
+            .or(remote_addr)
+    } else {
+        remote_addr
+    };
+    if let Some(remote_addr) = remote_addr {
+        tracing::Span::current().record("remote_addr", tracing::field::display(remote_addr));
+    }
+    tracing::debug!("accepted connection");
+
+    match &config.implicit_tls {
+        Some(tls_config) => {
+            let socket = accept_tls(tls_config, socket, handshake_deadline).await?;
+            session(
+                socket,
+                remote_addr,
+                config,
+                true,
+                validate_email,
+                validate_credentials,
+                handle_event,
+            )
+            .await
+        }
+        None => {
+            session(
+                socket,
+                remote_addr,
+                config,
+                false,
+                validate_email,
+                validate_credentials,
+                handle_event,
+            )
+            .await
+        }
+    }
+}
+
+async fn accept_tls<S>(
+    tls_config: &Arc<ServerConfig>,
+    socket: S,
+    deadline: Instant,
+) -> Result<TlsStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let accept = TlsAcceptor::from(tls_config.clone()).accept(socket);
+    Ok(tokio::time::timeout_at(deadline, accept)
+        .await
+        .map_err(|_| Error::Timeout)??)
+}
+
+/// Greets the client and speaks SMTP until it goes away, upgrading to TLS halfway
+/// through if it asks for STARTTLS.
+async fn session<S, F, Fut, C, CFut, G, GFut>(
+    mut socket: S,
+    remote_addr: Option<SocketAddr>,
+    config: &Config,
+    tls: bool,
+    validate_email: F,
+    validate_credentials: C,
+    handle_event: G,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(&str, Option<&str>) -> Fut + Send,
+    Fut: Future<Output: Into<RecipientVerdict>> + Send,
+    C: Fn(&str, &str) -> CFut + Send,
+    CFut: Future<Output = bool> + Send,
+    G: Fn(Event) -> GFut,
+    GFut: Future<Output: Into<Delivery>> + Send,
+{
+    let mut state = State {
+        remote_addr,
+        tls,
+        ..State::default()
+    };
+    if let (Some(tarpit), Some(remote_addr)) = (&config.tarpit, remote_addr) {
+        state.errors = tarpit.errors(remote_addr.ip());
+        if tarpit.should_disconnect(state.errors) {
+            tracing::debug!("too many errors ({}) already, refusing", state.errors);
+            send(&mut socket, &config.timeouts, messages::TOO_MANY_ERRORS).await?;
+            return Ok(());
+        }
+    }
+    let (mut milters, verdict) =
+        Filters::connect(&config.milters, consts::HOSTNAME, remote_addr).await;
+    match verdict {
+        Verdict::Continue => {}
+        Verdict::Discard => state.discard_connection = true,
+        // The milter's own reply would be a 5xx or 4xx meant for a command, but in
+        // place of the greeting only 554 and 421 make sense (RFC 5321 3.1).
+        Verdict::Reject(_) => {
+            tracing::debug!("connection refused by milter");
+            send(&mut socket, &config.timeouts, messages::CONNECTION_REFUSED).await?;
+            return Ok(());
+        }
+        Verdict::TempFail(_) => {
+            tracing::debug!("connection tempfailed by milter");
+            send(&mut socket, &config.timeouts, messages::SERVICE_UNAVAILABLE).await?;
+            return Ok(());
+        }
+    }
+
+    match config.protocol {
+        Protocol::Smtp => send(&mut socket, &config.timeouts, messages::GREETING).await?,
+        Protocol::Lmtp => send(&mut socket, &config.timeouts, messages::LMTP_GREETING).await?,
+    }
+
+    let outcome = process(
+        &mut socket,
+        config,
+        &mut state,
+        &mut milters,
+        &validate_email,
+        &validate_credentials,
+        &handle_event,
+    )
+    .await?;
+    let (Outcome::StartTls, Some(tls_config)) = (outcome, &config.starttls) else {
+        return Ok(());
+    };
+
+    tracing::debug!("starting TLS");
+    let deadline = Instant::now() + config.timeouts.greeting;
+    let socket = accept_tls(tls_config, socket, deadline).await?;
+    state.reset_for_tls();
+    process(
+        socket,
+        config,
+        &mut state,
+        &mut milters,
+        &validate_email,
+        &validate_credentials,
+        &handle_event,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Runs [`serve_connection`] in the background, with logging.
+fn spawn_connection<S, F, Fut, C, CFut, G, GFut>(
+    socket: S,
+    remote_addr: Option<SocketAddr>,
+    config: Config,
+    validate_email: F,
+    validate_credentials: C,
+    handle_event: G,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(&str, Option<&str>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output: Into<RecipientVerdict>> + Send + 'static,
+    C: Fn(&str, &str) -> CFut + Send + Sync + 'static,
+    CFut: Future<Output = bool> + Send + 'static,
+    G: Fn(Event) -> GFut + Send + Sync + 'static,
+    GFut: Future<Output: Into<Delivery>> + Send + 'static,
+{
+    let span = tracing::info_span!("connection", remote_addr = tracing::field::Empty);
+    tokio::spawn(
+        async move {
+            let result = serve_connection(
+                socket,
+                remote_addr,
+                &config,
+                validate_email,
+                validate_credentials,
+                handle_event,
+            );
+
+            match result.await {
+                Err(Error::Timeout) => {
+                    tracing::warn!("connection timed out");
+                }
+                Err(e) => {
+                    // handle the error from the connection handling logic
+                    tracing::error!("error handling connection: {:?}", e);
+                }
+                Ok(()) => {}
+            }
+        }
+        .instrument(span),
+    );
+}
+
+/// Accepts SMTP connections on `addr` forever.
+///
+/// `validate_email` is called for every RCPT TO (and VRFY/EXPN, if
+/// [`Config::verify`] is [`VerifyPolicy::Check`]) with the address and the username
+/// the client authenticated as (if any). `validate_credentials` is called with the
+/// username and password whenever a client tries to AUTH; it's only used if
+/// [`Config::auth`] isn't [`AuthMode::Disabled`].
+pub async fn listen<A, F, Fut, C, CFut, G, GFut>(
+    addr: A,
+    config: Config,
+    validate_email: F,
+    validate_credentials: C,
+    handle_event: G,
+) -> Result<(), Error>
 where
     A: ToSocketAddrs + Display + Copy + Send,
-    F: Fn(&str) -> Fut + Send + Sync + Clone + 'static,
-    Fut: Future<Output = bool> + Send + 'static,
-    G: Fn(Event) + Send + Sync + Clone + 'static,
+    F: Fn(&str, Option<&str>) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output: Into<RecipientVerdict>> + Send + 'static,
+    C: Fn(&str, &str) -> CFut + Send + Sync + Clone + 'static,
+    CFut: Future<Output = bool> + Send + 'static,
+    G: Fn(Event) -> GFut + Send + Sync + Clone + 'static,
+    GFut: Future<Output: Into<Delivery>> + Send + 'static,
 {
     let listener = TcpListener::bind(addr).await.map_err(Error::NetworkError)?;
 
     tracing::debug!("listening on {addr}");
 
+    serve(
+        listener,
+        config,
+        validate_email,
+        validate_credentials,
+        handle_event,
+    )
+    .await
+}
+
+/// Like [`listen`], but on a listener you've already bound, say to port 0.
+pub async fn serve<F, Fut, C, CFut, G, GFut>(
+    listener: TcpListener,
+    config: Config,
+    validate_email: F,
+    validate_credentials: C,
+    handle_event: G,
+) -> Result<(), Error>
+where
+    F: Fn(&str, Option<&str>) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output: Into<RecipientVerdict>> + Send + 'static,
+    C: Fn(&str, &str) -> CFut + Send + Sync + Clone + 'static,
+    CFut: Future<Output = bool> + Send + 'static,
+    G: Fn(Event) -> GFut + Send + Sync + Clone + 'static,
+    GFut: Future<Output: Into<Delivery>> + Send + 'static,
+{
+    loop {
+        let (socket, remote_addr) = listener.accept().await?;
+        socket.set_nodelay(true)?;
+        spawn_connection(
+            socket,
+            Some(remote_addr),
+            config.clone(),
+            validate_email.clone(),
+            validate_credentials.clone(),
+            handle_event.clone(),
+        );
+    }
+}
+
+/// Like [`listen`], but accepts connections on a Unix domain socket at `path`.
+///
+/// The socket file must not already exist.
+#[cfg(unix)]
+pub async fn listen_unix<P, F, Fut, C, CFut, G, GFut>(
+    path: P,
+    config: Config,
+    validate_email: F,
+    validate_credentials: C,
+    handle_event: G,
+) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+    F: Fn(&str, Option<&str>) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output: Into<RecipientVerdict>> + Send + 'static,
+    C: Fn(&str, &str) -> CFut + Send + Sync + Clone + 'static,
+    CFut: Future<Output = bool> + Send + 'static,
+    G: Fn(Event) -> GFut + Send + Sync + Clone + 'static,
+    GFut: Future<Output: Into<Delivery>> + Send + 'static,
+{
+    let listener = UnixListener::bind(&path).map_err(Error::NetworkError)?;
+
+    tracing::debug!("listening on {}", path.as_ref().display());
+
     loop {
         let (socket, _) = listener.accept().await?;
-        let validate_email_clone = validate_email.clone();
-        let handle_event_clone = handle_event.clone();
+        spawn_connection(
+            socket,
+            None,
+            config.clone(),
+            validate_email.clone(),
+            validate_credentials.clone(),
+            handle_event.clone(),
+        );
+    }
+}
 
-        socket.set_nodelay(true)?;
-        tokio::spawn(async move {
-            let result = async {
-                let state = process(socket, validate_email_clone).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
 
-                if state.finished {
-                    if state.rcpt_to.is_empty() {
-                        return Err(Error::RcptToMissing);
-                    }
+    type Connection = tokio::task::JoinHandle<Result<(), Error>>;
 
-                    if state.data.is_empty() {
-                        return Err(Error::DataMissing);
-                    }
+    /// Connects a client to a server with `config` that takes every recipient and
+    /// nobody's password. Returns the client's end and the server's task.
+    fn spawn(config: Config) -> (DuplexStream, Connection) {
+        spawn_with(config, None, |_, _| async { true }, |_| async {})
+    }
 
-                    handle_event_clone(Event::EmailReceived(crate::Email {
-                        mail_from: state.mail_from.ok_or(Error::MailFromMissing)?,
-                        rcpt_to: state.rcpt_to,
-                        data: String::from_utf8_lossy(&state.data).to_string(), // FIXME: this is inefficient.
-                        id: nanoid!(),
-                    }));
-                } else {
-                    tracing::debug!("connection closed before finishing");
-                }
+    /// Like [`spawn`], but the client is at `remote_addr`, `verify` decides on each
+    /// recipient and `deliver` gets each email.
+    fn spawn_with<F, Fut, G, GFut>(
+        config: Config,
+        remote_addr: Option<SocketAddr>,
+        verify: F,
+        deliver: G,
+    ) -> (DuplexStream, Connection)
+    where
+        F: Fn(&str, Option<&str>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output: Into<RecipientVerdict>> + Send + 'static,
+        G: Fn(Event) -> GFut + Send + Sync + 'static,
+        GFut: Future<Output: Into<Delivery>> + Send + 'static,
+    {
+        let (client, server) = tokio::io::duplex(consts::READ_BUFFER_SIZE);
+        let connection = tokio::spawn(async move {
+            serve_connection(
+                server,
+                remote_addr,
+                &config,
+                verify,
+                |_, _| async { false },
+                deliver,
+            )
+            .await
+        });
+        (client, connection)
+    }
 
-                Ok(())
-            };
+    /// Runs a whole connection where the client doesn't say anything.
+    async fn run(config: Config) -> (Result<(), Error>, String) {
+        let (mut client, connection) = spawn(config);
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        (connection.await.unwrap(), output)
+    }
 
-            let timeout_duration = Duration::from_secs(60 * 5);
-            match timeout(timeout_duration, result).await {
-                Ok(Err(e)) => {
-                    // handle the error from the connection handling logic
-                    tracing::error!("error handling connection: {:?}", e);
-                }
-                Err(_) => {
-                    // handle the timeout error
-                    tracing::warn!("connection timed out");
+    /// Reads whatever the server sends next.
+    async fn read<S: AsyncRead + Unpin>(socket: &mut S) -> String {
+        let mut buf = [0; 1024];
+        let n = socket.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).into_owned()
+    }
+
+    /// Sends `line` and returns whatever comes back.
+    async fn reply<S: AsyncRead + AsyncWrite + Unpin>(socket: &mut S, line: &str) -> String {
+        socket.write_all(line.as_bytes()).await.unwrap();
+        read(socket).await
+    }
+
+    #[test]
+    fn dot_unstuffing() {
+        assert_eq!(
+            dot_unstuff(b"..hidden\r\nplain. text\r\n...\r\n."),
+            b".hidden\r\nplain. text\r\n..\r\n"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tarpit_disconnects_after_too_many_errors() {
+        let config = Config {
+            tarpit: Some(Tarpit {
+                free_errors: 1,
+                initial_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(60),
+                disconnect_after: 4,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let (mut client, connection) = spawn(config);
+        client.write_all(b"BOGUS\r\n").await.unwrap();
+
+        let mut output = Vec::new();
+        let mut buf = [0; 1024];
+        for _ in 0..3 {
+            let n = client.read(&mut buf).await.unwrap();
+            output.extend_from_slice(&buf[..n]);
+            client.write_all(b"BOGUS\r\n").await.unwrap();
+        }
+        client.read_to_end(&mut output).await.unwrap();
+        assert!(connection.await.unwrap().is_ok());
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("500 ").count(), 3);
+        assert!(output.ends_with(
+            "421 4.7.0 mail.vortex.skyfall.dev Too many errors, closing connection\r\n"
+        ));
+        // Nothing for the first error, then 1s and 2s.
+        assert_eq!(start.elapsed().as_secs(), 3);
+    }
+
+    #[tokio::test]
+    async fn tarpit_remembers_clients_across_connections() {
+        let config = Config {
+            tarpit: Some(Tarpit {
+                disconnect_after: 3,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let connect = || {
+            let remote_addr = Some("192.0.2.1:1234".parse().unwrap());
+            spawn_with(
+                config.clone(),
+                remote_addr,
+                |_, _| async { true },
+                |_| async {},
+            )
+        };
+
+        let (mut client, connection) = connect();
+        assert!(read(&mut client).await.starts_with("220 "));
+        assert!(reply(&mut client, "BOGUS\r\n").await.starts_with("500 "));
+        assert!(reply(&mut client, "BOGUS\r\n").await.starts_with("500 "));
+        assert!(reply(&mut client, "QUIT\r\n").await.starts_with("221 "));
+        assert!(connection.await.unwrap().is_ok());
+
+        // Reconnecting doesn't wipe the slate clean.
+        let (mut client, connection) = connect();
+        assert!(read(&mut client).await.starts_with("220 "));
+        assert!(reply(&mut client, "BOGUS\r\n")
+            .await
+            .starts_with("421 4.7.0 "));
+        assert!(connection.await.unwrap().is_ok());
+
+        // And once it's run out, it's not let back in.
+        let (mut client, connection) = connect();
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        assert!(output.starts_with("421 4.7.0 "));
+        assert!(connection.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn recipients_over_quota_are_refused() {
+        let (mut client, connection) = spawn_with(
+            Config::default(),
+            None,
+            |email, _| {
+                let verdict = match email {
+                    "full@vortex.dev" => RecipientVerdict::MailboxFull,
+                    "small@vortex.dev" => RecipientVerdict::AcceptUpTo(10),
+                    _ => RecipientVerdict::Accept,
+                };
+                async move { verdict }
+            },
+            |_| async { panic!("the message shouldn't be delivered") as () },
+        );
+
+        assert!(read(&mut client).await.starts_with("220 "));
+        assert!(reply(&mut client, "EHLO client\r\n")
+            .await
+            .starts_with("250-"));
+        reply(&mut client, "MAIL FROM:<sender@example.com>\r\n").await;
+        assert!(reply(&mut client, "RCPT TO:<full@vortex.dev>\r\n")
+            .await
+            .starts_with("452 4.2.2 "));
+        assert!(reply(&mut client, "RCPT TO:<small@vortex.dev>\r\n")
+            .await
+            .starts_with("250 "));
+        assert!(reply(&mut client, "DATA\r\n").await.starts_with("354 "));
+        assert!(reply(&mut client, "Subject: too long\r\n\r\n.\r\n")
+            .await
+            .starts_with("552 5.2.2 "));
+        assert!(reply(&mut client, "QUIT\r\n").await.starts_with("221 "));
+        assert!(connection.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn declared_size_is_checked_at_rcpt() {
+        let (delivered, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let (mut client, connection) = spawn_with(
+            Config::default(),
+            None,
+            |email, _| {
+                let verdict = match email {
+                    "small@vortex.dev" => RecipientVerdict::AcceptUpTo(10),
+                    _ => RecipientVerdict::AcceptUpTo(1000),
+                };
+                async move { verdict }
+            },
+            move |Event::EmailReceived(email)| {
+                delivered.send(email.rcpt_to).unwrap();
+                async {}
+            },
+        );
+
+        assert!(read(&mut client).await.starts_with("220 "));
+        assert!(reply(&mut client, "EHLO client\r\n")
+            .await
+            .starts_with("250-"));
+        let too_big = format!(
+            "MAIL FROM:<sender@example.com> SIZE={}\r\n",
+            consts::MAX_SIZE + 1
+        );
+        assert!(reply(&mut client, &too_big).await.starts_with("552 "));
+        assert!(
+            reply(&mut client, "MAIL FROM:<sender@example.com> SIZE=100\r\n")
+                .await
+                .starts_with("250 ")
+        );
+        assert!(reply(&mut client, "RCPT TO:<small@vortex.dev>\r\n")
+            .await
+            .starts_with("552 5.2.2 "));
+        assert!(reply(&mut client, "RCPT TO:<big@vortex.dev>\r\n")
+            .await
+            .starts_with("250 "));
+        assert!(reply(&mut client, "DATA\r\n").await.starts_with("354 "));
+        assert!(reply(&mut client, "Subject: hi\r\n\r\n.\r\n")
+            .await
+            .starts_with("250 "));
+        assert_eq!(received.recv().await.unwrap(), ["big@vortex.dev"]);
+        assert!(reply(&mut client, "QUIT\r\n").await.starts_with("221 "));
+        assert!(connection.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn auth_needs_starttls() {
+        use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = CertificateDer::from(certified.cert.der().to_vec());
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+            certified.signing_key.serialize_der(),
+        ));
+        let server_tls = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_tls = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let (mut client, connection) = spawn(Config {
+            auth: AuthMode::Required,
+            starttls: Some(Arc::new(server_tls)),
+            tarpit: Some(Tarpit {
+                disconnect_after: 2,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        assert!(read(&mut client).await.starts_with("220 "));
+        let ehlo = reply(&mut client, "EHLO client\r\n").await;
+        assert!(ehlo.contains("STARTTLS"));
+        assert!(!ehlo.contains("AUTH"));
+        assert!(reply(&mut client, "AUTH PLAIN AHVzZXIAaHVudGVyMg==\r\n")
+            .await
+            .starts_with("538 5.7.11 "));
+        assert!(reply(&mut client, "MAIL FROM:<user@example.com>\r\n")
+            .await
+            .starts_with("530 5.7.0 "));
+        assert!(reply(&mut client, "STARTTLS\r\n")
+            .await
+            .starts_with("220 2.0.0 "));
+
+        let mut client = tokio_rustls::TlsConnector::from(Arc::new(client_tls))
+            .connect("localhost".try_into().unwrap(), client)
+            .await
+            .unwrap();
+        let ehlo = reply(&mut client, "EHLO client\r\n").await;
+        assert!(ehlo.contains("AUTH PLAIN LOGIN"));
+        assert!(!ehlo.contains("STARTTLS"));
+        // Wrong passwords count towards the tarpit.
+        assert!(reply(&mut client, "AUTH PLAIN AHVzZXIAaHVudGVyMg==\r\n")
+            .await
+            .starts_with("535 "));
+        assert!(reply(&mut client, "AUTH PLAIN AHVzZXIAaHVudGVyMg==\r\n")
+            .await
+            .starts_with("421 4.7.0 "));
+        assert!(connection.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn lmtp_replies_once_per_recipient() {
+        let config = Config {
+            protocol: Protocol::Lmtp,
+            ..Default::default()
+        };
+        let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let (mut client, connection) = spawn_with(
+            config,
+            None,
+            |email, _| {
+                let verdict = match email {
+                    "small@vortex.dev" => RecipientVerdict::AcceptUpTo(10),
+                    _ => RecipientVerdict::Accept,
+                };
+                async move { verdict }
+            },
+            move |Event::EmailReceived(email)| {
+                sender.send(email.rcpt_to).unwrap();
+                async {
+                    Delivery {
+                        failed: vec!["broken@vortex.dev".to_string()],
+                    }
                 }
-                _ => {}
+            },
+        );
+
+        assert!(read(&mut client).await.starts_with("220 "));
+        assert!(reply(&mut client, "LHLO client\r\n")
+            .await
+            .starts_with("250-"));
+        reply(&mut client, "MAIL FROM:<sender@example.com>\r\n").await;
+        for recipient in ["ok", "broken", "small"] {
+            let line = format!("RCPT TO:<{recipient}@vortex.dev>\r\n");
+            assert!(reply(&mut client, &line).await.starts_with("250 "));
+        }
+        reply(&mut client, "DATA\r\n").await;
+        assert_eq!(
+            reply(&mut client, "Subject: too long\r\n\r\n.\r\n").await,
+            concat!(
+                "250 <ok@vortex.dev> OK\r\n",
+                "451 4.3.0 Local error in processing, try again later\r\n",
+                "552 5.2.2 Message is too large for the recipient's mailbox\r\n",
+            )
+        );
+        assert_eq!(
+            received.recv().await.unwrap(),
+            ["ok@vortex.dev", "broken@vortex.dev"]
+        );
+
+        // Too big for anyone: both recipients get told.
+        reply(&mut client, "MAIL FROM:<sender@example.com>\r\n").await;
+        reply(&mut client, "RCPT TO:<ok@vortex.dev>\r\n").await;
+        reply(&mut client, "RCPT TO:<broken@vortex.dev>\r\n").await;
+        reply(&mut client, "DATA\r\n").await;
+        client
+            .write_all(&vec![b'a'; consts::MAX_SIZE + 1])
+            .await
+            .unwrap();
+        let too_large = "552 Message size exceeds fixed maximum message size\r\n";
+        assert_eq!(reply(&mut client, "\r\n.\r\n").await, too_large.repeat(2));
+
+        assert!(reply(&mut client, "QUIT\r\n").await.starts_with("221 "));
+        assert!(connection.await.unwrap().is_ok());
+        assert!(received.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn vrfy_without_an_argument_is_a_syntax_error() {
+        let (mut client, connection) = spawn(Config::default());
+        assert!(read(&mut client).await.starts_with("220 "));
+        for command in ["VRFY\r\n", "EXPN\r\n"] {
+            assert_eq!(
+                reply(&mut client, command).await,
+                "501 Syntax error in parameters or arguments\r\n"
+            );
+        }
+        assert!(reply(&mut client, "QUIT\r\n").await.starts_with("221 "));
+        assert!(connection.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn milter_tempfail_replaces_the_greeting() {
+        // Nobody's listening, and by default that means try again later.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = milter::MilterAddress::Tcp(listener.local_addr().unwrap().to_string());
+        drop(listener);
+
+        let config = Config {
+            milters: vec![Milter::new(address)],
+            ..Config::default()
+        };
+        let (result, output) = run(config).await;
+        assert!(result.is_ok());
+        assert_eq!(
+            output,
+            "421 4.7.0 mail.vortex.skyfall.dev Service unavailable, try again later\r\n"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_client_gets_421() {
+        let (result, output) = run(Config::default()).await;
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert!(output.starts_with("220 "));
+        assert!(output
+            .ends_with("\r\n421 4.4.2 mail.vortex.skyfall.dev Timeout, closing connection\r\n"));
+    }
+
+    /// Gets a client as far as the `354`, then has it send `chunk` every `interval`
+    /// until the server hangs up. Returns everything the server sent, and how long
+    /// that took from the `354`.
+    async fn stall_in_data(
+        chunk: &'static [u8],
+        interval: Duration,
+    ) -> (Result<(), Error>, String, Duration) {
+        let (mut client, connection) = spawn(Config::default());
+        let mut output = read(&mut client).await;
+        for line in [
+            "EHLO client\r\n",
+            "MAIL FROM:<sender@example.com>\r\n",
+            "RCPT TO:<alice@vortex.dev>\r\n",
+            "DATA\r\n",
+        ] {
+            output += &reply(&mut client, line).await;
+        }
+        assert!(output.ends_with("354 End data with <CR><LF>.<CR><LF>\r\n"));
+
+        let start = Instant::now();
+        let (mut reader, mut writer) = tokio::io::split(client);
+        tokio::spawn(async move {
+            while writer.write_all(chunk).await.is_ok() {
+                tokio::time::sleep(interval).await;
             }
         });
+        reader.read_to_string(&mut output).await.unwrap();
+        (connection.await.unwrap(), output, start.elapsed())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_data_gets_421() {
+        // One block, then nothing: the data block timeout (3 minutes) kicks in.
+        let (result, output, elapsed) =
+            stall_in_data(b"Subject: hi\r\n", Duration::from_secs(60 * 60)).await;
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert!(
+            output.ends_with("421 4.4.2 mail.vortex.skyfall.dev Timeout, closing connection\r\n")
+        );
+        assert_eq!(elapsed.as_secs(), 3 * 60);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn endless_data_gets_421() {
+        // A block every 2 minutes never trips the block timeout, but the whole
+        // message has to be in within 10.
+        let (result, output, elapsed) =
+            stall_in_data(b"more\r\n", Duration::from_secs(2 * 60)).await;
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert!(
+            output.ends_with("421 4.4.2 mail.vortex.skyfall.dev Timeout, closing connection\r\n")
+        );
+        assert_eq!(elapsed.as_secs(), 10 * 60);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_that_never_reads_times_out() {
+        // Room for the greeting, but not for the HELP reply after it.
+        let (mut client, server) = tokio::io::duplex(messages::GREETING.len());
+        let config = Config::default();
+        client.write_all(b"HELP\r\n").await.unwrap();
+
+        let start = Instant::now();
+        let result = serve_connection(
+            server,
+            None,
+            &config,
+            |_, _| async { true },
+            |_, _| async { false },
+            |_| async {},
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Timeout)));
+        // There's no point sending a 421 to someone who isn't reading.
+        assert_eq!(start.elapsed(), config.timeouts.command);
+        drop(client);
     }
 }
@@ -1,34 +1,220 @@
+use std::fmt::{self, Display};
+
 use const_format::formatcp;
 
 pub const GREETING: &[u8] = formatcp!(
-    "220 mail.vortex.skyfall.dev ESMTP VortexSMTP(v{})\n",
+    "220 mail.vortex.skyfall.dev ESMTP VortexSMTP(v{})\r\n",
+    env!("CARGO_PKG_VERSION")
+)
+.as_bytes();
+pub const LMTP_GREETING: &[u8] = formatcp!(
+    "220 mail.vortex.skyfall.dev LMTP VortexSMTP(v{})\r\n",
     env!("CARGO_PKG_VERSION")
 )
 .as_bytes();
-pub const BAD_COMMAND_SEQUENCE: &[u8] = b"503 Bad sequence of commands\n";
-pub const OK: &[u8] = b"250 OK\n";
-pub const DATA_RESPONSE: &[u8] = b"354 End data with <CR><LF>.<CR><LF>\n";
-pub const HELP_RESPONSE: &[u8] =
-    b"214-go check out https://datatracker.ietf.org/doc/html/rfc5321\n";
-pub const UNRECOGNIZED_COMMAND: &[u8] = b"500 Unrecognized command\n";
-pub const USER_UNKNOWN: &[u8] = b"550 User unknown\n";
-pub const MESSAGE_TOO_LARGE: &[u8] = b"552 Message size exceeds fixed maximum message size\n";
-pub const BYE: &[u8] = b"221 Bye\n";
+pub const BAD_COMMAND_SEQUENCE: &[u8] = b"503 Bad sequence of commands\r\n";
+pub const OK: &[u8] = b"250 OK\r\n";
+pub const DATA_RESPONSE: &[u8] = b"354 End data with <CR><LF>.<CR><LF>\r\n";
+pub const UNRECOGNIZED_COMMAND: &[u8] = b"500 Unrecognized command\r\n";
+pub const USER_UNKNOWN: &[u8] = b"550 User unknown\r\n";
+pub const MAILBOX_FULL: &[u8] = b"452 4.2.2 Mailbox full, try again later\r\n";
+pub const OVER_QUOTA: &[u8] = b"552 5.2.2 Message is too large for the recipient's mailbox\r\n";
+pub const MESSAGE_TOO_LARGE: &[u8] = b"552 Message size exceeds fixed maximum message size\r\n";
+pub const LOCAL_ERROR: &[u8] = b"451 4.3.0 Local error in processing, try again later\r\n";
+pub const BYE: &[u8] = b"221 Bye\r\n";
+pub const TOO_MANY_ERRORS: &[u8] =
+    b"421 4.7.0 mail.vortex.skyfall.dev Too many errors, closing connection\r\n";
+pub const CONNECTION_REFUSED: &[u8] = b"554 5.7.1 mail.vortex.skyfall.dev Connection refused\r\n";
+pub const SERVICE_UNAVAILABLE: &[u8] =
+    b"421 4.7.0 mail.vortex.skyfall.dev Service unavailable, try again later\r\n";
+pub const TIMEOUT: &[u8] = b"421 4.4.2 mail.vortex.skyfall.dev Timeout, closing connection\r\n";
+pub const NOT_IMPLEMENTED: &[u8] = b"502 Command not implemented\r\n";
+pub const CANNOT_VRFY: &[u8] =
+    b"252 Cannot VRFY user, but will accept message and attempt delivery\r\n";
+pub const EXPN_NOT_SUPPORTED: &[u8] = b"502 EXPN not supported\r\n";
+pub const NOT_A_LIST: &[u8] = b"550 That is a mailbox, not a mailing list\r\n";
+pub const AUTH_SUCCESSFUL: &[u8] = b"235 Authentication successful\r\n";
+pub const AUTH_REQUIRED: &[u8] = b"530 Authentication required\r\n";
+pub const AUTH_FAILED: &[u8] = b"535 Authentication credentials invalid\r\n";
+pub const AUTH_MECHANISM_UNSUPPORTED: &[u8] = b"504 Unrecognized authentication type\r\n";
+pub const AUTH_CANCELLED: &[u8] = b"501 Authentication cancelled\r\n";
+pub const AUTH_MALFORMED: &[u8] = b"501 Malformed authentication response\r\n";
+pub const ENCRYPTION_REQUIRED: &[u8] =
+    b"538 5.7.11 Encryption required for requested authentication mechanism\r\n";
+pub const STARTTLS_REQUIRED: &[u8] = b"530 5.7.0 Must issue a STARTTLS command first\r\n";
+pub const READY_FOR_TLS: &[u8] = b"220 2.0.0 Ready to start TLS\r\n";
+pub const SYNTAX_ERROR: &[u8] = b"501 Syntax error in parameters or arguments\r\n";
+pub const UNKNOWN_HELP_TOPIC: &[u8] = b"504 HELP topic unknown\r\n";
+pub const INVALID_DSN_PARAMETERS: &[u8] = b"501 5.5.4 Invalid DSN parameters\r\n";
+
+/// A reply to send to the client, which can span several lines.
+///
+/// RFC 5321 section 4.2.1 says every line of a multi-line reply but the last is
+/// `<code>-<text>`, and the last one is `<code> <text>`. Get that wrong and clients
+/// sit there waiting for a last line that never comes, so build them with this.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    code: u16,
+    lines: Vec<String>,
+}
+
+impl Reply {
+    pub fn new(code: u16, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            lines: vec![text.into()],
+        }
+    }
+
+    #[must_use]
+    pub fn line(mut self, text: impl Into<String>) -> Self {
+        self.lines.push(text.into());
+        self
+    }
+
+    pub const fn code(&self) -> u16 {
+        self.code
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+}
+
+impl Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let last = self.lines.len() - 1;
+        for (i, line) in self.lines.iter().enumerate() {
+            let separator = if i == last { ' ' } else { '-' };
+            // A stray line break would end the reply early.
+            let line = line.replace(['\r', '\n'], " ");
+            write!(f, "{}{separator}{line}\r\n", self.code)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every command we understand, and what to say about it for `HELP <command>`.
+const HELP_TOPICS: &[(&str, &str)] = &[
+    ("HELO", "HELO <domain> - introduce yourself"),
+    (
+        "EHLO",
+        "EHLO <domain> - introduce yourself and list extensions",
+    ),
+    (
+        "LHLO",
+        "LHLO <domain> - introduce yourself to an LMTP server",
+    ),
+    ("STARTTLS", "STARTTLS - switch the connection to TLS"),
+    (
+        "AUTH",
+        "AUTH <mechanism> [initial-response] - authenticate (PLAIN or LOGIN)",
+    ),
+    ("MAIL", "MAIL FROM:<sender> - start a mail transaction"),
+    ("RCPT", "RCPT TO:<recipient> - add a recipient"),
+    (
+        "DATA",
+        "DATA - send the message, ending with <CR><LF>.<CR><LF>",
+    ),
+    ("RSET", "RSET - abort the current mail transaction"),
+    ("VRFY", "VRFY <address> - check whether a mailbox exists"),
+    ("EXPN", "EXPN <list> - expand a mailing list"),
+    ("NOOP", "NOOP - do nothing"),
+    ("HELP", "HELP [command] - show this help"),
+    ("QUIT", "QUIT - close the connection"),
+];
 
 pub fn helo_response(hostname: &str) -> String {
-    format!("250-mail.vortex.skyfall.dev ready when you are, {hostname}\n")
+    format!("250 mail.vortex.skyfall.dev ready when you are, {hostname}\r\n")
+}
+
+pub fn ehlo_response(hostname: &str, extensions: &[&str]) -> Reply {
+    extensions.iter().fold(
+        Reply::new(
+            250,
+            format!("mail.vortex.skyfall.dev ready when you are, {hostname}"),
+        ),
+        |reply, ext| reply.line(*ext),
+    )
+}
+
+/// Lists `commands`, which should be the ones the client can actually use.
+pub fn help_response(commands: &[&str]) -> Reply {
+    Reply::new(214, "Commands supported:")
+        .line(format!("  {}", commands.join(" ")))
+        .line("For more info use \"HELP <command>\"")
+}
+
+/// Describes one of `commands`, or returns `None` if it isn't one of them.
+pub fn help_topic(commands: &[&str], topic: &str) -> Option<Reply> {
+    let topic = topic.to_uppercase();
+    if !commands.contains(&topic.as_str()) {
+        return None;
+    }
+
+    HELP_TOPICS
+        .iter()
+        .find(|(command, _)| *command == topic)
+        .map(|(_, description)| Reply::new(214, *description))
+}
+
+pub fn vrfy_response(address: &str) -> String {
+    format!("250 <{address}>\r\n")
+}
+
+pub fn lmtp_delivered(recipient: &str) -> String {
+    format!("250 <{recipient}> OK\r\n")
+}
+
+pub fn auth_challenge(challenge: &str) -> String {
+    format!("334 {challenge}\r\n")
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Command<'a> {
-    Helo { fqdn: &'a str },
-    Ehlo { fqdn: &'a str },
+    Helo {
+        fqdn: &'a str,
+    },
+    Ehlo {
+        fqdn: &'a str,
+    },
+    Lhlo {
+        fqdn: &'a str,
+    },
 
-    MailFrom { email: &'a str },
-    RcptTo { email: &'a str },
+    MailFrom {
+        email: &'a str,
+        /// Everything after the address, like `SIZE=1000` or `RET=HDRS`.
+        parameters: Vec<&'a str>,
+    },
+    RcptTo {
+        email: &'a str,
+        parameters: Vec<&'a str>,
+    },
     Data,
 
-    Help,
+    StartTls,
+    Auth {
+        mechanism: &'a str,
+        initial_response: Option<&'a str>,
+    },
+
+    /// `address` is `None` if the client didn't say what to verify, which gets a `501`
+    /// rather than a `500`, as VRFY on its own is a command we know.
+    Vrfy {
+        address: Option<&'a str>,
+    },
+    Expn {
+        address: Option<&'a str>,
+    },
+
+    Help {
+        topic: Option<&'a str>,
+    },
     NoOp,
     Rset,
     Quit,
@@ -44,6 +230,7 @@ impl<'a> Command<'a> {
         match cmd {
             "HELO" => Some(Self::Helo { fqdn: msg.get(1)? }),
             "EHLO" => Some(Self::Ehlo { fqdn: msg.get(1)? }),
+            "LHLO" => Some(Self::Lhlo { fqdn: msg.get(1)? }),
 
             "MAIL" => {
                 let arg = msg.get(1)?.to_uppercase();
@@ -54,7 +241,10 @@ impl<'a> Command<'a> {
                         // Extract the substring between the < and >
                         let email = &arg[start + 1..end];
                         if !email.is_empty() {
-                            return Some(Self::MailFrom { email });
+                            return Some(Self::MailFrom {
+                                email,
+                                parameters: msg[2..].to_vec(),
+                            });
                         }
                     }
                 }
@@ -70,7 +260,10 @@ impl<'a> Command<'a> {
                         // Extract the substring between the < and >
                         let email = &arg[start + 1..end];
                         if !email.is_empty() {
-                            return Some(Self::RcptTo { email });
+                            return Some(Self::RcptTo {
+                                email,
+                                parameters: msg[2..].to_vec(),
+                            });
                         }
                     }
                 }
@@ -80,7 +273,24 @@ impl<'a> Command<'a> {
 
             "DATA" => Some(Self::Data),
 
-            "HELP" => Some(Self::Help),
+            // Anything pipelined after STARTTLS would be read in plaintext, so we
+            // don't accept it at all (RFC 3207 section 5).
+            "STARTTLS" => (msg.len() == 1).then_some(Self::StartTls),
+            "AUTH" => Some(Self::Auth {
+                mechanism: msg.get(1)?,
+                initial_response: msg.get(2).copied(),
+            }),
+
+            "VRFY" => Some(Self::Vrfy {
+                address: msg.get(1).map(|address| strip_angle_brackets(address)),
+            }),
+            "EXPN" => Some(Self::Expn {
+                address: msg.get(1).map(|address| strip_angle_brackets(address)),
+            }),
+
+            "HELP" => Some(Self::Help {
+                topic: msg.get(1).copied(),
+            }),
             "NOOP" => Some(Self::NoOp),
             "RSET" => Some(Self::Rset),
             "QUIT" => Some(Self::Quit),
@@ -92,6 +302,12 @@ impl<'a> Command<'a> {
     }
 }
 
+fn strip_angle_brackets(arg: &str) -> &str {
+    arg.strip_prefix('<')
+        .and_then(|arg| arg.strip_suffix('>'))
+        .unwrap_or(arg)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -116,12 +332,30 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_lhlo() {
+        assert_eq!(
+            Command::from_smtp_message("LHLO mx.example.org"),
+            Some(Command::Lhlo {
+                fqdn: "mx.example.org"
+            })
+        );
+    }
+
     #[test]
     fn test_mail_from() {
         assert_eq!(
             Command::from_smtp_message("MAIL FROM:<test@skyfall.com>"),
             Some(Command::MailFrom {
-                email: "test@skyfall.com"
+                email: "test@skyfall.com",
+                parameters: vec![],
+            })
+        );
+        assert_eq!(
+            Command::from_smtp_message("MAIL FROM:<test@skyfall.com> RET=HDRS ENVID=abc"),
+            Some(Command::MailFrom {
+                email: "test@skyfall.com",
+                parameters: vec!["RET=HDRS", "ENVID=abc"],
             })
         );
         assert_eq!(Command::from_smtp_message("MAIL FROM:<"), None);
@@ -133,7 +367,15 @@ mod tests {
         assert_eq!(
             Command::from_smtp_message("RCPT TO:<test@skyfall.com>"),
             Some(Command::RcptTo {
-                email: "test@skyfall.com"
+                email: "test@skyfall.com",
+                parameters: vec![],
+            })
+        );
+        assert_eq!(
+            Command::from_smtp_message("RCPT TO:<test@skyfall.com> NOTIFY=NEVER"),
+            Some(Command::RcptTo {
+                email: "test@skyfall.com",
+                parameters: vec!["NOTIFY=NEVER"],
             })
         );
         assert_eq!(Command::from_smtp_message("RCPT TO:<"), None);
@@ -142,20 +384,25 @@ mod tests {
 
     #[test]
     fn case_insensitive_commands() {
-        assert_eq!(Command::from_smtp_message("help"), Some(Command::Help));
+        assert_eq!(
+            Command::from_smtp_message("help"),
+            Some(Command::Help { topic: None })
+        );
     }
     #[test]
     fn command_argument_casing_is_kept() {
         assert_eq!(
             Command::from_smtp_message("MAIL FROM:<Test@test.com>"),
             Some(Command::MailFrom {
-                email: "Test@test.com"
+                email: "Test@test.com",
+                parameters: vec![],
             })
         );
         assert_eq!(
             Command::from_smtp_message("RCPT TO:<Test@test.com>"),
             Some(Command::RcptTo {
-                email: "Test@test.com"
+                email: "Test@test.com",
+                parameters: vec![],
             })
         );
         assert_eq!(
@@ -164,6 +411,67 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_auth() {
+        assert_eq!(
+            Command::from_smtp_message("AUTH PLAIN AGFsaWNlAGh1bnRlcjI="),
+            Some(Command::Auth {
+                mechanism: "PLAIN",
+                initial_response: Some("AGFsaWNlAGh1bnRlcjI=")
+            })
+        );
+        assert_eq!(
+            Command::from_smtp_message("AUTH LOGIN"),
+            Some(Command::Auth {
+                mechanism: "LOGIN",
+                initial_response: None
+            })
+        );
+        assert_eq!(Command::from_smtp_message("AUTH"), None);
+    }
+
+    #[test]
+    fn test_starttls() {
+        assert_eq!(
+            Command::from_smtp_message("starttls"),
+            Some(Command::StartTls)
+        );
+        assert_eq!(
+            Command::from_smtp_message("STARTTLS\r\nMAIL FROM:<evil@example.com>"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_vrfy() {
+        assert_eq!(
+            Command::from_smtp_message("VRFY <test@skyfall.com>"),
+            Some(Command::Vrfy {
+                address: Some("test@skyfall.com")
+            })
+        );
+        assert_eq!(
+            Command::from_smtp_message("vrfy test@skyfall.com"),
+            Some(Command::Vrfy {
+                address: Some("test@skyfall.com")
+            })
+        );
+        assert_eq!(
+            Command::from_smtp_message("VRFY"),
+            Some(Command::Vrfy { address: None })
+        );
+    }
+
+    #[test]
+    fn test_expn() {
+        assert_eq!(
+            Command::from_smtp_message("EXPN staff"),
+            Some(Command::Expn {
+                address: Some("staff")
+            })
+        );
+    }
+
     // Miscellaneous. Will probably never fail lol (famous last words)
     #[test]
     fn test_data() {
@@ -171,7 +479,16 @@ mod tests {
     }
     #[test]
     fn test_help() {
-        assert_eq!(Command::from_smtp_message("HELP"), Some(Command::Help));
+        assert_eq!(
+            Command::from_smtp_message("HELP"),
+            Some(Command::Help { topic: None })
+        );
+        assert_eq!(
+            Command::from_smtp_message("HELP mail"),
+            Some(Command::Help {
+                topic: Some("mail")
+            })
+        );
     }
     #[test]
     fn test_noop() {
@@ -185,4 +502,42 @@ mod tests {
     fn test_quit() {
         assert_eq!(Command::from_smtp_message("QUIT"), Some(Command::Quit));
     }
+
+    #[test]
+    fn single_line_reply() {
+        assert_eq!(Reply::new(250, "OK").to_string(), "250 OK\r\n");
+    }
+
+    #[test]
+    fn multi_line_reply() {
+        let reply = Reply::new(250, "mail.example.org")
+            .line("SIZE 100")
+            .line("SMTPUTF8");
+        assert_eq!(
+            reply.to_string(),
+            "250-mail.example.org\r\n250-SIZE 100\r\n250 SMTPUTF8\r\n"
+        );
+    }
+
+    #[test]
+    fn reply_lines_cant_smuggle_line_breaks() {
+        assert_eq!(
+            Reply::new(250, "a\r\n250 b").to_string(),
+            "250 a  250 b\r\n"
+        );
+    }
+
+    #[test]
+    fn help_lists_commands_and_topics() {
+        let reply = help_response(&["HELO", "QUIT"]).to_string();
+        assert!(reply.starts_with("214-"));
+        assert!(reply.contains("HELO QUIT"));
+        assert!(reply.lines().last().unwrap().starts_with("214 "));
+
+        assert_eq!(
+            help_topic(&["HELO", "QUIT"], "quit"),
+            Some(Reply::new(214, "QUIT - close the connection"))
+        );
+        assert_eq!(help_topic(&["HELO", "QUIT"], "AUTH"), None);
+    }
 }
@@ -0,0 +1,774 @@
+//! A client for the Sendmail/Postfix milter protocol, so external filters like
+//! rspamd or clamav-milter can accept, reject or tag mail while we receive it.
+//!
+//! We speak protocol version 6, but only offer the milter the "add header" action.
+//! Other modifications it asks for (changing headers, recipients or the body) are
+//! ignored. See <https://github.com/emersion/go-milter/blob/master/milter-protocol.txt>
+//! for a description of the protocol.
+
+use std::fmt::{self, Display};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::time::{timeout, Duration};
+
+const VERSION: u32 = 6;
+/// The only action we'll carry out: adding (or inserting) headers.
+const SMFIF_ADDHDRS: u32 = 0x01;
+
+// Steps the milter may ask us to skip.
+const SMFIP_NOCONNECT: u32 = 0x01;
+const SMFIP_NOHELO: u32 = 0x02;
+const SMFIP_NOMAIL: u32 = 0x04;
+const SMFIP_NORCPT: u32 = 0x08;
+const SMFIP_NOBODY: u32 = 0x10;
+const SMFIP_NOHDRS: u32 = 0x20;
+const SMFIP_NOEOH: u32 = 0x40;
+const SMFIP_NOUNKNOWN: u32 = 0x100;
+const SMFIP_NODATA: u32 = 0x200;
+/// We understand the `s` (skip the rest of the body) reply.
+const SMFIP_SKIP: u32 = 0x400;
+const PROTOCOL: u32 = SMFIP_NOCONNECT
+    | SMFIP_NOHELO
+    | SMFIP_NOMAIL
+    | SMFIP_NORCPT
+    | SMFIP_NOBODY
+    | SMFIP_NOHDRS
+    | SMFIP_NOEOH
+    | SMFIP_NOUNKNOWN
+    | SMFIP_NODATA
+    | SMFIP_SKIP;
+
+/// Body chunks can't be longer than this.
+const MAX_CHUNK: usize = 65535;
+/// Packets from the milter longer than this are refused.
+const MAX_PACKET: usize = 1024 * 1024;
+
+const DEFAULT_REJECT: &[u8] = b"550 5.7.1 Command rejected\r\n";
+const DEFAULT_TEMPFAIL: &[u8] = b"451 4.7.1 Service unavailable - try again later\r\n";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("milter network error: {0}")]
+    NetworkError(#[from] std::io::Error),
+
+    #[error("milter timed out")]
+    Timeout,
+
+    #[error("milter protocol error: {0}")]
+    Protocol(&'static str),
+}
+
+/// Where a milter listens, in Postfix's syntax: `inet:host:port` or `unix:/path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MilterAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for MilterAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("inet", address)) if address.contains(':') => Ok(Self::Tcp(address.to_string())),
+            Some(("unix", path)) if path.starts_with('/') => Ok(Self::Unix(PathBuf::from(path))),
+            _ => Err(format!(
+                "milter address {s:?} must look like inet:host:port or unix:/path"
+            )),
+        }
+    }
+}
+
+impl Display for MilterAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "inet:{address}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// What to do with mail when a milter can't be reached or misbehaves.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DefaultAction {
+    /// Carry on as if the milter wasn't there.
+    Accept,
+    /// Tell the client to try again later, like Postfix does by default.
+    #[default]
+    TempFail,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Milter {
+    pub address: MilterAddress,
+    pub default_action: DefaultAction,
+    /// How long we wait for any one reply.
+    pub timeout: Duration,
+}
+
+impl Milter {
+    pub fn new(address: MilterAddress) -> Self {
+        Self {
+            address,
+            default_action: DefaultAction::default(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// What the milters decided about a stage of the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Continue,
+    /// Accept the message, but throw it away.
+    Discard,
+    /// Refuse with this (complete, CRLF-terminated) reply.
+    Reject(Vec<u8>),
+    /// Refuse for now with this reply.
+    TempFail(Vec<u8>),
+}
+
+/// A header a milter wants added to the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderChange {
+    /// Where among the existing headers it goes, or `None` for after all of them.
+    pub index: Option<usize>,
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Response {
+    Continue,
+    Accept,
+    Skip,
+    Verdict(Verdict),
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+struct Session {
+    milter: Milter,
+    stream: Box<dyn Stream>,
+    /// The steps the milter asked us to skip.
+    skip: u32,
+    /// The milter accepted the whole connection, so we don't ask it anything more.
+    accepted_connection: bool,
+    /// The milter accepted the current message.
+    accepted_message: bool,
+    /// Something went wrong, and we've given up on this milter.
+    failed: bool,
+}
+
+impl Session {
+    async fn connect(milter: &Milter) -> Result<Self, Error> {
+        let stream: Box<dyn Stream> = match &milter.address {
+            MilterAddress::Tcp(address) => Box::new(
+                timeout(milter.timeout, TcpStream::connect(address))
+                    .await
+                    .map_err(|_| Error::Timeout)??,
+            ),
+            #[cfg(unix)]
+            MilterAddress::Unix(path) => Box::new(
+                timeout(milter.timeout, UnixStream::connect(path))
+                    .await
+                    .map_err(|_| Error::Timeout)??,
+            ),
+            #[cfg(not(unix))]
+            MilterAddress::Unix(_) => {
+                return Err(Error::Protocol("Unix sockets aren't supported here"))
+            }
+        };
+
+        let mut session = Self {
+            milter: milter.clone(),
+            stream,
+            skip: 0,
+            accepted_connection: false,
+            accepted_message: false,
+            failed: false,
+        };
+
+        let mut options = Vec::with_capacity(12);
+        options.extend_from_slice(&VERSION.to_be_bytes());
+        options.extend_from_slice(&SMFIF_ADDHDRS.to_be_bytes());
+        options.extend_from_slice(&PROTOCOL.to_be_bytes());
+        session.send(b'O', &options).await?;
+
+        let (command, data) = session.read_packet().await?;
+        if command != b'O' || data.len() < 12 {
+            return Err(Error::Protocol("bad option negotiation reply"));
+        }
+        let version = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        if version < 2 {
+            return Err(Error::Protocol("unsupported milter version"));
+        }
+        // Only the steps we offered to skip, and never the "no reply" ones.
+        session.skip = u32::from_be_bytes([data[8], data[9], data[10], data[11]]) & PROTOCOL;
+
+        Ok(session)
+    }
+
+    async fn send(&mut self, command: u8, data: &[u8]) -> Result<(), Error> {
+        let length =
+            u32::try_from(data.len() + 1).map_err(|_| Error::Protocol("packet too long"))?;
+        let mut packet = Vec::with_capacity(data.len() + 5);
+        packet.extend_from_slice(&length.to_be_bytes());
+        packet.push(command);
+        packet.extend_from_slice(data);
+
+        timeout(self.milter.timeout, self.stream.write_all(&packet))
+            .await
+            .map_err(|_| Error::Timeout)??;
+        Ok(())
+    }
+
+    async fn read_packet(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        let read = async {
+            let length = self.stream.read_u32().await? as usize;
+            if length == 0 || length > MAX_PACKET {
+                return Err(Error::Protocol("bad packet length"));
+            }
+            let command = self.stream.read_u8().await?;
+            let mut data = vec![0; length - 1];
+            self.stream.read_exact(&mut data).await?;
+            Ok((command, data))
+        };
+        timeout(self.milter.timeout, read)
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    /// Sends a command and waits for the milter's decision, collecting any header
+    /// changes it asks for on the way into `changes`.
+    async fn command(
+        &mut self,
+        command: u8,
+        data: &[u8],
+        changes: &mut Vec<HeaderChange>,
+    ) -> Result<Response, Error> {
+        self.send(command, data).await?;
+        loop {
+            let (reply, data) = self.read_packet().await?;
+            return Ok(match reply {
+                b'c' => Response::Continue,
+                b'a' => Response::Accept,
+                b's' => Response::Skip,
+                b'd' => Response::Verdict(Verdict::Discard),
+                b'r' => Response::Verdict(Verdict::Reject(DEFAULT_REJECT.to_vec())),
+                b't' => Response::Verdict(Verdict::TempFail(DEFAULT_TEMPFAIL.to_vec())),
+                b'y' => Response::Verdict(parse_reply_code(&data)?),
+                // Progress: the milter needs more time.
+                b'p' => continue,
+                b'h' => {
+                    let (name, value) = parse_header(&data)?;
+                    changes.push(HeaderChange {
+                        index: None,
+                        name,
+                        value,
+                    });
+                    continue;
+                }
+                b'i' => {
+                    let index = data
+                        .get(..4)
+                        .ok_or(Error::Protocol("short insert header request"))?;
+                    let index = u32::from_be_bytes([index[0], index[1], index[2], index[3]]);
+                    let (name, value) = parse_header(&data[4..])?;
+                    changes.push(HeaderChange {
+                        index: Some(index as usize),
+                        name,
+                        value,
+                    });
+                    continue;
+                }
+                other => {
+                    // Something we didn't offer, like changing recipients.
+                    tracing::debug!(
+                        milter = %self.milter.address,
+                        "ignoring milter modification {:?}",
+                        other as char
+                    );
+                    continue;
+                }
+            });
+        }
+    }
+}
+
+fn parse_reply_code(data: &[u8]) -> Result<Verdict, Error> {
+    let text = String::from_utf8_lossy(data.strip_suffix(b"\0").unwrap_or(data));
+    let mut reply = text.trim_end().replace("\r\n", "\n").replace('\n', "\r\n");
+    reply.push_str("\r\n");
+    match reply.as_bytes().first() {
+        Some(b'4') => Ok(Verdict::TempFail(reply.into_bytes())),
+        Some(b'5') => Ok(Verdict::Reject(reply.into_bytes())),
+        _ => Err(Error::Protocol("reply code must be 4xx or 5xx")),
+    }
+}
+
+fn parse_header(data: &[u8]) -> Result<(String, String), Error> {
+    let mut parts = data.split(|&b| b == 0);
+    let (Some(name), Some(value)) = (parts.next(), parts.next()) else {
+        return Err(Error::Protocol("malformed header"));
+    };
+    Ok((
+        String::from_utf8_lossy(name).to_string(),
+        String::from_utf8_lossy(value).to_string(),
+    ))
+}
+
+/// NUL-terminates each of `strings` and glues them together.
+fn strings(strings: &[&str]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for s in strings {
+        data.extend_from_slice(s.as_bytes());
+        data.push(0);
+    }
+    data
+}
+
+/// All the milters one SMTP connection is checked against, in order.
+pub(crate) struct Filters {
+    sessions: Vec<Session>,
+}
+
+impl Filters {
+    /// Connects to every milter and runs the connect stage. A milter we can't reach
+    /// counts as a failure at that stage.
+    pub async fn connect(
+        milters: &[Milter],
+        hostname: &str,
+        remote_addr: Option<SocketAddr>,
+    ) -> (Self, Verdict) {
+        let mut filters = Self {
+            sessions: Vec::with_capacity(milters.len()),
+        };
+        let mut unreachable = Verdict::Continue;
+        for milter in milters {
+            match Session::connect(milter).await {
+                Ok(session) => filters.sessions.push(session),
+                Err(e) => {
+                    tracing::warn!(milter = %milter.address, "couldn't connect to milter: {e}");
+                    if milter.default_action == DefaultAction::TempFail {
+                        unreachable = Verdict::TempFail(DEFAULT_TEMPFAIL.to_vec());
+                    }
+                }
+            }
+        }
+        if unreachable != Verdict::Continue {
+            return (filters, unreachable);
+        }
+
+        let mut data = format!(
+            "{}\0",
+            remote_addr.map_or_else(|| "unknown".to_string(), |addr| format!("[{}]", addr.ip()))
+        )
+        .into_bytes();
+        match remote_addr {
+            Some(addr) => {
+                data.push(if addr.is_ipv4() { b'4' } else { b'6' });
+                data.extend_from_slice(&addr.port().to_be_bytes());
+                data.extend_from_slice(&strings(&[&addr.ip().to_string()]));
+            }
+            None => data.push(b'U'),
+        }
+
+        let macros = strings(&["j", hostname]);
+        let verdict = filters
+            .stage(SMFIP_NOCONNECT, Some((b'C', &macros)), b'C', &data, true)
+            .await;
+        (filters, verdict)
+    }
+
+    pub async fn helo(&mut self, helo: &str) -> Verdict {
+        self.stage(SMFIP_NOHELO, None, b'H', &strings(&[helo]), true)
+            .await
+    }
+
+    pub async fn mail_from(&mut self, sender: &str, authenticated_as: Option<&str>) -> Verdict {
+        let macros = authenticated_as.map(|username| strings(&["{auth_authen}", username]));
+        self.stage(
+            SMFIP_NOMAIL,
+            macros.as_deref().map(|macros| (b'M', macros)),
+            b'M',
+            &strings(&[&format!("<{sender}>")]),
+            false,
+        )
+        .await
+    }
+
+    pub async fn rcpt_to(&mut self, recipient: &str) -> Verdict {
+        self.stage(
+            SMFIP_NORCPT,
+            None,
+            b'R',
+            &strings(&[&format!("<{recipient}>")]),
+            false,
+        )
+        .await
+    }
+
+    /// Sends the message's headers and body, and returns what the milters made of it
+    /// and the headers they want added. Ends the message either way.
+    pub async fn message(&mut self, message: &[u8]) -> (Verdict, Vec<HeaderChange>) {
+        let (headers, body) = split_message(message);
+        let mut changes = Vec::new();
+        let mut verdict = Verdict::Continue;
+
+        for session in &mut self.sessions {
+            if !session.is_active() {
+                continue;
+            }
+            match message_stage(session, &headers, body, &mut changes).await {
+                Ok(Response::Verdict(v)) => {
+                    verdict = v;
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    if let Some(v) = session.give_up(&e) {
+                        verdict = v;
+                        break;
+                    }
+                }
+            }
+        }
+
+        self.end_message();
+        (verdict, changes)
+    }
+
+    /// Tells the milters the current transaction was abandoned, say by RSET.
+    pub async fn abort(&mut self) {
+        for session in &mut self.sessions {
+            if !session.failed && !session.accepted_connection {
+                if let Err(e) = session.send(b'A', &[]).await {
+                    session.give_up(&e);
+                }
+            }
+        }
+        self.end_message();
+    }
+
+    pub async fn quit(&mut self) {
+        for session in &mut self.sessions {
+            if !session.failed {
+                let _ = session.send(b'Q', &[]).await;
+            }
+        }
+    }
+
+    fn end_message(&mut self) {
+        for session in &mut self.sessions {
+            session.accepted_message = false;
+        }
+    }
+
+    /// Sends `command` to every milter that wants it, and stops at the first one that
+    /// doesn't say to carry on. `connection_wide` says an accept covers the whole
+    /// connection rather than just the current message.
+    async fn stage(
+        &mut self,
+        skip: u32,
+        macros: Option<(u8, &[u8])>,
+        command: u8,
+        data: &[u8],
+        connection_wide: bool,
+    ) -> Verdict {
+        for session in &mut self.sessions {
+            if !session.is_active() || session.skip & skip != 0 {
+                continue;
+            }
+
+            let result = async {
+                if let Some((stage, macros)) = macros {
+                    let mut packet = vec![stage];
+                    packet.extend_from_slice(macros);
+                    session.send(b'D', &packet).await?;
+                }
+                session.command(command, data, &mut Vec::new()).await
+            }
+            .await;
+
+            match result {
+                Ok(Response::Continue | Response::Skip) => {}
+                Ok(Response::Accept) if connection_wide => session.accepted_connection = true,
+                Ok(Response::Accept) => session.accepted_message = true,
+                Ok(Response::Verdict(verdict)) => return verdict,
+                Err(e) => {
+                    if let Some(verdict) = session.give_up(&e) {
+                        return verdict;
+                    }
+                }
+            }
+        }
+        Verdict::Continue
+    }
+}
+
+impl Session {
+    const fn is_active(&self) -> bool {
+        !self.failed && !self.accepted_connection && !self.accepted_message
+    }
+
+    /// Stops talking to this milter after `error`, and returns what its default
+    /// action says to do about the current stage.
+    fn give_up(&mut self, error: &Error) -> Option<Verdict> {
+        tracing::warn!(milter = %self.milter.address, "milter failed: {error}");
+        self.failed = true;
+        match self.milter.default_action {
+            DefaultAction::Accept => None,
+            DefaultAction::TempFail => Some(Verdict::TempFail(DEFAULT_TEMPFAIL.to_vec())),
+        }
+    }
+}
+
+async fn message_stage(
+    session: &mut Session,
+    headers: &[(String, String)],
+    body: &[u8],
+    changes: &mut Vec<HeaderChange>,
+) -> Result<Response, Error> {
+    if session.skip & SMFIP_NOHDRS == 0 {
+        for (name, value) in headers {
+            match session
+                .command(b'L', &strings(&[name, value]), changes)
+                .await?
+            {
+                Response::Continue | Response::Skip => {}
+                response => return Ok(response),
+            }
+        }
+    }
+    if session.skip & SMFIP_NOEOH == 0 {
+        match session.command(b'N', &[], changes).await? {
+            Response::Continue | Response::Skip => {}
+            response => return Ok(response),
+        }
+    }
+    if session.skip & SMFIP_NOBODY == 0 {
+        for chunk in body.chunks(MAX_CHUNK) {
+            match session.command(b'B', chunk, changes).await? {
+                Response::Continue => {}
+                Response::Skip => break,
+                response => return Ok(response),
+            }
+        }
+    }
+    session.command(b'E', &[], changes).await
+}
+
+/// Splits a message into its (unfolded-as-sent) headers and its body.
+fn split_message(message: &[u8]) -> (Vec<(String, String)>, &[u8]) {
+    let (header_block, body) = match message.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => (&message[..end], &message[end + 4..]),
+        None => (message, &[][..]),
+    };
+    let header_block = String::from_utf8_lossy(header_block);
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in header_block.split("\r\n") {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push_str("\r\n");
+                value.push_str(line);
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.to_string(), value.trim_start().to_string()));
+        }
+    }
+    (headers, body)
+}
+
+/// Adds the headers the milters asked for to `message`.
+pub(crate) fn apply_changes(message: &mut Vec<u8>, changes: &[HeaderChange]) {
+    for change in changes {
+        let header = format!("{}: {}\r\n", change.name, change.value);
+        let offset = match change.index {
+            Some(index) => header_offset(message, index),
+            None => header_offset(message, usize::MAX),
+        };
+        message.splice(offset..offset, header.into_bytes());
+    }
+}
+
+/// Where the `index`th header starts, or where the headers end if there are fewer.
+fn header_offset(message: &[u8], index: usize) -> usize {
+    let mut offset = 0;
+    let mut seen = 0;
+    while offset < message.len() {
+        let line_end = message[offset..]
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .map_or(message.len(), |end| offset + end + 2);
+        let line = &message[offset..line_end];
+        if line == b"\r\n" {
+            break;
+        }
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            if seen == index {
+                break;
+            }
+            seen += 1;
+        }
+        offset = line_end;
+    }
+    offset
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let length = stream.read_u32().await.ok()? as usize;
+        let command = stream.read_u8().await.ok()?;
+        let mut data = vec![0; length - 1];
+        stream.read_exact(&mut data).await.ok()?;
+        Some((command, data))
+    }
+
+    async fn write_packet(stream: &mut TcpStream, command: u8, data: &[u8]) {
+        stream
+            .write_all(&(data.len() as u32 + 1).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_u8(command).await.unwrap();
+        stream.write_all(data).await.unwrap();
+    }
+
+    /// A milter that rejects `spam@` senders, tempfails `later@` recipients, and tags
+    /// every message it sees with a header. Returns every command it got.
+    async fn mock_milter(listener: TcpListener) -> Vec<u8> {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut commands = Vec::new();
+
+        while let Some((command, data)) = read_packet(&mut stream).await {
+            commands.push(command);
+            let data = String::from_utf8_lossy(&data);
+            match command {
+                b'O' => {
+                    let mut options = Vec::new();
+                    options.extend_from_slice(&6u32.to_be_bytes());
+                    options.extend_from_slice(&SMFIF_ADDHDRS.to_be_bytes());
+                    // We don't care about HELO.
+                    options.extend_from_slice(&SMFIP_NOHELO.to_be_bytes());
+                    write_packet(&mut stream, b'O', &options).await;
+                }
+                b'D' | b'A' => {}
+                b'Q' => break,
+                b'M' if data.starts_with("<spam@") => {
+                    write_packet(&mut stream, b'y', b"550 5.7.1 No spam please\0").await;
+                }
+                b'R' if data.starts_with("<later@") => {
+                    write_packet(&mut stream, b't', &[]).await;
+                }
+                b'B' => {
+                    assert_eq!(data, "Hello!\r\n");
+                    write_packet(&mut stream, b'c', &[]).await;
+                }
+                b'E' => {
+                    write_packet(&mut stream, b'p', &[]).await;
+                    write_packet(&mut stream, b'h', b"X-Spam\0no\0").await;
+                    let mut insert = 0u32.to_be_bytes().to_vec();
+                    insert.extend_from_slice(b"X-First\0yes\0");
+                    write_packet(&mut stream, b'i', &insert).await;
+                    write_packet(&mut stream, b'c', &[]).await;
+                }
+                _ => write_packet(&mut stream, b'c', &[]).await,
+            }
+        }
+        commands
+    }
+
+    #[tokio::test]
+    async fn test_filters_against_mock_milter() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = MilterAddress::Tcp(listener.local_addr().unwrap().to_string());
+        let milter = tokio::spawn(mock_milter(listener));
+
+        let (mut filters, verdict) = Filters::connect(
+            &[Milter::new(address)],
+            "mail.example.org",
+            Some("203.0.113.7:4000".parse().unwrap()),
+        )
+        .await;
+        assert_eq!(verdict, Verdict::Continue);
+        assert_eq!(filters.helo("client.example").await, Verdict::Continue);
+
+        assert_eq!(
+            filters.mail_from("spam@example.com", None).await,
+            Verdict::Reject(b"550 5.7.1 No spam please\r\n".to_vec())
+        );
+        filters.abort().await;
+
+        assert_eq!(
+            filters.mail_from("ham@example.com", None).await,
+            Verdict::Continue
+        );
+        assert_eq!(
+            filters.rcpt_to("later@example.org").await,
+            Verdict::TempFail(DEFAULT_TEMPFAIL.to_vec())
+        );
+        assert_eq!(filters.rcpt_to("now@example.org").await, Verdict::Continue);
+
+        let mut message = b"Subject: hi\r\nFrom: ham@example.com\r\n\r\nHello!\r\n".to_vec();
+        let (verdict, changes) = filters.message(&message).await;
+        assert_eq!(verdict, Verdict::Continue);
+        apply_changes(&mut message, &changes);
+        assert_eq!(
+            String::from_utf8(message).unwrap(),
+            "X-First: yes\r\nSubject: hi\r\nFrom: ham@example.com\r\nX-Spam: no\r\n\r\nHello!\r\n"
+        );
+
+        filters.quit().await;
+        let commands = milter.await.unwrap();
+        assert_eq!(
+            commands,
+            b"ODCMAMRRLLNBEQ",
+            "{:?}",
+            String::from_utf8_lossy(&commands)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_milter() {
+        // Grab a port nobody's listening on.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = MilterAddress::Tcp(listener.local_addr().unwrap().to_string());
+        drop(listener);
+
+        let mut milter = Milter::new(address);
+        let (_, verdict) = Filters::connect(&[milter.clone()], "mail.example.org", None).await;
+        assert!(matches!(verdict, Verdict::TempFail(_)));
+
+        milter.default_action = DefaultAction::Accept;
+        let (_, verdict) = Filters::connect(&[milter], "mail.example.org", None).await;
+        assert_eq!(verdict, Verdict::Continue);
+    }
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(
+            "inet:127.0.0.1:11332".parse(),
+            Ok(MilterAddress::Tcp("127.0.0.1:11332".to_string()))
+        );
+        assert_eq!(
+            "unix:/run/clamav/milter.sock".parse(),
+            Ok(MilterAddress::Unix(PathBuf::from(
+                "/run/clamav/milter.sock"
+            )))
+        );
+        assert!("127.0.0.1:11332".parse::<MilterAddress>().is_err());
+    }
+}
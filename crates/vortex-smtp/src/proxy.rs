@@ -0,0 +1,177 @@
+//! HAProxy PROXY protocol (v1 and v2) parsing, so that we can see the real client
+//! address when we're sitting behind a TCP load balancer.
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::Error;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest possible v1 header, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads a PROXY protocol header from the start of `socket`.
+///
+/// This reads *exactly* the header and nothing more, so whatever comes next
+/// (a TLS ClientHello, say) is left for the caller. Returns `Ok(None)` when the
+/// header is valid but doesn't carry an address, like v2 `LOCAL` health checks
+/// or v1 `UNKNOWN`; the caller should then keep using the socket's own address.
+pub async fn read_header<S>(socket: &mut S) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut start = [0; 12];
+    socket.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        let mut header = [0; 4];
+        socket.read_exact(&mut header).await?;
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut body = vec![0; length];
+        socket.read_exact(&mut body).await?;
+
+        parse_v2(header[0], header[1], &body)
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(Error::InvalidProxyHeader("v1 header too long"));
+            }
+            line.push(socket.read_u8().await?);
+        }
+
+        let line = std::str::from_utf8(&line)
+            .map_err(|_| Error::InvalidProxyHeader("v1 header isn't ASCII"))?;
+        parse_v1(line)
+    } else {
+        Err(Error::InvalidProxyHeader("missing PROXY header"))
+    }
+}
+
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>, Error> {
+    let mut parts = line.trim_end_matches("\r\n").split(' ').skip(1);
+    match parts.next() {
+        Some("UNKNOWN") => return Ok(None),
+        Some("TCP4" | "TCP6") => {}
+        _ => return Err(Error::InvalidProxyHeader("unsupported v1 protocol")),
+    }
+
+    let (Some(source), Some(_destination), Some(source_port), Some(_destination_port), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return Err(Error::InvalidProxyHeader("malformed v1 header"));
+    };
+
+    let ip: IpAddr = source
+        .parse()
+        .map_err(|_| Error::InvalidProxyHeader("invalid v1 source address"))?;
+    let port: u16 = source_port
+        .parse()
+        .map_err(|_| Error::InvalidProxyHeader("invalid v1 source port"))?;
+
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    if version_command >> 4 != 2 {
+        return Err(Error::InvalidProxyHeader("unsupported v2 version"));
+    }
+
+    match version_command & 0x0F {
+        // LOCAL: the proxy is talking to us on its own behalf (health checks).
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => return Err(Error::InvalidProxyHeader("unsupported v2 command")),
+    }
+
+    // The high nibble is the address family, the low one the transport.
+    match family >> 4 {
+        // AF_INET
+        0x1 => {
+            let Some(addresses) = body.get(..12) else {
+                return Err(Error::InvalidProxyHeader("v2 IPv4 header too short"));
+            };
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6
+        0x2 => {
+            let Some(addresses) = body.get(..36) else {
+                return Err(Error::InvalidProxyHeader("v2 IPv6 header too short"));
+            };
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)))
+        }
+        // AF_UNSPEC, AF_UNIX: nothing we can use.
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_v1_tcp4() {
+        let mut input: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 25\r\nEHLO a\r\n";
+        let addr = read_header(&mut input).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.7:51234".parse().unwrap()));
+        // The rest of the stream is left alone.
+        assert_eq!(input, b"EHLO a\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_v1_tcp6() {
+        let mut input: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 25\r\n";
+        let addr = read_header(&mut input).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_v1_unknown() {
+        let mut input: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut input).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_v2_tcp4() {
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        input.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1]);
+        input.extend_from_slice(&51234u16.to_be_bytes());
+        input.extend_from_slice(&25u16.to_be_bytes());
+        input.extend_from_slice(b"\x16\x03\x01"); // start of a TLS ClientHello
+
+        let mut input = input.as_slice();
+        let addr = read_header(&mut input).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(input, b"\x16\x03\x01");
+    }
+
+    #[tokio::test]
+    async fn test_v2_local() {
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut input.as_slice()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_missing_header_is_refused() {
+        let mut input: &[u8] = b"EHLO example.com\r\n";
+        assert!(read_header(&mut input).await.is_err());
+
+        let mut input: &[u8] = b"PROXY TCP4 not-an-ip 10.0.0.1 1 25\r\n";
+        assert!(read_header(&mut input).await.is_err());
+    }
+}
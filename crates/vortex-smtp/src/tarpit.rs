@@ -0,0 +1,157 @@
+//! Slowing down clients that keep getting things wrong.
+//!
+//! Dictionary-attack spammers try lots of random local parts hoping one of them
+//! sticks. Every unknown recipient or unrecognised command makes us wait a little
+//! longer before replying, and once they've made enough of them we hang up.
+//!
+//! Errors are counted per client address, so reconnecting doesn't start the count over.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::time::{Duration, Instant};
+
+/// Past this many addresses, we clear out the ones we've forgotten about.
+const MAX_REMEMBERED: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct Tarpit {
+    /// How many errors a client gets for free, since real clients make typos too.
+    pub free_errors: u32,
+    /// The delay after the first error that isn't free. It doubles with every error
+    /// after that.
+    pub initial_delay: Duration,
+    /// The delay never grows past this.
+    pub max_delay: Duration,
+    /// After this many errors, we reply with a `421` and close the connection.
+    pub disconnect_after: u32,
+    /// How long an address's errors count against it after its last one.
+    pub remember_for: Duration,
+    /// Errors per client address, shared by every connection on the listener.
+    pub history: ErrorHistory,
+}
+
+impl Default for Tarpit {
+    fn default() -> Self {
+        Self {
+            free_errors: 3,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            disconnect_after: 20,
+            remember_for: Duration::from_secs(60 * 60),
+            history: ErrorHistory::default(),
+        }
+    }
+}
+
+impl Tarpit {
+    /// How long to wait before replying to the client's `errors`th error.
+    pub fn delay(&self, errors: u32) -> Duration {
+        let Some(exponent) = errors.checked_sub(self.free_errors + 1) else {
+            return Duration::ZERO;
+        };
+        self.initial_delay
+            .saturating_mul(2u32.saturating_pow(exponent))
+            .min(self.max_delay)
+    }
+
+    pub fn should_disconnect(&self, errors: u32) -> bool {
+        errors >= self.disconnect_after
+    }
+
+    /// How many errors `ip` has made recently, on any connection.
+    pub fn errors(&self, ip: IpAddr) -> u32 {
+        self.history.errors(ip, self.remember_for)
+    }
+
+    /// Counts another error against `ip`, and returns its new total.
+    pub fn record_error(&self, ip: IpAddr) -> u32 {
+        self.history.record(ip, self.remember_for)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ErrorHistory {
+    clients: Arc<Mutex<HashMap<IpAddr, Offences>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Offences {
+    errors: u32,
+    last: Instant,
+}
+
+impl ErrorHistory {
+    fn errors(&self, ip: IpAddr, remember_for: Duration) -> u32 {
+        let clients = self.clients.lock().unwrap();
+        clients
+            .get(&ip)
+            .filter(|offences| offences.last.elapsed() < remember_for)
+            .map_or(0, |offences| offences.errors)
+    }
+
+    fn record(&self, ip: IpAddr, remember_for: Duration) -> u32 {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_REMEMBERED {
+            clients.retain(|_, offences| offences.last.elapsed() < remember_for);
+        }
+
+        let now = Instant::now();
+        let offences = clients.entry(ip).or_insert(Offences {
+            errors: 0,
+            last: now,
+        });
+        if now.duration_since(offences.last) >= remember_for {
+            offences.errors = 0;
+        }
+        offences.errors += 1;
+        offences.last = now;
+        offences.errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_escalates() {
+        let tarpit = Tarpit {
+            free_errors: 2,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            disconnect_after: 10,
+            ..Default::default()
+        };
+
+        let delays: Vec<_> = (1..=8)
+            .map(|errors| tarpit.delay(errors).as_secs())
+            .collect();
+        assert_eq!(delays, [0, 0, 1, 2, 4, 8, 10, 10]);
+        assert_eq!(tarpit.delay(u32::MAX), Duration::from_secs(10));
+
+        assert!(!tarpit.should_disconnect(9));
+        assert!(tarpit.should_disconnect(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_errors_outlive_connections() {
+        let tarpit = Tarpit {
+            remember_for: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert_eq!(tarpit.record_error(ip), 1);
+        // Each connection has its own clone of the config, sharing the history.
+        assert_eq!(tarpit.clone().record_error(ip), 2);
+        assert_eq!(tarpit.errors(ip), 2);
+        assert_eq!(tarpit.errors(other), 0);
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(tarpit.errors(ip), 0);
+        assert_eq!(tarpit.record_error(ip), 1);
+    }
+}
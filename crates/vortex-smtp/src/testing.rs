@@ -0,0 +1,162 @@
+//! An SMTP sink to run inside integration tests.
+//!
+//! [`spawn`] starts a server on an ephemeral localhost port that accepts every
+//! recipient, so each test can have its own and they can all run in parallel.
+//! Point the code under test at [`Sink::addr`], then check what arrived with
+//! [`Sink::recv`] or [`Sink::wait_for`].
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Duration, Instant};
+
+use crate::event::Event;
+use crate::{Config, Email, Error};
+
+/// A running test server. It's shut down when this is dropped.
+#[derive(Debug)]
+pub struct Sink {
+    addr: SocketAddr,
+    emails: mpsc::UnboundedReceiver<Email>,
+    /// Emails [`Sink::wait_for`] skipped over, which [`Sink::recv`] hands out first.
+    skipped: VecDeque<Email>,
+    server: JoinHandle<Result<(), Error>>,
+}
+
+/// Starts a sink with the default [`Config`].
+pub async fn spawn() -> Result<Sink, Error> {
+    spawn_with_config(Config::default()).await
+}
+
+/// Starts a sink with `config`, to test AUTH, LMTP and the like. Any credentials
+/// are accepted.
+pub async fn spawn_with_config(config: Config) -> Result<Sink, Error> {
+    let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    let (sender, emails) = mpsc::unbounded_channel();
+
+    let server = tokio::spawn(crate::serve(
+        listener,
+        config,
+        |_, _| async { true },
+        |_, _| async { true },
+        move |event| {
+            match event {
+                Event::EmailReceived(email) => {
+                    // The receiver only goes away with the sink, which stops the server.
+                    let _ = sender.send(email);
+                }
+            }
+            async {}
+        },
+    ));
+
+    Ok(Sink {
+        addr,
+        emails,
+        skipped: VecDeque::new(),
+        server,
+    })
+}
+
+impl Sink {
+    pub const fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Waits for the next email.
+    pub async fn recv(&mut self) -> Option<Email> {
+        match self.skipped.pop_front() {
+            Some(email) => Some(email),
+            None => self.emails.recv().await,
+        }
+    }
+
+    /// Waits up to `timeout` for an email matching `predicate`, and returns it.
+    ///
+    /// Emails that don't match are kept for [`Sink::recv`] and later calls.
+    pub async fn wait_for<P>(&mut self, predicate: P, timeout: Duration) -> Option<Email>
+    where
+        P: Fn(&Email) -> bool,
+    {
+        if let Some(index) = self.skipped.iter().position(&predicate) {
+            return self.skipped.remove(index);
+        }
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let email = timeout_at(deadline, self.emails.recv()).await.ok()??;
+            if predicate(&email) {
+                return Some(email);
+            }
+            self.skipped.push_back(email);
+        }
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    async fn send(addr: SocketAddr, to: &str, body: &str) {
+        let mut socket = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let mut line = String::new();
+        for command in [
+            "EHLO test".to_string(),
+            "MAIL FROM:<sender@example.com>".to_string(),
+            format!("RCPT TO:<{to}>"),
+            "DATA".to_string(),
+            format!("{body}\r\n."),
+            "QUIT".to_string(),
+        ] {
+            // Read the whole reply to the previous command, multi-line or not.
+            loop {
+                line.clear();
+                socket.read_line(&mut line).await.unwrap();
+                if line.as_bytes().get(3) != Some(&b'-') {
+                    break;
+                }
+            }
+            socket
+                .write_all(format!("{command}\r\n").as_bytes())
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sink_receives_emails() {
+        let mut sink = spawn().await.unwrap();
+
+        send(sink.addr(), "first@example.com", "Subject: one").await;
+        send(sink.addr(), "second@example.com", "Subject: two").await;
+
+        let second = sink
+            .wait_for(
+                |email| email.rcpt_to == ["second@example.com"],
+                Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+        assert_eq!(second.data, "Subject: two");
+
+        // The first one was skipped over, not lost.
+        let first = sink.recv().await.unwrap();
+        assert_eq!(first.rcpt_to, ["first@example.com"]);
+
+        let nothing = sink.wait_for(|_| true, Duration::from_millis(50)).await;
+        assert!(nothing.is_none());
+    }
+}
@@ -0,0 +1,26 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+pub use tokio_rustls::rustls::ServerConfig;
+
+use crate::Error;
+
+/// Builds a rustls server config from a PEM certificate chain and private key.
+pub fn load_server_config(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+) -> Result<Arc<ServerConfig>, Error> {
+    let certs = CertificateDer::pem_file_iter(cert_path.as_ref())
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::TlsConfig(format!("failed to read certificates: {e}")))?;
+    let key = PrivateKeyDer::from_pem_file(key_path.as_ref())
+        .map_err(|e| Error::TlsConfig(format!("failed to read private key: {e}")))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::TlsConfig(e.to_string()))?;
+
+    Ok(Arc::new(config))
+}